@@ -1,90 +1,181 @@
-use crate::lexer::{SourceToken, Token};
+use crate::diagnostic::Diagnostic;
+use crate::lexer::{SourceToken, Span, Token};
 
-#[derive(Debug)]
+type Tokens<'a> = std::iter::Peekable<std::slice::Iter<'a, SourceToken>>;
+
+#[derive(Debug, Clone)]
 pub enum Operation {
     Add,
     Sub,
     Mul,
     Div,
     Mod,
+    BitAnd,
+    BitOr,
+    BitXor,
+    ShiftLeft,
+    ShiftRight,
     CompareEquals,
+    CompareNotEquals,
     CompareLessThan,
+    CompareLessOrEqual,
+    CompareGreaterThan,
+    CompareGreaterOrEqual,
 }
 
-impl ToString for Operation {
-    fn to_string(&self) -> String {
-        match self {
-            Operation::Add => "+".to_string(),
-            Operation::Sub => "-".to_string(),
-            Operation::Mul => "*".to_string(),
-            Operation::Div => "/".to_string(),
-            Operation::Mod => "%".to_string(),
-            Operation::CompareEquals => "==".to_string(),
-            Operation::CompareLessThan => "<".to_string(),
-        }
+impl Operation {
+    pub fn is_comparison(&self) -> bool {
+        matches!(
+            self,
+            Operation::CompareEquals
+                | Operation::CompareNotEquals
+                | Operation::CompareLessThan
+                | Operation::CompareLessOrEqual
+                | Operation::CompareGreaterThan
+                | Operation::CompareGreaterOrEqual
+        )
     }
 }
 
-#[derive(Debug)]
-pub enum DataType {
-    Int,
-    String,
+impl std::fmt::Display for Operation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let s = match self {
+            Operation::Add => "+",
+            Operation::Sub => "-",
+            Operation::Mul => "*",
+            Operation::Div => "/",
+            Operation::Mod => "%",
+            Operation::BitAnd => "&",
+            Operation::BitOr => "|",
+            Operation::BitXor => "^",
+            Operation::ShiftLeft => "<<",
+            Operation::ShiftRight => ">>",
+            Operation::CompareEquals => "==",
+            Operation::CompareNotEquals => "!=",
+            Operation::CompareLessThan => "<",
+            Operation::CompareLessOrEqual => "<=",
+            Operation::CompareGreaterThan => ">",
+            Operation::CompareGreaterOrEqual => ">=",
+        };
+        write!(f, "{}", s)
+    }
 }
 
-#[derive(Debug)]
-pub enum Expression {
+#[derive(Debug, Clone)]
+pub struct Expression {
+    pub kind: ExpressionKind,
+    pub span: Span,
+}
+
+impl Expression {
+    pub fn new(kind: ExpressionKind, span: Span) -> Self {
+        Expression { kind, span }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ExpressionKind {
     Variable(String),
     Integer(i32),
-    Declaration {
-        identifier: String,
-        dataType: DataType,
-        value: Box<Expression>,
-    },
+    Character(char),
+    /// `Enum::Variant`
+    EnumVariant(String, String),
+    /// `pointer[index]`
+    Index(String, Box<Expression>),
     Literal(String),
     BinaryOp {
         operator: Operation,
         left: Box<Expression>,
         right: Box<Expression>,
     },
+    Call(String, Vec<Expression>),
+    Interpolation(Vec<InterpolationPart>),
+    /// `condition ? then : otherwise`, only the chosen side is evaluated
+    Conditional {
+        condition: Box<Expression>,
+        then: Box<Expression>,
+        otherwise: Box<Expression>,
+    },
 }
 
-impl ToString for Expression {
-    fn to_string(&self) -> String {
-        match self {
-            Expression::Literal(value) => format!("{}", value),
-            Expression::Variable(id) => format!("{}", id),
-            Expression::Integer(value) => format!("{}", value.to_string()),
-            Expression::Declaration {
-                identifier,
-                dataType,
-                value,
-            } => format!(
-                "let {} = {}",
-                identifier,
-                match dataType {
-                    DataType::Int => value.to_string(),
-                    DataType::String => value.to_string(),
-                }
-            ),
-            Expression::BinaryOp {
+/// Piece of a string literal with `{variable}` placeholders
+#[derive(Debug, Clone)]
+pub enum InterpolationPart {
+    Text(String),
+    Value(Expression),
+}
+
+impl std::fmt::Display for Expression {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.kind {
+            ExpressionKind::Literal(value) => write!(f, "{}", value),
+            ExpressionKind::Variable(id) => write!(f, "{}", id),
+            ExpressionKind::Integer(value) => write!(f, "{}", value),
+            ExpressionKind::Character(value) => write!(f, "{:?}", value),
+            ExpressionKind::EnumVariant(name, variant) => write!(f, "{}::{}", name, variant),
+            ExpressionKind::Index(name, index) => write!(f, "{}[{}]", name, index),
+            ExpressionKind::BinaryOp {
                 operator,
                 left,
                 right,
-            } => format!(
-                "{} {} {}",
-                left.to_string(),
-                operator.to_string(),
-                right.to_string()
-            ),
+            } => write!(f, "{} {} {}", left, operator, right),
+            ExpressionKind::Call(name, args) => {
+                let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+                write!(f, "{}({})", name, args.join(", "))
+            }
+            ExpressionKind::Interpolation(parts) => {
+                for part in parts {
+                    match part {
+                        InterpolationPart::Text(text) => write!(f, "{}", text)?,
+                        InterpolationPart::Value(value) => write!(f, "{{{}}}", value)?,
+                    }
+                }
+                Ok(())
+            }
+            ExpressionKind::Conditional {
+                condition,
+                then,
+                otherwise,
+            } => write!(f, "{} ? {} : {}", condition, then, otherwise),
         }
     }
 }
 
-#[derive(Debug)]
-pub enum Statement {
+#[derive(Debug, Clone)]
+pub struct Statement {
+    pub kind: StatementKind,
+    pub span: Span,
+}
+
+impl Statement {
+    pub fn new(kind: StatementKind, span: Span) -> Self {
+        Statement { kind, span }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum StatementKind {
     IntDeclaration(String),
     StringDeclaration(String, usize),
+    /// Growable string, see [`crate::transpiler::VariableType::Str`]
+    StrDeclaration(String),
+    PointerDeclaration(String),
+    /// `map<key, int> name`
+    MapDeclaration(String, MapKey),
+    /// Variable of an enum type, the type name comes first
+    EnumDeclaration(String, String),
+    /// Enum type with the values of its variants
+    EnumDefinition(String, Vec<(String, i32)>),
     Assignment(String, Expression),
+    /// `a += b` and the like, `a++` is `a += 1`
+    CompoundAssignment(String, Operation, Expression),
+    /// `pointer[index] = value`, or `pointer[index] += value` when there is an operator
+    IndexAssignment {
+        name: String,
+        index: Box<Expression>,
+        operator: Option<Operation>,
+        value: Expression,
+    },
     Exit,
     WhileLoop {
         condition: Box<Expression>,
@@ -92,696 +183,1045 @@ pub enum Statement {
     },
     Call(String, Vec<Expression>),
     Block(Vec<Statement>),
+    /// Runs the case that matches the value, or the default case. There is no fall-through.
+    Switch {
+        value: Box<Expression>,
+        cases: Vec<SwitchCase>,
+        default: Option<Box<Statement>>,
+    },
+    /// `import "path";`, replaced by the statements of the file when the program is loaded
+    Import(String),
+    Function(Function),
+    /// `return;`, or `return value;` in a function that returns a value
+    Return(Option<Expression>),
+    Macro(Macro),
+    /// `label name:`, a place `goto` can jump to within the same function
+    Label(String),
+    /// `goto name;`
+    Goto(String),
+    /// `uninit int a;`, a declaration whose variable is not set to zero or the empty string.
+    /// Declarations with an initial value are wrapped too, their assignment follows them.
+    Uninitialized(Box<Statement>),
+    /// `asm { push 1; print_stack_top_as_number; }`, Whitespace instructions written by hand
+    Asm(Vec<Instruction>),
+    /// Body of a macro with the arguments of a call substituted, the span is the one of the
+    /// call. See [`crate::macros`].
+    Expansion(String, Vec<Statement>),
+}
+
+/// Instruction of an `asm` block, named like in the debug output. `label name:` is an
+/// instruction too.
+#[derive(Debug, Clone)]
+pub struct Instruction {
+    pub mnemonic: String,
+    pub operand: Option<Operand>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub enum Operand {
+    /// Integer or character
+    Number(i32),
+    /// `&name`, the heap address of a variable
+    Address(String),
+    /// Name of a label
+    Label(String),
+}
+
+/// `macro name(a, b) { ... }`, the parameters stand for the expressions passed to a call
+#[derive(Debug, Clone)]
+pub struct Macro {
+    pub name: String,
+    pub parameters: Vec<String>,
+    pub body: Vec<Statement>,
+}
+
+/// `func name(int a, string b) { ... }`, or `func int name(...)` for a function that returns
+/// an integer. Calls are expanded in place, see [`crate::transpiler::function`].
+#[derive(Debug, Clone)]
+pub struct Function {
+    pub name: String,
+    pub parameters: Vec<(String, ParameterType)>,
+    pub returns_value: bool,
+    pub body: Vec<Statement>,
+}
+
+/// Type of a function parameter. Strings are passed by reference, everything else by value.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParameterType {
+    Int,
+    String,
+    Pointer,
 }
 
-pub fn parse(tokens: &Vec<SourceToken>) -> Result<Vec<Statement>, String> {
+/// Key type of a map, the values are always integers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MapKey {
+    Int,
+    String,
+}
+
+#[derive(Debug, Clone)]
+pub struct SwitchCase {
+    /// Integer, character or enum variant
+    pub value: Expression,
+    pub body: Statement,
+}
+
+pub fn parse(tokens: &[SourceToken]) -> Result<Vec<Statement>, Diagnostic> {
     let mut ast = vec![];
     let mut tokens = tokens.iter().peekable();
 
-    while let Some(&token) = tokens.peek() {
-        match token {
-            SourceToken {
-                token: Token::Int, ..
-            } => {
-                tokens.next();
-                let identifier = match tokens.next() {
-                    Some(SourceToken {
-                        token: Token::Identifier(ref id),
-                        ..
-                    }) => id.clone(),
-                    _ => {
-                        return Err(format!(
-                            "Expected identifier at {}:{}",
-                            token.position.0, token.position.1
-                        ))
-                    }
-                };
-                ast.push(Statement::IntDeclaration(identifier.clone()));
-                if let Some(SourceToken {
-                    token: Token::Assign,
+    while peek(&mut tokens).token != Token::EOF {
+        parse_statement(&mut tokens, &mut ast)?;
+    }
+
+    Ok(ast)
+}
+
+/// Returns the next token without consuming it. The token stream always ends with
+/// [`Token::EOF`], which is never consumed.
+fn peek<'a>(tokens: &mut Tokens<'a>) -> &'a SourceToken {
+    tokens
+        .peek()
+        .copied()
+        .expect("token stream must end with EOF")
+}
+
+/// Consumes and returns the next token, stopping at [`Token::EOF`].
+fn next<'a>(tokens: &mut Tokens<'a>) -> &'a SourceToken {
+    let token = peek(tokens);
+    if token.token != Token::EOF {
+        tokens.next();
+    }
+    token
+}
+
+fn expect(tokens: &mut Tokens<'_>, expected: Token) -> Result<Span, Diagnostic> {
+    let token = next(tokens);
+    if token.token == expected {
+        Ok(token.position)
+    } else {
+        Err(Diagnostic::new(
+            format!("Expected {}, found {}", expected, token.token),
+            token.position,
+        ))
+    }
+}
+
+fn expect_identifier(tokens: &mut Tokens<'_>) -> Result<(String, Span), Diagnostic> {
+    match next(tokens) {
+        SourceToken {
+            token: Token::Identifier(id),
+            position,
+        } => Ok((id.clone(), *position)),
+        token => Err(Diagnostic::new(
+            format!("Expected identifier, found {}", token.token),
+            token.position,
+        )),
+    }
+}
+
+/// Parses the assignment part of `target = value`, `target += value` or `target++`.
+/// Plain assignments have no operator, `++` and `--` add or subtract 1.
+/// Returns `None` without consuming anything when no assignment operator follows.
+fn parse_assignment(
+    tokens: &mut Tokens<'_>,
+) -> Result<Option<(Option<Operation>, Expression)>, Diagnostic> {
+    let operator = match peek(tokens).token {
+        Token::Assign => None,
+        Token::PlusAssign | Token::Increment => Some(Operation::Add),
+        Token::MinusAssign | Token::Decrement => Some(Operation::Sub),
+        Token::StarAssign => Some(Operation::Mul),
+        Token::SlashAssign => Some(Operation::Div),
+        Token::PercentAssign => Some(Operation::Mod),
+        _ => return Ok(None),
+    };
+    let token = next(tokens);
+    let value = match token.token {
+        Token::Increment | Token::Decrement => {
+            Expression::new(ExpressionKind::Integer(1), token.position)
+        }
+        _ => parse_expression(tokens)?,
+    };
+    Ok(Some((operator, value)))
+}
+
+/// Parses a single statement. Declarations with an initial value produce two statements,
+/// so the result is pushed to `ast` instead of being returned.
+fn parse_statement(tokens: &mut Tokens<'_>, ast: &mut Vec<Statement>) -> Result<(), Diagnostic> {
+    let token = next(tokens);
+    let span = token.position;
+    match &token.token {
+        Token::Int => {
+            let (identifier, _) = expect_identifier(tokens)?;
+            ast.push(Statement::new(
+                StatementKind::IntDeclaration(identifier.clone()),
+                span,
+            ));
+            parse_initializer(tokens, identifier, ast)?;
+            expect(tokens, Token::Semicolon)?;
+        }
+        Token::Str => {
+            let (identifier, _) = expect_identifier(tokens)?;
+            ast.push(Statement::new(
+                StatementKind::StrDeclaration(identifier.clone()),
+                span,
+            ));
+            parse_initializer(tokens, identifier, ast)?;
+            expect(tokens, Token::Semicolon)?;
+        }
+        Token::Import => {
+            let path = match next(tokens) {
+                SourceToken {
+                    token: Token::Literal(path),
                     ..
-                }) = tokens.peek()
-                {
-                    tokens.next();
-                    let expr = parse_expression(&mut tokens)?;
-                    ast.push(Statement::Assignment(identifier, expr));
+                } => path.clone(),
+                token => {
+                    return Err(Diagnostic::new(
+                        format!("Expected file name, found {}", token.token),
+                        token.position,
+                    ))
                 }
-                if let Some(SourceToken {
-                    token: Token::Semicolon,
+            };
+            ast.push(Statement::new(StatementKind::Import(path), span));
+            expect(tokens, Token::Semicolon)?;
+        }
+        Token::Map => {
+            expect(tokens, Token::Less)?;
+            let key = match next(tokens) {
+                SourceToken {
+                    token: Token::Int, ..
+                } => MapKey::Int,
+                SourceToken {
+                    token: Token::String,
                     ..
-                }) = tokens.peek()
-                {
-                    tokens.next();
-                } else {
-                    return Err(format!(
-                        "Expected ; after {}:{}, found {:?}",
-                        token.position.0,
-                        token.position.1,
-                        tokens.peek().unwrap().token
+                } => MapKey::String,
+                token => {
+                    return Err(Diagnostic::new(
+                        format!("Expected int or string, found {}", token.token),
+                        token.position,
+                    ))
+                }
+            };
+            expect(tokens, Token::Comma)?;
+            expect(tokens, Token::Int)?;
+            expect(tokens, Token::Greater)?;
+            let (identifier, _) = expect_identifier(tokens)?;
+            ast.push(Statement::new(
+                StatementKind::MapDeclaration(identifier, key),
+                span,
+            ));
+            expect(tokens, Token::Semicolon)?;
+        }
+        Token::Ptr => {
+            let (identifier, _) = expect_identifier(tokens)?;
+            ast.push(Statement::new(
+                StatementKind::PointerDeclaration(identifier.clone()),
+                span,
+            ));
+            parse_initializer(tokens, identifier, ast)?;
+            expect(tokens, Token::Semicolon)?;
+        }
+        Token::String => {
+            expect(tokens, Token::LSquare)?;
+            let length = match next(tokens) {
+                SourceToken {
+                    token: Token::Integer(value),
+                    position,
+                } => {
+                    if *value <= 0 {
+                        return Err(Diagnostic::new(
+                            "String size must be greater than 0",
+                            *position,
+                        ));
+                    }
+                    *value as usize
+                }
+                token => {
+                    return Err(Diagnostic::new(
+                        format!("Expected integer value, found {}", token.token),
+                        token.position,
+                    ))
+                }
+            };
+            expect(tokens, Token::RSquare)?;
+            let (identifier, _) = expect_identifier(tokens)?;
+            ast.push(Statement::new(
+                StatementKind::StringDeclaration(identifier.clone(), length),
+                span,
+            ));
+            parse_initializer(tokens, identifier, ast)?;
+            expect(tokens, Token::Semicolon)?;
+        }
+        // TODO: Delete?
+        Token::Const => {
+            let (identifier, _) = expect_identifier(tokens)?;
+            match next(tokens) {
+                SourceToken {
+                    token: Token::Integer(value),
+                    position,
+                } => {
+                    ast.push(Statement::new(
+                        StatementKind::IntDeclaration(identifier.clone()),
+                        span,
                     ));
+                    ast.push(Statement::new(
+                        StatementKind::Assignment(
+                            identifier,
+                            Expression::new(ExpressionKind::Integer(*value), *position),
+                        ),
+                        span,
+                    ));
+                }
+                token => {
+                    return Err(Diagnostic::new(
+                        format!("Expected integer value, found {}", token.token),
+                        token.position,
+                    ))
                 }
             }
-            SourceToken {
-                token: Token::String,
-                ..
-            } => {
+            expect(tokens, Token::Semicolon)?;
+        }
+        Token::Print => {
+            // `print(a, b);` takes an argument list, `print a, b;` and `print (a + b) * 2;`
+            // bare expressions
+            let args = if is_argument_list(tokens) {
                 tokens.next();
-                if let Some(SourceToken {
-                    token: Token::LSquare,
-                    ..
-                }) = tokens.peek()
-                {
+                parse_arguments(tokens)?
+            } else {
+                let mut args = vec![parse_expression(tokens)?];
+                while peek(tokens).token == Token::Comma {
                     tokens.next();
-                } else {
-                    return Err(format!(
-                        "Expected [, found {:?} at {}:{}",
-                        tokens.peek().unwrap().token,
-                        tokens.peek().unwrap().position.0,
-                        tokens.peek().unwrap().position.1
-                    ));
+                    args.push(parse_expression(tokens)?);
                 }
-                let length = match tokens.next() {
-                    Some(SourceToken {
-                        token: Token::Integer(value),
-                        ..
-                    }) => {
-                        if value <= &0 {
-                            return Err(format!(
-                                "String size must be greater than 0 at {}:{}",
-                                token.position.0, token.position.1
-                            ));
-                        }
-                        let value = *value as usize;
-                        if let Some(SourceToken {
-                            token: Token::RSquare,
-                            ..
-                        }) = tokens.peek()
-                        {
-                            tokens.next();
-                            value
-                        } else {
-                            return Err(format!(
-                                "Expected ] at {}:{}",
-                                token.position.0, token.position.1
-                            ));
-                        }
+                args
+            };
+            ast.push(Statement::new(
+                StatementKind::Call("print".to_string(), args),
+                span,
+            ));
+            expect(tokens, Token::Semicolon)?;
+        }
+        Token::Exit => {
+            ast.push(Statement::new(StatementKind::Exit, span));
+            expect(tokens, Token::Semicolon)?;
+        }
+        Token::While => {
+            expect(tokens, Token::LParen)?;
+            let condition = parse_expression(tokens)?;
+            expect(tokens, Token::RParen)?;
+            let body_span = peek(tokens).position;
+            let mut body = vec![];
+            parse_statement(tokens, &mut body)?;
+            let body = if body.len() == 1 {
+                body.remove(0)
+            } else {
+                Statement::new(StatementKind::Block(body), body_span)
+            };
+            ast.push(Statement::new(
+                StatementKind::WhileLoop {
+                    condition: Box::new(condition),
+                    body: Box::new(body),
+                },
+                span,
+            ));
+        }
+        Token::Switch => {
+            expect(tokens, Token::LParen)?;
+            let value = parse_expression(tokens)?;
+            expect(tokens, Token::RParen)?;
+            expect(tokens, Token::CurlyL)?;
+            let mut cases: Vec<SwitchCase> = vec![];
+            let mut default = None;
+            loop {
+                let token = next(tokens);
+                match token.token {
+                    Token::CurlyR => break,
+                    Token::Case => {
+                        let value = parse_primary(tokens)?;
+                        expect(tokens, Token::Colon)?;
+                        let body = parse_case_body(tokens, token.position)?;
+                        cases.push(SwitchCase { value, body });
                     }
-                    _ => {
-                        return Err(format!(
-                            "Expected integer value at {}:{}",
-                            token.position.0, token.position.1,
-                        ))
+                    Token::Default => {
+                        if default.is_some() {
+                            return Err(Diagnostic::new("Duplicate default case", token.position));
+                        }
+                        expect(tokens, Token::Colon)?;
+                        default = Some(Box::new(parse_case_body(tokens, token.position)?));
                     }
-                };
-                let identifier = match tokens.next() {
-                    Some(SourceToken {
-                        token: Token::Identifier(ref id),
-                        ..
-                    }) => id.clone(),
                     _ => {
-                        return Err(format!(
-                            "Expected identifier at {}:{}",
-                            token.position.0, token.position.1
+                        return Err(Diagnostic::new(
+                            format!("Expected case, default or }}, found {}", token.token),
+                            token.position,
                         ))
                     }
-                };
-                ast.push(Statement::StringDeclaration(identifier.clone(), length));
-                if let Some(SourceToken {
-                    token: Token::Assign,
-                    ..
-                }) = tokens.peek()
-                {
-                    tokens.next();
-                    let expr = parse_expression(&mut tokens)?;
-                    ast.push(Statement::Assignment(identifier, expr));
-                }
-                if let Some(SourceToken {
-                    token: Token::Semicolon,
-                    ..
-                }) = tokens.peek()
-                {
-                    tokens.next();
-                } else {
-                    return Err(format!(
-                        "Expected ; after {}:{}, found {:?}",
-                        token.position.0,
-                        token.position.1,
-                        tokens.peek().unwrap().token
-                    ));
                 }
             }
-            // TODO: Delete?
-            SourceToken {
-                token: Token::Const,
-                ..
-            } => {
-                tokens.next();
-                let identifier = match tokens.next() {
-                    Some(SourceToken {
-                        token: Token::Identifier(ref id),
-                        ..
-                    }) => id.clone(),
-                    _ => {
-                        return Err(format!(
-                            "Expected identifier at {}:{}",
-                            token.position.0, token.position.1
-                        ))
+            ast.push(Statement::new(
+                StatementKind::Switch {
+                    value: Box::new(value),
+                    cases,
+                    default,
+                },
+                span,
+            ));
+        }
+        Token::Enum => {
+            let (name, _) = expect_identifier(tokens)?;
+            expect(tokens, Token::CurlyL)?;
+            let mut variants: Vec<(String, i32)> = vec![];
+            // Value of the next variant without one, `None` once it would overflow
+            let mut next_value = Some(0);
+            while peek(tokens).token != Token::CurlyR {
+                let (variant, variant_span) = expect_identifier(tokens)?;
+                let value = if peek(tokens).token == Token::Assign {
+                    tokens.next();
+                    match next(tokens) {
+                        SourceToken {
+                            token: Token::Integer(value),
+                            ..
+                        } => *value,
+                        token => {
+                            return Err(Diagnostic::new(
+                                format!("Expected integer value, found {}", token.token),
+                                token.position,
+                            ))
+                        }
                     }
-                };
-                if let Some(SourceToken {
-                    token: Token::Integer(value),
-                    ..
-                }) = tokens.next()
-                {
-                    ast.push(Statement::IntDeclaration(identifier.clone()));
-                    ast.push(Statement::Assignment(
-                        identifier,
-                        Expression::Integer(*value),
-                    ));
                 } else {
-                    return Err(format!(
-                        "Expected integer value at {}:{}",
-                        token.position.0, token.position.1
+                    next_value.ok_or_else(|| {
+                        Diagnostic::new(
+                            format!("Value of variant {} is out of range", variant),
+                            variant_span,
+                        )
+                    })?
+                };
+                if variants.iter().any(|(name, _)| *name == variant) {
+                    return Err(Diagnostic::new(
+                        format!("Duplicate variant {}", variant),
+                        variant_span,
                     ));
                 }
-
-                if let Some(SourceToken {
-                    token: Token::Semicolon,
-                    ..
-                }) = tokens.peek()
-                {
+                variants.push((variant, value));
+                next_value = value.checked_add(1);
+                if peek(tokens).token == Token::Comma {
                     tokens.next();
                 } else {
-                    return Err(format!(
-                        "Expected ; after {}:{}, found {:?}",
-                        token.position.0,
-                        token.position.1,
-                        tokens.peek().unwrap().token
-                    ));
+                    break;
                 }
             }
-            SourceToken {
-                token: Token::Print,
-                ..
-            } => {
-                tokens.next();
-                let expr = parse_expression(&mut tokens)?;
-                ast.push(Statement::Call("print".to_string(), vec![expr]));
-
-                if let Some(SourceToken {
-                    token: Token::Semicolon,
-                    ..
-                }) = tokens.peek()
-                {
-                    tokens.next();
-                } else {
-                    return Err(format!(
-                        "Expected ; after {}:{}, found {:?}",
-                        token.position.0,
-                        token.position.1,
-                        tokens.peek().unwrap().token
+            expect(tokens, Token::CurlyR)?;
+            ast.push(Statement::new(
+                StatementKind::EnumDefinition(name, variants),
+                span,
+            ));
+        }
+        Token::Identifier(name) => {
+            match &peek(tokens).token {
+                Token::Identifier(_) => {
+                    let (identifier, _) = expect_identifier(tokens)?;
+                    ast.push(Statement::new(
+                        StatementKind::EnumDeclaration(name.clone(), identifier.clone()),
+                        span,
                     ));
+                    parse_initializer(tokens, identifier, ast)?;
                 }
-            }
-            SourceToken {
-                token: Token::Exit, ..
-            } => {
-                tokens.next();
-                ast.push(Statement::Exit);
-
-                if let Some(SourceToken {
-                    token: Token::Semicolon,
-                    ..
-                }) = tokens.peek()
-                {
+                Token::LParen => {
                     tokens.next();
-                } else {
-                    return Err(format!(
-                        "Expected ; after {}:{}, found {:?}",
-                        token.position.0,
-                        token.position.1,
-                        tokens.peek().unwrap().token
+                    let args = parse_arguments(tokens)?;
+                    ast.push(Statement::new(
+                        StatementKind::Call(name.clone(), args),
+                        span,
                     ));
                 }
-            }
-            SourceToken {
-                token: Token::While,
-                ..
-            } => {
-                tokens.next();
-                if let Some(SourceToken {
-                    token: Token::LParen,
-                    ..
-                }) = tokens.peek()
-                {
+                Token::LSquare => {
                     tokens.next();
-                    let condition: Expression = parse_expression(&mut tokens)?;
-
-                    if let Some(SourceToken {
-                        token: Token::RParen,
-                        ..
-                    }) = tokens.peek()
-                    {
-                        tokens.next();
-                        ast.push(Statement::WhileLoop {
-                            condition: Box::new(condition),
-                            body: Box::new(parse_statement(&mut tokens)?),
-                        });
-                    } else {
-                        return Err(format!(
-                            "Expected ) at {}:{}",
-                            token.position.0, token.position.1
+                    let index = parse_expression(tokens)?;
+                    expect(tokens, Token::RSquare)?;
+                    let Some((operator, value)) = parse_assignment(tokens)? else {
+                        let token = next(tokens);
+                        return Err(Diagnostic::new(
+                            format!("Expected assignment, found {}", token.token),
+                            token.position,
                         ));
-                    }
-                } else {
-                    return Err(format!(
-                        "Expected ( at {}:{}",
-                        token.position.0, token.position.1
+                    };
+                    ast.push(Statement::new(
+                        StatementKind::IndexAssignment {
+                            name: name.clone(),
+                            index: Box::new(index),
+                            operator,
+                            value,
+                        },
+                        span,
                     ));
                 }
-            }
-            SourceToken {
-                token: Token::Identifier(name),
-                ..
-            } => {
-                tokens.next();
-                if let Some(SourceToken {
-                    token: Token::Assign,
-                    ..
-                }) = tokens.peek()
-                {
-                    tokens.next();
-                    let expr = parse_expression(&mut tokens)?;
-                    ast.push(Statement::Assignment(name.clone(), expr));
-                } else if let Some(SourceToken {
-                    token: Token::LParen,
-                    ..
-                }) = tokens.peek()
-                {
-                    let mut args = vec![];
-                    tokens.next();
-                    while let Some(&token) = tokens.peek() {
-                        match token {
-                            SourceToken {
-                                token: Token::RParen,
-                                ..
-                            } => {
-                                tokens.next();
-                                break;
-                            }
-                            SourceToken {
-                                token: Token::Comma,
-                                ..
-                            } => {
-                                tokens.next();
-                            }
-                            _ => args.push(parse_expression(&mut tokens)?),
+                _ => {
+                    let kind = match parse_assignment(tokens)? {
+                        Some((None, value)) => StatementKind::Assignment(name.clone(), value),
+                        Some((Some(operator), value)) => {
+                            StatementKind::CompoundAssignment(name.clone(), operator, value)
                         }
-                    }
-                    ast.push(Statement::Call(name.clone(), args));
-                } else {
-                    return Err(format!(
-                        "Unexpected identifier: {} at {}:{}",
-                        name, token.position.0, token.position.1
-                    ));
-                }
-                if let Some(SourceToken {
-                    token: Token::Semicolon,
-                    ..
-                }) = tokens.peek()
-                {
-                    tokens.next();
-                } else {
-                    return Err(format!(
-                        "Expected ; after {}:{}, found {:?}",
-                        token.position.0,
-                        token.position.1,
-                        tokens.peek().unwrap().token
-                    ));
+                        None => {
+                            return Err(Diagnostic::new(
+                                format!("Unexpected identifier: {}", name),
+                                span,
+                            ))
+                        }
+                    };
+                    ast.push(Statement::new(kind, span));
                 }
             }
-            SourceToken {
-                token: Token::EOF, ..
-            } => break,
-            _ => {
-                return Err(format!(
-                    "Unexpected token: {:?} at {}:{}",
-                    token.token, token.position.0, token.position.1
-                ))
-            }
+            expect(tokens, Token::Semicolon)?;
         }
-    }
-
-    Ok(ast)
-}
-
-fn parse_statement(
-    tokens: &mut std::iter::Peekable<std::slice::Iter<'_, SourceToken>>,
-) -> Result<Statement, String> {
-    let token = tokens.next();
-    let statement = match token {
-        Some(SourceToken {
-            token: Token::Const,
-            ..
-        }) => {
-            let identifier = match tokens.next() {
-                Some(SourceToken {
-                    token: Token::Identifier(ref id),
-                    ..
-                }) => id.clone(),
-                _ => {
-                    return Err(format!(
-                        "Expected identifier at {}:{}",
-                        token.unwrap().position.0,
-                        token.unwrap().position.1
-                    ))
+        Token::CurlyL => {
+            let body = parse_block(tokens)?;
+            ast.push(Statement::new(StatementKind::Block(body), span));
+        }
+        Token::Func => {
+            let returns_value = peek(tokens).token == Token::Int;
+            if returns_value {
+                tokens.next();
+            }
+            let (name, _) = expect_identifier(tokens)?;
+            expect(tokens, Token::LParen)?;
+            let mut parameters = vec![];
+            while peek(tokens).token != Token::RParen {
+                if !parameters.is_empty() {
+                    expect(tokens, Token::Comma)?;
                 }
-            };
-            if let Some(SourceToken {
-                token: Token::Integer(value),
-                ..
-            }) = tokens.next()
-            {
-                Statement::IntDeclaration(identifier)
-            } else {
-                return Err(format!(
-                    "Expected integer value at {}:{}",
-                    token.unwrap().position.0,
-                    token.unwrap().position.1
-                ));
+                let type_ = match next(tokens) {
+                    SourceToken {
+                        token: Token::Int, ..
+                    } => ParameterType::Int,
+                    SourceToken {
+                        token: Token::String,
+                        ..
+                    } => ParameterType::String,
+                    SourceToken {
+                        token: Token::Ptr, ..
+                    } => ParameterType::Pointer,
+                    token => {
+                        return Err(Diagnostic::new(
+                            format!("Expected int, string or ptr, found {}", token.token),
+                            token.position,
+                        ))
+                    }
+                };
+                let (parameter, _) = expect_identifier(tokens)?;
+                parameters.push((parameter, type_));
             }
+            tokens.next();
+            expect(tokens, Token::CurlyL)?;
+            let body = parse_block(tokens)?;
+            ast.push(Statement::new(
+                StatementKind::Function(Function {
+                    name,
+                    parameters,
+                    returns_value,
+                    body,
+                }),
+                span,
+            ));
         }
-        Some(SourceToken {
-            token: Token::Print,
-            ..
-        }) => {
-            let expr = parse_expression(tokens)?;
-            Statement::Call("print".to_string(), vec![expr])
-        }
-        Some(SourceToken {
-            token: Token::Exit, ..
-        }) => Statement::Exit,
-        Some(SourceToken {
-            token: Token::While,
-            ..
-        }) => {
+        Token::Macro => {
+            let (name, _) = expect_identifier(tokens)?;
+            expect(tokens, Token::LParen)?;
+            let mut parameters = vec![];
+            while peek(tokens).token != Token::RParen {
+                if !parameters.is_empty() {
+                    expect(tokens, Token::Comma)?;
+                }
+                let (parameter, _) = expect_identifier(tokens)?;
+                parameters.push(parameter);
+            }
             tokens.next();
-            if let Some(SourceToken {
-                token: Token::LParen,
-                ..
-            }) = tokens.peek()
-            {
-                tokens.next();
-                let condition: Expression = parse_expression(tokens)?;
-                let mut body: Vec<Statement> = vec![];
-                while let Some(&token) = tokens.peek() {
-                    match token {
-                        SourceToken {
-                            token: Token::RParen,
-                            ..
-                        } => {
-                            tokens.next();
-                            break;
-                        }
-                        _ => body.push(parse_statement(tokens)?),
-                    }
+            expect(tokens, Token::CurlyL)?;
+            let body = parse_block(tokens)?;
+            ast.push(Statement::new(
+                StatementKind::Macro(Macro {
+                    name,
+                    parameters,
+                    body,
+                }),
+                span,
+            ));
+        }
+        Token::Label => {
+            let (name, _) = expect_identifier(tokens)?;
+            expect(tokens, Token::Colon)?;
+            ast.push(Statement::new(StatementKind::Label(name), span));
+        }
+        Token::Goto => {
+            let (name, _) = expect_identifier(tokens)?;
+            expect(tokens, Token::Semicolon)?;
+            ast.push(Statement::new(StatementKind::Goto(name), span));
+        }
+        Token::Uninit => {
+            let start = ast.len();
+            parse_statement(tokens, ast)?;
+            match ast.get(start).map(|statement| &statement.kind) {
+                // Declared with a value, which already skips the zeroing
+                Some(StatementKind::Uninitialized(declaration))
+                    if !matches!(declaration.kind, StatementKind::StrDeclaration(_)) => {}
+                Some(
+                    StatementKind::IntDeclaration(_)
+                    | StatementKind::StringDeclaration(..)
+                    | StatementKind::PointerDeclaration(_)
+                    | StatementKind::EnumDeclaration(..),
+                ) => {
+                    let declaration = ast.remove(start);
+                    ast.insert(
+                        start,
+                        Statement::new(StatementKind::Uninitialized(Box::new(declaration)), span),
+                    );
                 }
-                Statement::WhileLoop {
-                    condition: Box::new(condition),
-                    body: Box::new(Statement::Block(body)),
+                _ => {
+                    return Err(Diagnostic::new(
+                        "uninit only applies to int, string, ptr and enum declarations",
+                        span,
+                    ))
                 }
-            } else {
-                return Err(format!(
-                    "Expected ( at {}:{}",
-                    token.unwrap().position.0,
-                    token.unwrap().position.1
-                ));
             }
         }
-        Some(SourceToken {
-            token: Token::Identifier(ref id),
-            ..
-        }) => {
-            if let Some(SourceToken {
-                token: Token::Assign,
-                ..
-            }) = tokens.peek()
-            {
-                tokens.next();
-                let expr = parse_expression(tokens)?;
-                Statement::Assignment(id.clone(), expr)
-            } else {
-                return Err(format!(
-                    "Expected = at {}:{}",
-                    token.unwrap().position.0,
-                    token.unwrap().position.1
-                ));
+        Token::Asm => {
+            expect(tokens, Token::CurlyL)?;
+            let mut instructions = vec![];
+            while peek(tokens).token != Token::CurlyR {
+                instructions.push(parse_instruction(tokens)?);
             }
+            tokens.next();
+            ast.push(Statement::new(StatementKind::Asm(instructions), span));
         }
-        Some(SourceToken {
-            token: Token::CurlyL,
-            ..
-        }) => {
-            let mut body: Vec<Statement> = vec![];
-            while let Some(&token) = tokens.peek() {
-                match token {
-                    SourceToken {
-                        token: Token::CurlyR,
-                        ..
-                    } => {
-                        tokens.next();
-                        break;
-                    }
-                    SourceToken {
-                        token: Token::Semicolon,
-                        ..
-                    } => {
-                        tokens.next();
-                    }
-                    _ => body.push(parse_statement(tokens)?),
-                }
-            }
-            Statement::Block(body)
-        }
-        _ => {
-            return Err(format!(
-                "Statement, unexpected token: {:?} at {}:{}",
-                token.unwrap().token,
-                token.unwrap().position.0,
-                token.unwrap().position.1
+        Token::Return => {
+            let value = match peek(tokens).token {
+                Token::Semicolon => None,
+                _ => Some(parse_expression(tokens)?),
+            };
+            ast.push(Statement::new(StatementKind::Return(value), span));
+            expect(tokens, Token::Semicolon)?;
+        }
+        token => {
+            return Err(Diagnostic::new(
+                format!("Unexpected token: {}", token),
+                span,
             ))
         }
-    };
+    }
 
-    Ok(statement)
+    Ok(())
+}
+
+/// Parses `mnemonic;`, `mnemonic operand;` or `label name:` in an `asm` block. The operand
+/// can be written in parentheses too, like `copy_nth(1)` in the debug output.
+fn parse_instruction(tokens: &mut Tokens<'_>) -> Result<Instruction, Diagnostic> {
+    let SourceToken { token, position } = next(tokens);
+    let mnemonic = match token {
+        Token::Label => {
+            let (name, _) = expect_identifier(tokens)?;
+            expect(tokens, Token::Colon)?;
+            return Ok(Instruction {
+                mnemonic: "label".to_string(),
+                operand: Some(Operand::Label(name)),
+                span: *position,
+            });
+        }
+        Token::Identifier(mnemonic) => mnemonic.clone(),
+        Token::Return | Token::Exit => token.to_string(),
+        token => {
+            return Err(Diagnostic::new(
+                format!("Expected instruction, found {}", token),
+                *position,
+            ))
+        }
+    };
+    let parenthesized = peek(tokens).token == Token::LParen;
+    if parenthesized {
+        tokens.next();
+    }
+    let operand = match &peek(tokens).token {
+        Token::Integer(value) => Some(Operand::Number(*value)),
+        Token::Character(value) => Some(Operand::Number(*value as i32)),
+        Token::Identifier(name) => Some(Operand::Label(name.clone())),
+        Token::Ampersand => {
+            tokens.next();
+            let (name, _) = expect_identifier(tokens)?;
+            Some(Operand::Address(name))
+        }
+        _ => None,
+    };
+    if matches!(operand, Some(Operand::Number(_) | Operand::Label(_))) {
+        tokens.next();
+    }
+    if parenthesized {
+        expect(tokens, Token::RParen)?;
+    }
+    expect(tokens, Token::Semicolon)?;
+    Ok(Instruction {
+        mnemonic,
+        operand,
+        span: *position,
+    })
 }
 
-fn parse_expression(
-    tokens: &mut std::iter::Peekable<std::slice::Iter<'_, SourceToken>>,
-) -> Result<Expression, String> {
-    let mut expr: Expression = parse_factor(tokens)?;
-    while let Some(&token) = tokens.peek() {
-        match token {
-            SourceToken {
-                token: Token::Plus, ..
-            } => {
+/// Parses statements up to the closing brace, the opening brace is expected to be consumed.
+fn parse_block(tokens: &mut Tokens<'_>) -> Result<Vec<Statement>, Diagnostic> {
+    let mut body: Vec<Statement> = vec![];
+    loop {
+        match peek(tokens).token {
+            Token::CurlyR => {
                 tokens.next();
-                let right = parse_factor(tokens)?;
-                expr = Expression::BinaryOp {
-                    operator: Operation::Add,
-                    left: Box::new(expr),
-                    right: Box::new(right),
-                };
+                break;
             }
-            SourceToken {
-                token: Token::Minus,
-                ..
-            } => {
+            Token::Semicolon => {
                 tokens.next();
-                let right = parse_factor(tokens)?;
-                expr = Expression::BinaryOp {
-                    operator: Operation::Sub,
-                    left: Box::new(expr),
-                    right: Box::new(right),
-                };
             }
-            SourceToken {
-                token: Token::Star, ..
-            } => {
-                tokens.next();
-                let right = parse_factor(tokens)?;
-                expr = Expression::BinaryOp {
-                    operator: Operation::Mul,
-                    left: Box::new(expr),
-                    right: Box::new(right),
-                };
+            Token::EOF => {
+                return Err(Diagnostic::new(
+                    "Expected }, found EOF",
+                    peek(tokens).position,
+                ))
             }
-            SourceToken {
-                token: Token::Slash,
-                ..
-            } => {
+            _ => parse_statement(tokens, &mut body)?,
+        }
+    }
+    Ok(body)
+}
+
+/// Parses the statements of a switch case up to the next case, default or the closing brace.
+fn parse_case_body(tokens: &mut Tokens<'_>, span: Span) -> Result<Statement, Diagnostic> {
+    let mut body: Vec<Statement> = vec![];
+    loop {
+        match peek(tokens).token {
+            Token::Case | Token::Default | Token::CurlyR => break,
+            Token::Semicolon => {
                 tokens.next();
-                let right = parse_factor(tokens)?;
-                expr = Expression::BinaryOp {
-                    operator: Operation::Div,
-                    left: Box::new(expr),
-                    right: Box::new(right),
-                };
             }
-            SourceToken {
-                token: Token::Percent,
-                ..
-            } => {
-                tokens.next();
-                let right = parse_factor(tokens)?;
-                expr = Expression::BinaryOp {
-                    operator: Operation::Mod,
-                    left: Box::new(expr),
-                    right: Box::new(right),
-                };
+            Token::EOF => {
+                return Err(Diagnostic::new(
+                    "Expected }, found EOF",
+                    peek(tokens).position,
+                ))
             }
-            SourceToken {
-                token: Token::Equals,
-                ..
-            } => {
-                tokens.next();
-                let right = parse_factor(tokens)?;
-                expr = Expression::BinaryOp {
-                    operator: Operation::CompareEquals,
-                    left: Box::new(expr),
-                    right: Box::new(right),
-                };
+            _ => parse_statement(tokens, &mut body)?,
+        }
+    }
+    Ok(Statement::new(StatementKind::Block(body), span))
+}
+
+/// Parses an optional `= <expression>` following a declaration.
+fn parse_initializer(
+    tokens: &mut Tokens<'_>,
+    identifier: String,
+    ast: &mut Vec<Statement>,
+) -> Result<(), Diagnostic> {
+    if let Token::Assign = peek(tokens).token {
+        // The value is assigned right away, so the declaration is not zeroed first
+        let declaration = ast
+            .pop()
+            .expect("the declaration is pushed before its initializer");
+        let span = declaration.span;
+        ast.push(Statement::new(
+            StatementKind::Uninitialized(Box::new(declaration)),
+            span,
+        ));
+        let span = next(tokens).position;
+        let expr = parse_expression(tokens)?;
+        ast.push(Statement::new(
+            StatementKind::Assignment(identifier, expr),
+            span,
+        ));
+    }
+    Ok(())
+}
+
+/// Whether the next tokens are a parenthesized list that ends the statement, rather than the
+/// start of a larger expression. Consumes nothing.
+fn is_argument_list(tokens: &Tokens<'_>) -> bool {
+    let mut tokens = tokens.clone();
+    if !matches!(tokens.next().map(|token| &token.token), Some(Token::LParen)) {
+        return false;
+    }
+    let mut depth = 1;
+    for token in tokens.by_ref() {
+        match token.token {
+            Token::LParen => depth += 1,
+            Token::RParen => {
+                depth -= 1;
+                if depth == 0 {
+                    break;
+                }
             }
-            SourceToken {
-                token: Token::Less, ..
-            } => {
-                tokens.next();
-                let right = parse_factor(tokens)?;
-                expr = Expression::BinaryOp {
-                    operator: Operation::CompareLessThan,
-                    left: Box::new(expr),
-                    right: Box::new(right),
-                };
+            Token::EOF => return false,
+            _ => {}
+        }
+    }
+    matches!(
+        tokens.next().map(|token| &token.token),
+        Some(Token::Semicolon)
+    )
+}
+
+/// Parses a comma-separated argument list, the opening parenthesis is expected to be consumed.
+fn parse_arguments(tokens: &mut Tokens<'_>) -> Result<Vec<Expression>, Diagnostic> {
+    let mut args = vec![];
+    if peek(tokens).token == Token::RParen {
+        tokens.next();
+        return Ok(args);
+    }
+    loop {
+        args.push(parse_expression(tokens)?);
+        let token = next(tokens);
+        match token.token {
+            Token::Comma => {}
+            Token::RParen => break,
+            _ => {
+                return Err(Diagnostic::new(
+                    format!("Expected , or ), found {}", token.token),
+                    token.position,
+                ))
             }
-            _ => break,
         }
     }
+    Ok(args)
+}
+
+/// Parses a chain of left-associative binary operations. `operand` parses the operands,
+/// `operator` picks the tokens of this precedence level.
+fn parse_binary(
+    tokens: &mut Tokens<'_>,
+    operand: fn(&mut Tokens<'_>) -> Result<Expression, Diagnostic>,
+    operator: fn(&Token) -> Option<Operation>,
+) -> Result<Expression, Diagnostic> {
+    let mut expr: Expression = operand(tokens)?;
+    loop {
+        let token = peek(tokens);
+        let Some(operator) = operator(&token.token) else {
+            break;
+        };
+        tokens.next();
+        let right = operand(tokens)?;
+        expr = Expression::new(
+            ExpressionKind::BinaryOp {
+                operator,
+                left: Box::new(expr),
+                right: Box::new(right),
+            },
+            token.position,
+        );
+    }
     Ok(expr)
 }
 
-fn parse_factor(
-    tokens: &mut std::iter::Peekable<std::slice::Iter<'_, SourceToken>>,
-) -> Result<Expression, String> {
-    let mut expr: Expression = parse_unary(tokens)?;
-    while let Some(&token) = tokens.peek() {
-        match token {
-            SourceToken {
-                token: Token::Star, ..
-            } => {
+fn parse_expression(tokens: &mut Tokens<'_>) -> Result<Expression, Diagnostic> {
+    let condition = parse_comparison(tokens)?;
+    let token = peek(tokens);
+    if token.token != Token::Question {
+        return Ok(condition);
+    }
+    tokens.next();
+    let then = parse_expression(tokens)?;
+    expect(tokens, Token::Colon)?;
+    let otherwise = parse_expression(tokens)?;
+    Ok(Expression::new(
+        ExpressionKind::Conditional {
+            condition: Box::new(condition),
+            then: Box::new(then),
+            otherwise: Box::new(otherwise),
+        },
+        token.position,
+    ))
+}
+
+fn parse_comparison(tokens: &mut Tokens<'_>) -> Result<Expression, Diagnostic> {
+    parse_binary(tokens, parse_bit_or, |token| match token {
+        Token::Equals => Some(Operation::CompareEquals),
+        Token::NotEquals => Some(Operation::CompareNotEquals),
+        Token::Less => Some(Operation::CompareLessThan),
+        Token::LessOrEqual => Some(Operation::CompareLessOrEqual),
+        Token::Greater => Some(Operation::CompareGreaterThan),
+        Token::GreaterOrEqual => Some(Operation::CompareGreaterOrEqual),
+        _ => None,
+    })
+}
+
+fn parse_bit_or(tokens: &mut Tokens<'_>) -> Result<Expression, Diagnostic> {
+    parse_binary(tokens, parse_bit_xor, |token| match token {
+        Token::Pipe => Some(Operation::BitOr),
+        _ => None,
+    })
+}
+
+fn parse_bit_xor(tokens: &mut Tokens<'_>) -> Result<Expression, Diagnostic> {
+    parse_binary(tokens, parse_bit_and, |token| match token {
+        Token::Caret => Some(Operation::BitXor),
+        _ => None,
+    })
+}
+
+fn parse_bit_and(tokens: &mut Tokens<'_>) -> Result<Expression, Diagnostic> {
+    parse_binary(tokens, parse_shift, |token| match token {
+        Token::Ampersand => Some(Operation::BitAnd),
+        _ => None,
+    })
+}
+
+fn parse_shift(tokens: &mut Tokens<'_>) -> Result<Expression, Diagnostic> {
+    parse_binary(tokens, parse_term, |token| match token {
+        Token::ShiftLeft => Some(Operation::ShiftLeft),
+        Token::ShiftRight => Some(Operation::ShiftRight),
+        _ => None,
+    })
+}
+
+fn parse_term(tokens: &mut Tokens<'_>) -> Result<Expression, Diagnostic> {
+    parse_binary(tokens, parse_factor, |token| match token {
+        Token::Plus => Some(Operation::Add),
+        Token::Minus => Some(Operation::Sub),
+        _ => None,
+    })
+}
+
+fn parse_factor(tokens: &mut Tokens<'_>) -> Result<Expression, Diagnostic> {
+    parse_binary(tokens, parse_unary, |token| match token {
+        Token::Star => Some(Operation::Mul),
+        Token::Slash => Some(Operation::Div),
+        Token::Percent => Some(Operation::Mod),
+        _ => None,
+    })
+}
+
+fn parse_unary(tokens: &mut Tokens<'_>) -> Result<Expression, Diagnostic> {
+    // TODO: Unary minus
+    let token = peek(tokens);
+    if token.token == Token::Tilde {
+        tokens.next();
+        // ~x is -1 - x in two's complement
+        let operand = parse_unary(tokens)?;
+        return Ok(Expression::new(
+            ExpressionKind::BinaryOp {
+                operator: Operation::Sub,
+                left: Box::new(Expression::new(ExpressionKind::Integer(-1), token.position)),
+                right: Box::new(operand),
+            },
+            token.position,
+        ));
+    }
+    parse_primary(tokens)
+}
+
+fn parse_primary(tokens: &mut Tokens<'_>) -> Result<Expression, Diagnostic> {
+    let token = next(tokens);
+    let span = token.position;
+    let expr = match &token.token {
+        Token::Literal(value) => parse_literal(value, span)?,
+        Token::Integer(value) => Expression::new(ExpressionKind::Integer(*value), span),
+        Token::Character(value) => Expression::new(ExpressionKind::Character(*value), span),
+        Token::Identifier(id) => match peek(tokens).token {
+            Token::LParen => {
                 tokens.next();
-                let right = parse_unary(tokens)?;
-                expr = Expression::BinaryOp {
-                    operator: Operation::Mul,
-                    left: Box::new(expr),
-                    right: Box::new(right),
-                };
+                let args = parse_arguments(tokens)?;
+                Expression::new(ExpressionKind::Call(id.clone(), args), span)
             }
-            SourceToken {
-                token: Token::Slash,
-                ..
-            } => {
+            Token::DoubleColon => {
                 tokens.next();
-                let right = parse_unary(tokens)?;
-                expr = Expression::BinaryOp {
-                    operator: Operation::Div,
-                    left: Box::new(expr),
-                    right: Box::new(right),
-                };
+                let (variant, _) = expect_identifier(tokens)?;
+                Expression::new(ExpressionKind::EnumVariant(id.clone(), variant), span)
             }
-            SourceToken {
-                token: Token::Percent,
-                ..
-            } => {
+            Token::LSquare => {
                 tokens.next();
-                let right = parse_unary(tokens)?;
-                expr = Expression::BinaryOp {
-                    operator: Operation::Mod,
-                    left: Box::new(expr),
-                    right: Box::new(right),
-                };
+                let index = parse_expression(tokens)?;
+                expect(tokens, Token::RSquare)?;
+                Expression::new(ExpressionKind::Index(id.clone(), Box::new(index)), span)
             }
-            _ => break,
+            _ => Expression::new(ExpressionKind::Variable(id.clone()), span),
+        },
+        Token::LParen => {
+            let expr = parse_expression(tokens)?;
+            expect(tokens, Token::RParen)?;
+            expr
         }
-    }
+        token => {
+            return Err(Diagnostic::new(
+                format!("Unexpected token in expression: {}", token),
+                span,
+            ))
+        }
+    };
+
     Ok(expr)
 }
 
-fn parse_unary(
-    tokens: &mut std::iter::Peekable<std::slice::Iter<'_, SourceToken>>,
-) -> Result<Expression, String> {
-    let mut expr: Expression = parse_primary(tokens)?;
-    while let Some(&token) = tokens.peek() {
-        match token {
-            // Token::Minus => {
-            //     tokens.next();
-            //     expr = Expression::BinaryOp {
-            //         operator: Operation::Sub,
-            //         left: Box::new(Expression::Integer(0)),
-            //         right: Box::new(expr),
-            //     };
-            // }
-            _ => break,
+/// Splits a string literal into text and `{variable}` placeholders, `{{` and `}}` stand for
+/// literal braces. Braces around anything else than a variable name are kept as they are.
+/// Literals without placeholders stay plain literals.
+fn parse_literal(value: &str, span: Span) -> Result<Expression, Diagnostic> {
+    fn advance(position: &mut Span, ch: char) {
+        if ch == '\n' {
+            position.line += 1;
+            position.column = 1;
+        } else {
+            position.column += 1;
         }
     }
-    Ok(expr)
-}
 
-fn parse_primary(
-    tokens: &mut std::iter::Peekable<std::slice::Iter<'_, SourceToken>>,
-) -> Result<Expression, String> {
-    let token = tokens.next();
-    let expr = match token {
-        Some(SourceToken {
-            token: Token::Literal(ref value),
-            ..
-        }) => Expression::Literal(value.clone()),
-        Some(SourceToken {
-            token: Token::Integer(value),
-            ..
-        }) => Expression::Integer(*value),
-        Some(SourceToken {
-            token: Token::Identifier(ref id),
-            ..
-        }) => Expression::Variable(id.clone()),
-        Some(SourceToken {
-            token: Token::LParen,
-            ..
-        }) => {
-            let expr = parse_expression(tokens)?;
-            if let Some(SourceToken {
-                token: Token::RParen,
-                ..
-            }) = tokens.peek()
-            {
-                tokens.next();
-                expr
-            } else {
-                return Err(format!(
-                    "Expected ) at {}:{}",
-                    token.unwrap().position.0,
-                    token.unwrap().position.1
-                ));
+    /// Name of the placeholder that starts after an opening brace, if it is one
+    fn placeholder(mut chars: impl Iterator<Item = char>) -> Option<String> {
+        let mut name = String::new();
+        loop {
+            match chars.next()? {
+                '}' => break,
+                ch if ch.is_ascii_alphanumeric() || ch == '_' => name.push(ch),
+                _ => return None,
             }
         }
-        _ => {
-            return Err(format!(
-                "Primary, unexpected token: {:?} at {}:{}",
-                token.unwrap().token,
-                token.unwrap().position.0,
-                token.unwrap().position.1,
-            ))
-        }
+        name.starts_with(|ch: char| ch.is_ascii_alphabetic())
+            .then_some(name)
+    }
+
+    let mut parts = vec![];
+    let mut text = String::new();
+    // Skip the opening quote
+    let mut position = Span {
+        column: span.column + 1,
+        ..span
     };
+    let mut chars = value.chars().peekable();
+    while let Some(ch) = chars.next() {
+        advance(&mut position, ch);
+        match ch {
+            '{' | '}' if chars.peek() == Some(&ch) => {
+                chars.next();
+                advance(&mut position, ch);
+                text.push(ch);
+            }
+            '{' => {
+                let Some(name) = placeholder(chars.clone()) else {
+                    text.push(ch);
+                    continue;
+                };
+                let name_span = position;
+                for ch in chars.by_ref().take(name.len() + 1) {
+                    advance(&mut position, ch);
+                }
+                if !text.is_empty() {
+                    parts.push(InterpolationPart::Text(std::mem::take(&mut text)));
+                }
+                parts.push(InterpolationPart::Value(Expression::new(
+                    ExpressionKind::Variable(name),
+                    name_span,
+                )));
+            }
+            _ => text.push(ch),
+        }
+    }
 
-    Ok(expr)
+    if parts.is_empty() {
+        return Ok(Expression::new(ExpressionKind::Literal(text), span));
+    }
+    if !text.is_empty() {
+        parts.push(InterpolationPart::Text(text));
+    }
+    Ok(Expression::new(ExpressionKind::Interpolation(parts), span))
 }
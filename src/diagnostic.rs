@@ -0,0 +1,41 @@
+use crate::lexer::Span;
+
+/// An error reported by the parser or the transpiler, pointing back at the source code.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub message: String,
+    pub span: Span,
+    /// Name of the file of the span, set for programs made of several files
+    pub file: Option<String>,
+    /// Further locations involved in the error, like the macro calls that produced the code
+    pub notes: Vec<Diagnostic>,
+}
+
+impl Diagnostic {
+    pub fn new(message: impl Into<String>, span: Span) -> Self {
+        Diagnostic {
+            message: message.into(),
+            span,
+            file: None,
+            notes: vec![],
+        }
+    }
+
+    pub fn with_note(mut self, message: impl Into<String>, span: Span) -> Self {
+        self.notes.push(Diagnostic::new(message, span));
+        self
+    }
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{} at {}:{}", self.message, file, self.span)?,
+            None => write!(f, "{} at {}", self.message, self.span)?,
+        }
+        for note in &self.notes {
+            write!(f, "\n  {}", note)?;
+        }
+        Ok(())
+    }
+}
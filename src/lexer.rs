@@ -1,16 +1,31 @@
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Token {
     Func,
     Return,
     Const,
     Int,
     String,
+    Str,
+    Ptr,
+    Map,
+    Import,
+    Macro,
+    Label,
+    Goto,
+    Asm,
+    Uninit,
     While,
+    Switch,
+    Case,
+    Default,
+    Enum,
     CurlyL,
     CurlyR,
     Identifier(String),
     Assign,
     Integer(i32),
+    /// 'c'
+    Character(char),
     Semicolon,
     Print,
     /// {}
@@ -22,6 +37,12 @@ pub enum Token {
     /// ]
     RSquare,
     Comma,
+    /// ?
+    Question,
+    /// :
+    Colon,
+    /// ::
+    DoubleColon,
 
     Plus,
     Minus,
@@ -29,6 +50,32 @@ pub enum Token {
     /// /
     Slash,
     Percent,
+    /// +=
+    PlusAssign,
+    /// -=
+    MinusAssign,
+    /// *=
+    StarAssign,
+    /// /=
+    SlashAssign,
+    /// %=
+    PercentAssign,
+    /// ++
+    Increment,
+    /// --
+    Decrement,
+    /// &
+    Ampersand,
+    /// |
+    Pipe,
+    /// ^
+    Caret,
+    /// ~
+    Tilde,
+    /// <<
+    ShiftLeft,
+    /// >>
+    ShiftRight,
 
     /// ==
     Equals,
@@ -36,27 +83,43 @@ pub enum Token {
     Less,
     /// <=
     LessOrEqual,
+    /// !=
+    NotEquals,
+    /// >
+    Greater,
+    /// >=
+    GreaterOrEqual,
     Literal(String),
 
-    /// proc
-    Procedure,
-
     Exit,
+    #[allow(clippy::upper_case_acronyms)]
     EOF,
 }
 
+/// Location of a token or an AST node in the source code (1-based).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Span {
+    /// Index of the file in [`crate::source::Sources`], 0 for the main file
+    pub file: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl std::fmt::Display for Span {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
 #[derive(Debug)]
 pub struct SourceToken {
     pub token: Token,
-    pub position: (usize, usize),
+    pub position: Span,
 }
 
 impl SourceToken {
-    pub fn get(token: Token, position: (usize, usize)) -> Self {
-        SourceToken {
-            token,
-            position: position,
-        }
+    pub fn get(token: Token, position: Span) -> Self {
+        SourceToken { token, position }
     }
 }
 
@@ -68,7 +131,20 @@ impl std::fmt::Display for Token {
             Token::Const => "const".to_string(),
             Token::Int => "int".to_string(),
             Token::String => "string".to_string(),
+            Token::Str => "str".to_string(),
+            Token::Ptr => "ptr".to_string(),
+            Token::Import => "import".to_string(),
+            Token::Macro => "macro".to_string(),
+            Token::Label => "label".to_string(),
+            Token::Goto => "goto".to_string(),
+            Token::Asm => "asm".to_string(),
+            Token::Uninit => "uninit".to_string(),
+            Token::Map => "map".to_string(),
             Token::While => "while".to_string(),
+            Token::Switch => "switch".to_string(),
+            Token::Case => "case".to_string(),
+            Token::Default => "default".to_string(),
+            Token::Enum => "enum".to_string(),
             Token::CurlyL => "{".to_string(),
             Token::CurlyR => "}".to_string(),
             Token::LSquare => "[".to_string(),
@@ -76,20 +152,39 @@ impl std::fmt::Display for Token {
             Token::Identifier(s) => s.clone(),
             Token::Assign => "=".to_string(),
             Token::Integer(i) => i.to_string(),
+            Token::Character(ch) => format!("{:?}", ch),
             Token::Semicolon => ";".to_string(),
             Token::Print => "print".to_string(),
             Token::LParen => "(".to_string(),
             Token::RParen => ")".to_string(),
-            Token::Comma => ".to_string(),".to_string(),
+            Token::Comma => ",".to_string(),
+            Token::Question => "?".to_string(),
+            Token::Colon => ":".to_string(),
+            Token::DoubleColon => "::".to_string(),
             Token::Plus => "+".to_string(),
             Token::Minus => "-".to_string(),
-            Token::Star => "".to_string(),
+            Token::Star => "*".to_string(),
             Token::Slash => "/".to_string(),
             Token::Percent => "%".to_string(),
+            Token::PlusAssign => "+=".to_string(),
+            Token::MinusAssign => "-=".to_string(),
+            Token::StarAssign => "*=".to_string(),
+            Token::SlashAssign => "/=".to_string(),
+            Token::PercentAssign => "%=".to_string(),
+            Token::Increment => "++".to_string(),
+            Token::Decrement => "--".to_string(),
+            Token::Ampersand => "&".to_string(),
+            Token::Pipe => "|".to_string(),
+            Token::Caret => "^".to_string(),
+            Token::Tilde => "~".to_string(),
+            Token::ShiftLeft => "<<".to_string(),
+            Token::ShiftRight => ">>".to_string(),
             Token::Equals => "==".to_string(),
             Token::Less => "<".to_string(),
             Token::LessOrEqual => "<=".to_string(),
-            Token::Procedure => "proc".to_string(),
+            Token::NotEquals => "!=".to_string(),
+            Token::Greater => ">".to_string(),
+            Token::GreaterOrEqual => ">=".to_string(),
             Token::Literal(v) => v.clone(),
             Token::Exit => "exit".to_string(),
             Token::EOF => "EOF".to_string(),
@@ -101,11 +196,21 @@ impl std::fmt::Display for Token {
 pub struct Lexer<'a> {
     input: &'a str,
     position: usize,
+    file: usize,
 }
 
 impl<'a> Lexer<'a> {
     pub fn new(input: &'a str) -> Self {
-        Lexer { input, position: 0 }
+        Self::for_file(input, 0)
+    }
+
+    /// Lexer for one of the files of a program, the spans of its tokens point to the file
+    pub fn for_file(input: &'a str, file: usize) -> Self {
+        Lexer {
+            input,
+            position: 0,
+            file,
+        }
     }
 
     pub fn peek(&self) -> Option<char> {
@@ -119,7 +224,7 @@ impl<'a> Lexer<'a> {
     fn read_integer(&mut self) -> i32 {
         let mut value = String::new();
         while let Some(ch) = self.peek() {
-            if ch.is_digit(10) {
+            if ch.is_ascii_digit() {
                 value.push(ch);
                 self.advance();
             } else {
@@ -129,7 +234,7 @@ impl<'a> Lexer<'a> {
         value.parse::<i32>().unwrap()
     }
 
-    fn getFileLocation(&self) -> (usize, usize) {
+    fn get_file_location(&self) -> Span {
         // TODO: This is a very inefficient way to do this!
         let mut line = 1;
         let mut column = 1;
@@ -144,7 +249,11 @@ impl<'a> Lexer<'a> {
                 column += 1;
             }
         }
-        (line, column)
+        Span {
+            file: self.file,
+            line,
+            column,
+        }
     }
 
     pub fn tokenize(&mut self) -> Vec<SourceToken> {
@@ -153,7 +262,7 @@ impl<'a> Lexer<'a> {
         while let Some(ch) = self.peek() {
             match ch {
                 '"' => {
-                    let start_pos = self.getFileLocation();
+                    let start_pos = self.get_file_location();
                     self.advance();
                     let mut value = String::new();
                     while let Some(ch) = self.peek() {
@@ -167,16 +276,36 @@ impl<'a> Lexer<'a> {
                     self.advance();
                     tokens.push(SourceToken::get(Token::Literal(value), start_pos));
                 }
+                '\'' => {
+                    let start_pos = self.get_file_location();
+                    self.advance();
+                    let mut value = self.peek().unwrap_or_default();
+                    self.advance();
+                    if value == '\\' {
+                        value = match self.peek() {
+                            Some('n') => '\n',
+                            Some('t') => '\t',
+                            Some('0') => '\0',
+                            Some(ch) => ch,
+                            None => value,
+                        };
+                        self.advance();
+                    }
+                    if self.peek() == Some('\'') {
+                        self.advance();
+                    }
+                    tokens.push(SourceToken::get(Token::Character(value), start_pos));
+                }
                 '[' => {
-                    tokens.push(SourceToken::get(Token::LSquare, self.getFileLocation()));
+                    tokens.push(SourceToken::get(Token::LSquare, self.get_file_location()));
                     self.advance();
                 }
                 ']' => {
-                    tokens.push(SourceToken::get(Token::RSquare, self.getFileLocation()));
+                    tokens.push(SourceToken::get(Token::RSquare, self.get_file_location()));
                     self.advance();
                 }
                 'a'..='z' | 'A'..='Z' => {
-                    let start_pos = self.getFileLocation();
+                    let start_pos = self.get_file_location();
                     let mut identifier = String::new();
                     while let Some(ch) = self.peek() {
                         if ch.is_ascii_alphanumeric() || ch == '_' {
@@ -191,82 +320,155 @@ impl<'a> Lexer<'a> {
                         "return" => tokens.push(SourceToken::get(Token::Return, start_pos)),
                         "const" => tokens.push(SourceToken::get(Token::Const, start_pos)),
                         "string" => tokens.push(SourceToken::get(Token::String, start_pos)),
+                        "str" => tokens.push(SourceToken::get(Token::Str, start_pos)),
                         "int" => tokens.push(SourceToken::get(Token::Int, start_pos)),
+                        "ptr" => tokens.push(SourceToken::get(Token::Ptr, start_pos)),
+                        "map" => tokens.push(SourceToken::get(Token::Map, start_pos)),
+                        "import" => tokens.push(SourceToken::get(Token::Import, start_pos)),
+                        "macro" => tokens.push(SourceToken::get(Token::Macro, start_pos)),
+                        "label" => tokens.push(SourceToken::get(Token::Label, start_pos)),
+                        "goto" => tokens.push(SourceToken::get(Token::Goto, start_pos)),
+                        "asm" => tokens.push(SourceToken::get(Token::Asm, start_pos)),
+                        "uninit" => tokens.push(SourceToken::get(Token::Uninit, start_pos)),
                         "print" => tokens.push(SourceToken::get(Token::Print, start_pos)),
                         "exit" => tokens.push(SourceToken::get(Token::Exit, start_pos)),
                         "while" => tokens.push(SourceToken::get(Token::While, start_pos)),
+                        "switch" => tokens.push(SourceToken::get(Token::Switch, start_pos)),
+                        "case" => tokens.push(SourceToken::get(Token::Case, start_pos)),
+                        "default" => tokens.push(SourceToken::get(Token::Default, start_pos)),
+                        "enum" => tokens.push(SourceToken::get(Token::Enum, start_pos)),
                         _ => {
                             tokens.push(SourceToken::get(Token::Identifier(identifier), start_pos))
                         }
                     }
                 }
                 '0'..='9' => {
-                    let start_pos = self.getFileLocation();
+                    let start_pos = self.get_file_location();
                     let value: i32 = self.read_integer();
                     tokens.push(SourceToken::get(Token::Integer(value), start_pos));
                 }
                 '-' => {
-                    let start_pos = self.getFileLocation();
+                    let start_pos = self.get_file_location();
                     self.advance();
                     if let Some(ch) = self.peek() {
-                        if ch.is_digit(10) {
+                        if ch.is_ascii_digit() {
                             let value = self.read_integer();
                             tokens.push(SourceToken::get(Token::Integer(-value), start_pos));
+                        } else if ch == '=' {
+                            tokens.push(SourceToken::get(Token::MinusAssign, start_pos));
+                            self.advance();
+                        } else if ch == '-' {
+                            tokens.push(SourceToken::get(Token::Decrement, start_pos));
+                            self.advance();
                         } else {
                             tokens.push(SourceToken::get(Token::Minus, start_pos));
                         }
                     }
                 }
                 ',' => {
-                    tokens.push(SourceToken::get(Token::Comma, self.getFileLocation()));
+                    tokens.push(SourceToken::get(Token::Comma, self.get_file_location()));
+                    self.advance();
+                }
+                '?' => {
+                    tokens.push(SourceToken::get(Token::Question, self.get_file_location()));
                     self.advance();
                 }
+                ':' => {
+                    let start_pos = self.get_file_location();
+                    self.advance();
+                    if self.peek() == Some(':') {
+                        tokens.push(SourceToken::get(Token::DoubleColon, start_pos));
+                        self.advance();
+                    } else {
+                        tokens.push(SourceToken::get(Token::Colon, start_pos));
+                    }
+                }
                 '+' => {
-                    let start_pos = self.getFileLocation();
+                    let start_pos = self.get_file_location();
                     self.advance();
                     if let Some(ch) = self.peek() {
-                        if ch.is_digit(10) {
+                        if ch.is_ascii_digit() {
                             let value = self.read_integer();
                             tokens.push(SourceToken::get(Token::Integer(value), start_pos));
+                        } else if ch == '=' {
+                            tokens.push(SourceToken::get(Token::PlusAssign, start_pos));
+                            self.advance();
+                        } else if ch == '+' {
+                            tokens.push(SourceToken::get(Token::Increment, start_pos));
+                            self.advance();
                         } else {
                             tokens.push(SourceToken::get(Token::Plus, start_pos));
                         }
                     }
                 }
                 '*' => {
-                    tokens.push(SourceToken::get(Token::Star, self.getFileLocation()));
+                    let start_pos = self.get_file_location();
                     self.advance();
+                    if self.peek() == Some('=') {
+                        tokens.push(SourceToken::get(Token::StarAssign, start_pos));
+                        self.advance();
+                    } else {
+                        tokens.push(SourceToken::get(Token::Star, start_pos));
+                    }
                 }
                 '/' => {
-                    tokens.push(SourceToken::get(Token::Slash, self.getFileLocation()));
+                    let start_pos = self.get_file_location();
                     self.advance();
+                    if self.peek() == Some('=') {
+                        tokens.push(SourceToken::get(Token::SlashAssign, start_pos));
+                        self.advance();
+                    } else {
+                        tokens.push(SourceToken::get(Token::Slash, start_pos));
+                    }
                 }
                 '%' => {
-                    tokens.push(SourceToken::get(Token::Percent, self.getFileLocation()));
+                    let start_pos = self.get_file_location();
+                    self.advance();
+                    if self.peek() == Some('=') {
+                        tokens.push(SourceToken::get(Token::PercentAssign, start_pos));
+                        self.advance();
+                    } else {
+                        tokens.push(SourceToken::get(Token::Percent, start_pos));
+                    }
+                }
+                '&' => {
+                    tokens.push(SourceToken::get(Token::Ampersand, self.get_file_location()));
+                    self.advance();
+                }
+                '|' => {
+                    tokens.push(SourceToken::get(Token::Pipe, self.get_file_location()));
+                    self.advance();
+                }
+                '^' => {
+                    tokens.push(SourceToken::get(Token::Caret, self.get_file_location()));
+                    self.advance();
+                }
+                '~' => {
+                    tokens.push(SourceToken::get(Token::Tilde, self.get_file_location()));
                     self.advance();
                 }
                 '(' => {
-                    tokens.push(SourceToken::get(Token::LParen, self.getFileLocation()));
+                    tokens.push(SourceToken::get(Token::LParen, self.get_file_location()));
                     self.advance();
                 }
                 ')' => {
-                    tokens.push(SourceToken::get(Token::RParen, self.getFileLocation()));
+                    tokens.push(SourceToken::get(Token::RParen, self.get_file_location()));
                     self.advance();
                 }
                 '{' => {
-                    tokens.push(SourceToken::get(Token::CurlyL, self.getFileLocation()));
+                    tokens.push(SourceToken::get(Token::CurlyL, self.get_file_location()));
                     self.advance();
                 }
                 '}' => {
-                    tokens.push(SourceToken::get(Token::CurlyR, self.getFileLocation()));
+                    tokens.push(SourceToken::get(Token::CurlyR, self.get_file_location()));
                     self.advance();
                 }
                 ';' => {
-                    tokens.push(SourceToken::get(Token::Semicolon, self.getFileLocation()));
+                    tokens.push(SourceToken::get(Token::Semicolon, self.get_file_location()));
                     self.advance();
                 }
                 '=' => {
-                    let start_pos = self.getFileLocation();
+                    let start_pos = self.get_file_location();
                     self.advance();
                     if self.peek() == Some('=') {
                         tokens.push(SourceToken::get(Token::Equals, start_pos));
@@ -276,21 +478,45 @@ impl<'a> Lexer<'a> {
                     }
                 }
                 '<' => {
-                    let start_pos = self.getFileLocation();
+                    let start_pos = self.get_file_location();
                     self.advance();
                     if self.peek() == Some('=') {
                         tokens.push(SourceToken::get(Token::LessOrEqual, start_pos));
                         self.advance();
+                    } else if self.peek() == Some('<') {
+                        tokens.push(SourceToken::get(Token::ShiftLeft, start_pos));
+                        self.advance();
                     } else {
                         tokens.push(SourceToken::get(Token::Less, start_pos));
                     }
                 }
+                '>' => {
+                    let start_pos = self.get_file_location();
+                    self.advance();
+                    if self.peek() == Some('=') {
+                        tokens.push(SourceToken::get(Token::GreaterOrEqual, start_pos));
+                        self.advance();
+                    } else if self.peek() == Some('>') {
+                        tokens.push(SourceToken::get(Token::ShiftRight, start_pos));
+                        self.advance();
+                    } else {
+                        tokens.push(SourceToken::get(Token::Greater, start_pos));
+                    }
+                }
+                '!' => {
+                    let start_pos = self.get_file_location();
+                    self.advance();
+                    if self.peek() == Some('=') {
+                        tokens.push(SourceToken::get(Token::NotEquals, start_pos));
+                        self.advance();
+                    }
+                }
                 _ => {
                     self.advance();
                 }
             }
         }
-        tokens.push(SourceToken::get(Token::EOF, self.getFileLocation()));
+        tokens.push(SourceToken::get(Token::EOF, self.get_file_location()));
         tokens
     }
 }
@@ -1,10 +1,16 @@
+use std::collections::HashMap;
+
 use wasm_bindgen::prelude::*;
 use web_sys::console;
 
 mod ast;
+mod diagnostic;
 mod lexer;
+mod macros;
+mod source;
 mod specs;
 mod transpiler;
+#[cfg(test)]
 mod util;
 
 #[wasm_bindgen]
@@ -20,6 +26,7 @@ pub struct InterpreterOutput {
     ast: String,
     debug_output: String,
     whitespace_output: String,
+    warnings: String,
 }
 
 #[wasm_bindgen]
@@ -35,27 +42,83 @@ impl InterpreterOutput {
     pub fn get_whitespace_output(&self) -> String {
         self.whitespace_output.clone()
     }
+
+    /// One warning per line, empty when there are none
+    pub fn get_warnings(&self) -> String {
+        self.warnings.clone()
+    }
+}
+
+/// Files that programs can import, filled from JS
+#[wasm_bindgen]
+#[derive(Default)]
+pub struct FileSystem {
+    files: HashMap<String, String>,
+}
+
+#[wasm_bindgen]
+impl FileSystem {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_file(&mut self, path: &str, content: &str) {
+        self.files.insert(path.to_string(), content.to_string());
+    }
 }
 
 #[wasm_bindgen]
 pub fn gen_all(code: &str) -> InterpreterOutput {
-    let mut lexer = lexer::Lexer::new(code);
-    let tokens: Vec<lexer::SourceToken> = lexer.tokenize();
-    let ast = ast::parse(&tokens);
-    match ast {
-        Ok(ast) => {
+    gen_all_with_files(code, &FileSystem::new())
+}
+
+/// Like [`gen_all`], with the files that the code can import
+#[wasm_bindgen]
+pub fn gen_all_with_files(code: &str, files: &FileSystem) -> InterpreterOutput {
+    gen_all_with_options(code, files, true)
+}
+
+/// Like [`gen_all_with_files`], `zero_initialize` set to false leaves declared variables
+/// unset, which makes the output smaller
+#[wasm_bindgen]
+pub fn gen_all_with_options(
+    code: &str,
+    files: &FileSystem,
+    zero_initialize: bool,
+) -> InterpreterOutput {
+    let options = transpiler::Options { zero_initialize };
+    match source::load("main", code, &files.files) {
+        Ok((ast, sources)) => {
             let ast_output = format!("{:#?}", ast);
-            let transpiler::CodeOutput { code, debug_code } = transpiler::transpile(ast, None);
-            InterpreterOutput {
-                ast: ast_output,
-                debug_output: debug_code,
-                whitespace_output: code,
+            let state = transpiler::State::with_options(options);
+            match transpiler::transpile_with_warnings(ast, Some(state)) {
+                Ok((transpiler::CodeOutput { code, debug_code }, warnings)) => InterpreterOutput {
+                    ast: ast_output,
+                    debug_output: debug_code,
+                    whitespace_output: code,
+                    warnings: warnings
+                        .into_iter()
+                        .map(|warning| sources.locate(warning).to_string())
+                        .collect::<Vec<_>>()
+                        .join("\n"),
+                },
+                Err(err) => {
+                    let err = sources.locate(err);
+                    InterpreterOutput {
+                        ast: ast_output,
+                        debug_output: err.to_string(),
+                        whitespace_output: err.to_string(),
+                        warnings: String::new(),
+                    }
+                }
             }
         }
         Err(err) => InterpreterOutput {
-            ast: format!("{:#?}", err),
-            debug_output: format!("{:#?}", err),
-            whitespace_output: format!("{:#?}", err),
+            ast: err.to_string(),
+            debug_output: err.to_string(),
+            whitespace_output: err.to_string(),
+            warnings: String::new(),
         },
     }
 }
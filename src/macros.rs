@@ -0,0 +1,444 @@
+//! Macros, expanded before the program is transpiled. A statement that calls a macro is
+//! replaced by the body of the macro, with every parameter replaced by the expression passed
+//! for it. Variables declared inside of the body are renamed at every call, so they can never
+//! hide or clash with the variables of the caller. Other names refer to the variables that are
+//! visible where the macro is called.
+//!
+//! ```text
+//! macro swap(a, b) { int t = a; a = b; b = t; }
+//! swap(x, y);
+//! ```
+
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    ast::{
+        Expression, ExpressionKind, Instruction, InterpolationPart, Macro, Operand, Statement,
+        StatementKind, SwitchCase,
+    },
+    diagnostic::Diagnostic,
+    lexer::Span,
+};
+
+/// Replaces the calls of the macros defined at the top level of the program by their bodies
+pub fn expand(program: Vec<Statement>) -> Result<Vec<Statement>, Diagnostic> {
+    let mut expander = Expander {
+        macros: HashMap::new(),
+        stack: vec![],
+        expansions: 0,
+    };
+    let mut statements = vec![];
+    for statement in program {
+        match statement.kind {
+            StatementKind::Macro(definition) => {
+                if expander.macros.contains_key(&definition.name) {
+                    return Err(Diagnostic::new(
+                        format!("Macro {} is already defined", definition.name),
+                        statement.span,
+                    ));
+                }
+                expander.macros.insert(definition.name.clone(), definition);
+            }
+            kind => statements.push(Statement::new(kind, statement.span)),
+        }
+    }
+    statements
+        .into_iter()
+        .map(|statement| expander.statement(statement))
+        .collect()
+}
+
+/// Rebuilds the AST, giving a chance to replace statements, expressions and the names of
+/// variables. The default implementations only visit the children.
+trait Rewriter {
+    fn statement(&mut self, statement: Statement) -> Result<Statement, Diagnostic> {
+        walk_statement(self, statement)
+    }
+
+    fn expression(&mut self, expression: Expression) -> Result<Expression, Diagnostic> {
+        walk_expression(self, expression)
+    }
+
+    /// Name of a variable that is declared, assigned or indexed
+    fn name(&mut self, name: String, _span: Span) -> Result<String, Diagnostic> {
+        Ok(name)
+    }
+
+    /// Name of a label that is defined or jumped to
+    fn label(&mut self, name: String) -> String {
+        name
+    }
+}
+
+fn walk_statements<R: Rewriter + ?Sized>(
+    rewriter: &mut R,
+    statements: Vec<Statement>,
+) -> Result<Vec<Statement>, Diagnostic> {
+    statements
+        .into_iter()
+        .map(|statement| rewriter.statement(statement))
+        .collect()
+}
+
+fn walk_statement<R: Rewriter + ?Sized>(
+    rewriter: &mut R,
+    statement: Statement,
+) -> Result<Statement, Diagnostic> {
+    let span = statement.span;
+    let kind = match statement.kind {
+        StatementKind::IntDeclaration(name) => {
+            StatementKind::IntDeclaration(rewriter.name(name, span)?)
+        }
+        StatementKind::StringDeclaration(name, length) => {
+            StatementKind::StringDeclaration(rewriter.name(name, span)?, length)
+        }
+        StatementKind::StrDeclaration(name) => {
+            StatementKind::StrDeclaration(rewriter.name(name, span)?)
+        }
+        StatementKind::PointerDeclaration(name) => {
+            StatementKind::PointerDeclaration(rewriter.name(name, span)?)
+        }
+        StatementKind::MapDeclaration(name, key) => {
+            StatementKind::MapDeclaration(rewriter.name(name, span)?, key)
+        }
+        StatementKind::EnumDeclaration(type_name, name) => {
+            StatementKind::EnumDeclaration(type_name, rewriter.name(name, span)?)
+        }
+        StatementKind::Assignment(name, value) => {
+            StatementKind::Assignment(rewriter.name(name, span)?, rewriter.expression(value)?)
+        }
+        StatementKind::CompoundAssignment(name, operator, value) => {
+            StatementKind::CompoundAssignment(
+                rewriter.name(name, span)?,
+                operator,
+                rewriter.expression(value)?,
+            )
+        }
+        StatementKind::IndexAssignment {
+            name,
+            index,
+            operator,
+            value,
+        } => StatementKind::IndexAssignment {
+            name: rewriter.name(name, span)?,
+            index: Box::new(rewriter.expression(*index)?),
+            operator,
+            value: rewriter.expression(value)?,
+        },
+        StatementKind::WhileLoop { condition, body } => StatementKind::WhileLoop {
+            condition: Box::new(rewriter.expression(*condition)?),
+            body: Box::new(rewriter.statement(*body)?),
+        },
+        StatementKind::Call(name, args) => StatementKind::Call(
+            name,
+            args.into_iter()
+                .map(|arg| rewriter.expression(arg))
+                .collect::<Result<_, _>>()?,
+        ),
+        StatementKind::Block(statements) => {
+            StatementKind::Block(walk_statements(rewriter, statements)?)
+        }
+        StatementKind::Switch {
+            value,
+            cases,
+            default,
+        } => StatementKind::Switch {
+            value: Box::new(rewriter.expression(*value)?),
+            cases: cases
+                .into_iter()
+                .map(|case| {
+                    Ok(SwitchCase {
+                        value: rewriter.expression(case.value)?,
+                        body: rewriter.statement(case.body)?,
+                    })
+                })
+                .collect::<Result<_, Diagnostic>>()?,
+            default: match default {
+                Some(default) => Some(Box::new(rewriter.statement(*default)?)),
+                None => None,
+            },
+        },
+        StatementKind::Function(mut function) => {
+            function.body = walk_statements(rewriter, function.body)?;
+            StatementKind::Function(function)
+        }
+        StatementKind::Return(value) => StatementKind::Return(match value {
+            Some(value) => Some(rewriter.expression(value)?),
+            None => None,
+        }),
+        StatementKind::Expansion(name, body) => {
+            StatementKind::Expansion(name, walk_statements(rewriter, body)?)
+        }
+        StatementKind::Uninitialized(declaration) => {
+            StatementKind::Uninitialized(Box::new(rewriter.statement(*declaration)?))
+        }
+        StatementKind::Label(name) => StatementKind::Label(rewriter.label(name)),
+        StatementKind::Goto(name) => StatementKind::Goto(rewriter.label(name)),
+        StatementKind::Asm(instructions) => StatementKind::Asm(
+            instructions
+                .into_iter()
+                .map(|instruction| {
+                    let operand = match instruction.operand {
+                        Some(Operand::Address(name)) => {
+                            Some(Operand::Address(rewriter.name(name, instruction.span)?))
+                        }
+                        Some(Operand::Label(name)) => Some(Operand::Label(rewriter.label(name))),
+                        operand => operand,
+                    };
+                    Ok(Instruction {
+                        operand,
+                        ..instruction
+                    })
+                })
+                .collect::<Result<_, Diagnostic>>()?,
+        ),
+        kind @ (StatementKind::EnumDefinition(..)
+        | StatementKind::Exit
+        | StatementKind::Import(_)
+        | StatementKind::Macro(_)) => kind,
+    };
+    Ok(Statement::new(kind, span))
+}
+
+fn walk_expression<R: Rewriter + ?Sized>(
+    rewriter: &mut R,
+    expression: Expression,
+) -> Result<Expression, Diagnostic> {
+    let span = expression.span;
+    let kind = match expression.kind {
+        ExpressionKind::Variable(name) => ExpressionKind::Variable(rewriter.name(name, span)?),
+        ExpressionKind::Index(name, index) => ExpressionKind::Index(
+            rewriter.name(name, span)?,
+            Box::new(rewriter.expression(*index)?),
+        ),
+        ExpressionKind::BinaryOp {
+            operator,
+            left,
+            right,
+        } => ExpressionKind::BinaryOp {
+            operator,
+            left: Box::new(rewriter.expression(*left)?),
+            right: Box::new(rewriter.expression(*right)?),
+        },
+        ExpressionKind::Call(name, args) => ExpressionKind::Call(
+            name,
+            args.into_iter()
+                .map(|arg| rewriter.expression(arg))
+                .collect::<Result<_, _>>()?,
+        ),
+        ExpressionKind::Interpolation(parts) => ExpressionKind::Interpolation(
+            parts
+                .into_iter()
+                .map(|part| match part {
+                    InterpolationPart::Value(value) => {
+                        Ok(InterpolationPart::Value(rewriter.expression(value)?))
+                    }
+                    text => Ok(text),
+                })
+                .collect::<Result<_, Diagnostic>>()?,
+        ),
+        ExpressionKind::Conditional {
+            condition,
+            then,
+            otherwise,
+        } => ExpressionKind::Conditional {
+            condition: Box::new(rewriter.expression(*condition)?),
+            then: Box::new(rewriter.expression(*then)?),
+            otherwise: Box::new(rewriter.expression(*otherwise)?),
+        },
+        kind @ (ExpressionKind::Integer(_)
+        | ExpressionKind::Character(_)
+        | ExpressionKind::EnumVariant(..)
+        | ExpressionKind::Literal(_)) => kind,
+    };
+    Ok(Expression::new(kind, span))
+}
+
+/// Replaces the calls of macros
+struct Expander {
+    macros: HashMap<String, Macro>,
+    /// Macros whose bodies are being expanded, for the detection of recursion
+    stack: Vec<String>,
+    /// Number of calls expanded so far, which makes the names of the variables unique
+    expansions: usize,
+}
+
+impl Rewriter for Expander {
+    fn statement(&mut self, statement: Statement) -> Result<Statement, Diagnostic> {
+        let span = statement.span;
+        match statement.kind {
+            StatementKind::Call(name, args) if self.macros.contains_key(&name) => {
+                self.expand_call(name, args, span)
+            }
+            StatementKind::Macro(_) => Err(Diagnostic::new(
+                "Macros can only be defined at the top level",
+                span,
+            )),
+            kind => walk_statement(self, Statement::new(kind, span)),
+        }
+    }
+
+    fn expression(&mut self, expression: Expression) -> Result<Expression, Diagnostic> {
+        if let ExpressionKind::Call(name, _) = &expression.kind {
+            if self.macros.contains_key(name) {
+                return Err(Diagnostic::new(
+                    format!("Macro {} can only be used as a statement", name),
+                    expression.span,
+                ));
+            }
+        }
+        walk_expression(self, expression)
+    }
+}
+
+impl Expander {
+    fn expand_call(
+        &mut self,
+        name: String,
+        args: Vec<Expression>,
+        span: Span,
+    ) -> Result<Statement, Diagnostic> {
+        if self.stack.contains(&name) {
+            return Err(Diagnostic::new(
+                format!("Recursive expansion of macro {}", name),
+                span,
+            ));
+        }
+        let definition = self.macros[&name].clone();
+        if args.len() != definition.parameters.len() {
+            return Err(Diagnostic::new(
+                format!(
+                    "{} expects {} arguments, found {}",
+                    name,
+                    definition.parameters.len(),
+                    args.len()
+                ),
+                span,
+            ));
+        }
+        // Macros in the arguments are expanded where they are written
+        let args = args
+            .into_iter()
+            .map(|arg| self.expression(arg))
+            .collect::<Result<Vec<_>, _>>()?;
+        self.expansions += 1;
+        let (mut locals, mut labels) = (HashSet::new(), HashSet::new());
+        declared_names(&definition.body, &mut locals, &mut labels);
+        let mut substitution = Substitution {
+            arguments: definition.parameters.into_iter().zip(args).collect(),
+            locals,
+            labels,
+            suffix: format!("#{}", self.expansions),
+        };
+        let body = walk_statements(&mut substitution, definition.body)
+            .map_err(|err| err.with_note(format!("in expansion of macro {}", name), span))?;
+        // Macros called by the body are expanded with the arguments already in place
+        self.stack.push(name.clone());
+        let body = walk_statements(self, body);
+        self.stack.pop();
+        let body =
+            body.map_err(|err| err.with_note(format!("in expansion of macro {}", name), span))?;
+        Ok(Statement::new(StatementKind::Expansion(name, body), span))
+    }
+}
+
+/// Collects the names of the variables and of the labels declared by the statements,
+/// including nested ones
+fn declared_names(
+    statements: &[Statement],
+    names: &mut HashSet<String>,
+    labels: &mut HashSet<String>,
+) {
+    for statement in statements {
+        match &statement.kind {
+            StatementKind::IntDeclaration(name)
+            | StatementKind::StringDeclaration(name, _)
+            | StatementKind::StrDeclaration(name)
+            | StatementKind::PointerDeclaration(name)
+            | StatementKind::MapDeclaration(name, _)
+            | StatementKind::EnumDeclaration(_, name) => {
+                names.insert(name.clone());
+            }
+            StatementKind::Label(name) => {
+                labels.insert(name.clone());
+            }
+            StatementKind::Asm(instructions) => {
+                for instruction in instructions {
+                    if let ("label", Some(Operand::Label(name))) =
+                        (instruction.mnemonic.as_str(), &instruction.operand)
+                    {
+                        labels.insert(name.clone());
+                    }
+                }
+            }
+            StatementKind::Uninitialized(declaration) => {
+                declared_names(std::slice::from_ref(declaration), names, labels)
+            }
+            StatementKind::WhileLoop { body, .. } => {
+                declared_names(std::slice::from_ref(body), names, labels)
+            }
+            StatementKind::Block(body) | StatementKind::Expansion(_, body) => {
+                declared_names(body, names, labels)
+            }
+            StatementKind::Switch { cases, default, .. } => {
+                for case in cases {
+                    declared_names(std::slice::from_ref(&case.body), names, labels);
+                }
+                if let Some(default) = default {
+                    declared_names(std::slice::from_ref(default), names, labels);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Puts the arguments of a call in place of the parameters and renames the local variables
+/// and labels
+struct Substitution {
+    arguments: HashMap<String, Expression>,
+    locals: HashSet<String>,
+    labels: HashSet<String>,
+    suffix: String,
+}
+
+impl Rewriter for Substitution {
+    fn expression(&mut self, expression: Expression) -> Result<Expression, Diagnostic> {
+        if let ExpressionKind::Variable(name) = &expression.kind {
+            if !self.locals.contains(name) {
+                if let Some(argument) = self.arguments.get(name) {
+                    return Ok(argument.clone());
+                }
+            }
+        }
+        walk_expression(self, expression)
+    }
+
+    fn name(&mut self, name: String, span: Span) -> Result<String, Diagnostic> {
+        if self.locals.contains(&name) {
+            return Ok(format!("{}{}", name, self.suffix));
+        }
+        match self.arguments.get(&name) {
+            // A parameter that is assigned or indexed has to be passed a variable
+            Some(argument) => match &argument.kind {
+                ExpressionKind::Variable(variable) => Ok(variable.clone()),
+                _ => Err(Diagnostic::new(
+                    format!(
+                        "Macro argument {} must be a variable, found {}",
+                        name, argument
+                    ),
+                    span,
+                )
+                .with_note("argument", argument.span)),
+            },
+            None => Ok(name),
+        }
+    }
+
+    fn label(&mut self, name: String) -> String {
+        match self.labels.contains(&name) {
+            true => format!("{}{}", name, self.suffix),
+            false => name,
+        }
+    }
+}
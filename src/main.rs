@@ -1,31 +1,66 @@
-use std::fs;
+use std::{env, fs};
 
 use crate::transpiler::CodeOutput;
 
 mod ast;
+mod diagnostic;
 mod lexer;
+mod macros;
+mod source;
 mod specs;
 mod transpiler;
 
 mod util;
 
+/// Imports are read from the disk, relative to the working directory
+struct DiskFiles;
+
+impl source::FileProvider for DiskFiles {
+    fn read(&self, path: &str) -> Option<String> {
+        fs::read_to_string(path).ok()
+    }
+}
+
 fn main() {
-    let code = "
+    let mut name = "main".to_string();
+    let mut code = "
     string[32] greeting = \"Hello, \";
     string[25] name;
     read(name);
     concat(greeting, name);
     print(greeting);
     exit;
-    ";
-    let mut lexer = lexer::Lexer::new(code);
+    "
+    .to_string();
+    // `--no-zero-init` leaves declared variables unset, which makes the output smaller
+    let (flags, paths): (Vec<String>, Vec<String>) =
+        env::args().skip(1).partition(|arg| arg.starts_with("--"));
+    let options = transpiler::Options {
+        zero_initialize: !flags.iter().any(|flag| flag == "--no-zero-init"),
+    };
+    // A file given on the command line replaces the program above
+    if let Some(path) = paths.into_iter().next() {
+        code = fs::read_to_string(&path).expect("Unable to read file");
+        name = path;
+    }
+    let mut lexer = lexer::Lexer::new(&code);
     let tokens: Vec<lexer::SourceToken> = lexer.tokenize();
     println!("---TOKENS---\n{:?}\n", tokens);
 
-    match ast::parse(&tokens) {
-        Ok(ast) => {
-            println!("--AST--\n{:#?}\n", ast);
-            let CodeOutput { code, debug_code } = transpiler::transpile(ast, None);
+    match source::load(&name, &code, &DiskFiles).and_then(|(ast, sources)| {
+        println!("--AST--\n{:#?}\n", ast);
+        let state = transpiler::State::with_options(options);
+        match transpiler::transpile_with_warnings(ast, Some(state)) {
+            Ok((output, warnings)) => {
+                for warning in warnings {
+                    println!("Warning: {}", sources.locate(warning));
+                }
+                Ok(output)
+            }
+            Err(err) => Err(sources.locate(err)),
+        }
+    }) {
+        Ok(CodeOutput { code, debug_code }) => {
             println!("---DEBUG---\n{}\n", debug_code);
             println!("---VISIBLE---\n{}\n", util::unbleach(code.clone()));
             fs::create_dir_all("out").expect("Unable to create directory");
@@ -0,0 +1,142 @@
+//! Programs made of several files. `import "path";` at the top level of a file is replaced by
+//! the statements of the imported file. Paths are relative to the directory of the importing
+//! file. A file that was already imported is skipped, so libraries can import each other
+//! as long as there is no cycle.
+
+use std::collections::HashMap;
+
+use crate::{
+    ast::{self, Statement, StatementKind},
+    diagnostic::Diagnostic,
+    lexer::Lexer,
+};
+
+/// File index of the spans of the prelude, see [`crate::transpiler::function`]
+pub const PRELUDE_FILE: usize = usize::MAX;
+
+/// Where imported files come from
+pub trait FileProvider {
+    /// Content of the file, `None` when it does not exist
+    fn read(&self, path: &str) -> Option<String>;
+}
+
+/// Files kept in memory, like a virtual filesystem supplied from JS
+impl FileProvider for HashMap<String, String> {
+    fn read(&self, path: &str) -> Option<String> {
+        self.get(path).cloned()
+    }
+}
+
+/// Names of the files of a program, indexed by [`crate::lexer::Span::file`]
+pub struct Sources {
+    names: Vec<String>,
+}
+
+impl Sources {
+    /// Adds the name of the file to a diagnostic when the program has more than one file, or
+    /// when the diagnostic comes from the prelude
+    pub fn locate(&self, mut diagnostic: Diagnostic) -> Diagnostic {
+        if diagnostic.span.file == PRELUDE_FILE {
+            diagnostic.file = Some("prelude".to_string());
+        } else if self.names.len() > 1 && diagnostic.file.is_none() {
+            diagnostic.file = self.names.get(diagnostic.span.file).cloned();
+        }
+        diagnostic.notes = diagnostic
+            .notes
+            .into_iter()
+            .map(|note| self.locate(note))
+            .collect();
+        diagnostic
+    }
+}
+
+/// Parses the main file and every file it imports into one program
+pub fn load(
+    name: &str,
+    code: &str,
+    files: &dyn FileProvider,
+) -> Result<(Vec<Statement>, Sources), Diagnostic> {
+    let mut loader = Loader {
+        files,
+        sources: Sources { names: vec![] },
+        stack: vec![],
+    };
+    let mut program = vec![];
+    match loader.load(resolve("", name), code, &mut program) {
+        Ok(()) => Ok((program, loader.sources)),
+        Err(err) => Err(loader.sources.locate(err)),
+    }
+}
+
+struct Loader<'a> {
+    files: &'a dyn FileProvider,
+    sources: Sources,
+    /// Files whose imports are being resolved, an import of one of them is a cycle
+    stack: Vec<String>,
+}
+
+impl Loader<'_> {
+    fn load(
+        &mut self,
+        name: String,
+        code: &str,
+        program: &mut Vec<Statement>,
+    ) -> Result<(), Diagnostic> {
+        let tokens = Lexer::for_file(code, self.sources.names.len()).tokenize();
+        self.sources.names.push(name.clone());
+        self.stack.push(name.clone());
+        for statement in ast::parse(&tokens)? {
+            let StatementKind::Import(path) = &statement.kind else {
+                program.push(statement);
+                continue;
+            };
+            let path = resolve(&name, path);
+            if let Some(start) = self.stack.iter().position(|file| *file == path) {
+                let mut cycle = self.stack[start..].to_vec();
+                cycle.push(path);
+                return Err(Diagnostic::new(
+                    format!("Import cycle: {}", cycle.join(" -> ")),
+                    statement.span,
+                ));
+            }
+            if self.sources.names.contains(&path) {
+                continue;
+            }
+            let Some(code) = self.files.read(&path) else {
+                return Err(Diagnostic::new(
+                    format!("Cannot find file {}", path),
+                    statement.span,
+                ));
+            };
+            self.load(path, &code, program)?;
+        }
+        self.stack.pop();
+        Ok(())
+    }
+}
+
+/// Resolves an import path relative to the directory of the importing file, so that every
+/// file has a single name
+fn resolve(importer: &str, path: &str) -> String {
+    let mut segments: Vec<&str> = importer.split('/').collect();
+    // The name of the importing file
+    segments.pop();
+    if path.starts_with('/') {
+        segments = vec![""];
+    }
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => match segments.last() {
+                // The root has no parent
+                Some(&"") => {}
+                Some(last) if *last != ".." => {
+                    segments.pop();
+                }
+                _ => segments.push(segment),
+            },
+            _ => segments.push(segment),
+        }
+    }
+    segments.join("/")
+}
@@ -21,7 +21,7 @@ impl StackOperations {
                 code: format!("{} {}", Self::PREFIX, utils::encode_number(*value)),
             },
             StackOperations::Duplicate => CodeOutput {
-                debug_code: format!("duplicate"),
+                debug_code: "duplicate".to_string(),
                 code: format!("{}\n ", Self::PREFIX),
             },
             StackOperations::CopyNth(n) => CodeOutput {
@@ -29,11 +29,11 @@ impl StackOperations {
                 code: format!("{}\t {}", Self::PREFIX, utils::encode_number(*n)),
             },
             StackOperations::Swap => CodeOutput {
-                debug_code: format!("swap"),
+                debug_code: "swap".to_string(),
                 code: format!("{}\n\t", Self::PREFIX),
             },
             StackOperations::Discard => CodeOutput {
-                debug_code: format!("discard"),
+                debug_code: "discard".to_string(),
                 code: format!("{}\n\n", Self::PREFIX),
             },
             StackOperations::Slide(n) => CodeOutput {
@@ -57,23 +57,23 @@ impl ArithmeticOperations {
     pub fn gen(&self) -> CodeOutput {
         match self {
             ArithmeticOperations::Add => CodeOutput {
-                debug_code: format!("add"),
+                debug_code: "add".to_string(),
                 code: format!("{}  ", Self::PREFIX),
             },
             ArithmeticOperations::Subtract => CodeOutput {
-                debug_code: format!("subtract"),
+                debug_code: "subtract".to_string(),
                 code: format!("{} \t", Self::PREFIX),
             },
             ArithmeticOperations::Multiply => CodeOutput {
-                debug_code: format!("multiply"),
+                debug_code: "multiply".to_string(),
                 code: format!("{} \n", Self::PREFIX),
             },
             ArithmeticOperations::DivideInteger => CodeOutput {
-                debug_code: format!("divide"),
+                debug_code: "divide".to_string(),
                 code: format!("{}\t ", Self::PREFIX),
             },
             ArithmeticOperations::Modulo => CodeOutput {
-                debug_code: format!("modulo"),
+                debug_code: "modulo".to_string(),
                 code: format!("{}\t\t", Self::PREFIX),
             },
         }
@@ -90,11 +90,11 @@ impl HeapOperations {
     pub fn gen(&self) -> CodeOutput {
         match self {
             HeapOperations::Store => CodeOutput {
-                debug_code: format!("store"),
+                debug_code: "store".to_string(),
                 code: format!("{} ", Self::PREFIX),
             },
             HeapOperations::Retrieve => CodeOutput {
-                debug_code: format!("retrieve"),
+                debug_code: "retrieve".to_string(),
                 code: format!("{}\t", Self::PREFIX),
             },
         }
@@ -105,6 +105,8 @@ pub enum IOOperations {
     PrintAsChar,
     PrintAsNumber,
     ReadAsChar,
+    // The transpiler parses numbers from a line of characters instead, so that the end of input
+    // is handled. Only `asm` blocks read numbers directly.
     ReadAsNumber,
 }
 
@@ -113,19 +115,19 @@ impl IOOperations {
     pub fn gen(&self) -> CodeOutput {
         match self {
             IOOperations::PrintAsChar => CodeOutput {
-                debug_code: format!("print_stack_top_as_char"),
+                debug_code: "print_stack_top_as_char".to_string(),
                 code: format!("{}  ", Self::PREFIX),
             },
             IOOperations::PrintAsNumber => CodeOutput {
-                debug_code: format!("print_stack_top_as_number"),
+                debug_code: "print_stack_top_as_number".to_string(),
                 code: format!("{} \t", Self::PREFIX),
             },
             IOOperations::ReadAsChar => CodeOutput {
-                debug_code: format!("read_char"),
+                debug_code: "read_char".to_string(),
                 code: format!("{}\t ", Self::PREFIX),
             },
             IOOperations::ReadAsNumber => CodeOutput {
-                debug_code: format!("read_number"),
+                debug_code: "read_number".to_string(),
                 code: format!("{}\t\t", Self::PREFIX),
             },
         }
@@ -134,10 +136,12 @@ impl IOOperations {
 
 pub enum FlowControlOperations {
     SetLabel(i32),
-    // ...
+    /// Call a subroutine, execution continues after the call once it returns
+    Call(i32),
     Jump(i32),
     JumpIfZero(i32),
     JumpIfNegative(i32),
+    Return,
     Exit,
 }
 
@@ -149,6 +153,10 @@ impl FlowControlOperations {
                 debug_code: format!("{}:", label),
                 code: format!("{}  {}", Self::PREFIX, utils::number_to_label(label)),
             },
+            FlowControlOperations::Call(label) => CodeOutput {
+                debug_code: format!("call {}", label),
+                code: format!("{} \t{}", Self::PREFIX, utils::number_to_label(label)),
+            },
             FlowControlOperations::Jump(label) => CodeOutput {
                 debug_code: format!("jump {}", label),
                 code: format!("{} \n{}", Self::PREFIX, utils::number_to_label(label)),
@@ -161,14 +169,19 @@ impl FlowControlOperations {
                 debug_code: format!("jump_if_negative {}", label),
                 code: format!("{}\t\t{}", Self::PREFIX, utils::number_to_label(label)),
             },
+            FlowControlOperations::Return => CodeOutput {
+                debug_code: "return".to_string(),
+                code: format!("{}\t\n", Self::PREFIX),
+            },
             FlowControlOperations::Exit => CodeOutput {
-                debug_code: format!("exit"),
+                debug_code: "exit".to_string(),
                 code: format!("{}\n\n", Self::PREFIX),
             },
         }
     }
 }
 
+#[allow(clippy::upper_case_acronyms)]
 pub enum IMP {
     Stack(StackOperations),
     Arithmetic(ArithmeticOperations),
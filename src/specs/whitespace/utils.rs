@@ -1,10 +1,5 @@
-use std::{
-    collections::hash_map::DefaultHasher,
-    hash::{Hash, Hasher},
-};
-
-fn to_bin(mut number: i32) -> Vec<i32> {
-    let mut bits: Vec<i32> = Vec::new();
+fn to_bin(mut number: u32) -> Vec<u32> {
+    let mut bits: Vec<u32> = Vec::new();
     while number > 0 {
         bits.push(number % 2);
         number /= 2;
@@ -16,11 +11,11 @@ fn to_bin(mut number: i32) -> Vec<i32> {
 pub fn encode_number(number: i32) -> String {
     let mut result = String::new();
     result += if number < 0 { "\t" } else { " " };
-    let mut number = number.abs();
+    let number = number.unsigned_abs();
     if number == 0 {
         result += " ";
     }
-    let mut bits = to_bin(number);
+    let bits = to_bin(number);
     for bit in bits {
         if bit == 0 {
             result.push(' ');
@@ -32,6 +27,19 @@ pub fn encode_number(number: i32) -> String {
     result
 }
 
+pub fn number_to_label(label: &i32) -> String {
+    let mut result = String::new();
+    for bit in to_bin(label.unsigned_abs()) {
+        if bit == 0 {
+            result.push(' ');
+        } else {
+            result.push('\t');
+        }
+    }
+    result += "\n";
+    result
+}
+
 #[cfg(test)]
 mod test {
     use crate::util::unbleach;
@@ -53,18 +61,11 @@ mod test {
         let actual = encode_number(input);
         assert_eq!(expect, unbleach(actual));
     }
-}
 
-pub fn number_to_label(label: &i32) -> String {
-    let mut result = String::new();
-    let mut number = *label;
-    for bit in to_bin(number) {
-        if bit == 0 {
-            result.push(' ');
-        } else {
-            result.push('\t');
-        }
+    #[test]
+    fn min_number() {
+        let expect = format!("tt{}n", "s".repeat(31));
+        let actual = encode_number(i32::MIN);
+        assert_eq!(expect, unbleach(actual));
     }
-    result += "\n";
-    result
 }
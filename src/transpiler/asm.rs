@@ -0,0 +1,95 @@
+//! `asm` blocks, Whitespace instructions written by hand. Instructions are named like in the
+//! debug output and map directly to [`IMP`] values. Labels are shared with `label` and
+//! `goto`, and `&name` is the heap address of a variable. Nothing checks that a block leaves
+//! the stack as it found it.
+
+use crate::{
+    ast::{Instruction, Operand},
+    diagnostic::Diagnostic,
+    specs::whitespace::{
+        ArithmeticOperations, FlowControlOperations, HeapOperations, IOOperations, StackOperations,
+        IMP,
+    },
+};
+
+use super::{emitter::CodeEmitter, state::State, CodeOutput};
+
+pub fn asm(
+    instructions: &[Instruction],
+    state: &mut State,
+    res: &mut CodeOutput,
+) -> Result<(), Diagnostic> {
+    let mut emitter = CodeEmitter {};
+    let imps = instructions
+        .iter()
+        .map(|instruction| imp(instruction, state))
+        .collect::<Result<Vec<_>, _>>()?;
+    res.append(emitter.emit(imps));
+    Ok(())
+}
+
+fn imp(instruction: &Instruction, state: &State) -> Result<IMP, Diagnostic> {
+    let Instruction {
+        mnemonic,
+        operand,
+        span,
+    } = instruction;
+    let number = || match operand {
+        Some(Operand::Number(value)) => Ok(*value),
+        Some(Operand::Address(name)) => Ok(state.heap_allocation_map.lookup(name, *span)?.offset()),
+        _ => Err(Diagnostic::new(
+            format!("{} expects a number", mnemonic),
+            *span,
+        )),
+    };
+    let label = || match operand {
+        Some(Operand::Label(name)) => state.label(name, *span),
+        _ => Err(Diagnostic::new(
+            format!("{} expects a label", mnemonic),
+            *span,
+        )),
+    };
+    let imp = match mnemonic.as_str() {
+        "push" => IMP::Stack(StackOperations::PushNumber(number()?)),
+        "copy_nth" => IMP::Stack(StackOperations::CopyNth(number()?)),
+        "slide" => IMP::Stack(StackOperations::Slide(number()?)),
+        "label" => IMP::FlowControl(FlowControlOperations::SetLabel(label()?)),
+        "call" => IMP::FlowControl(FlowControlOperations::Call(label()?)),
+        "jump" => IMP::FlowControl(FlowControlOperations::Jump(label()?)),
+        "jump_if_zero" => IMP::FlowControl(FlowControlOperations::JumpIfZero(label()?)),
+        "jump_if_negative" => IMP::FlowControl(FlowControlOperations::JumpIfNegative(label()?)),
+        _ => {
+            if operand.is_some() {
+                return Err(Diagnostic::new(
+                    format!("{} takes no operand", mnemonic),
+                    *span,
+                ));
+            }
+            match mnemonic.as_str() {
+                "duplicate" | "dup" => IMP::Stack(StackOperations::Duplicate),
+                "swap" => IMP::Stack(StackOperations::Swap),
+                "discard" => IMP::Stack(StackOperations::Discard),
+                "add" => IMP::Arithmetic(ArithmeticOperations::Add),
+                "subtract" => IMP::Arithmetic(ArithmeticOperations::Subtract),
+                "multiply" => IMP::Arithmetic(ArithmeticOperations::Multiply),
+                "divide" => IMP::Arithmetic(ArithmeticOperations::DivideInteger),
+                "modulo" => IMP::Arithmetic(ArithmeticOperations::Modulo),
+                "store" => IMP::Heap(HeapOperations::Store),
+                "retrieve" => IMP::Heap(HeapOperations::Retrieve),
+                "print_stack_top_as_char" | "print_char" => IMP::IO(IOOperations::PrintAsChar),
+                "print_stack_top_as_number" | "print_num" => IMP::IO(IOOperations::PrintAsNumber),
+                "read_char" => IMP::IO(IOOperations::ReadAsChar),
+                "read_number" => IMP::IO(IOOperations::ReadAsNumber),
+                "return" => IMP::FlowControl(FlowControlOperations::Return),
+                "exit" => IMP::FlowControl(FlowControlOperations::Exit),
+                _ => {
+                    return Err(Diagnostic::new(
+                        format!("Unknown instruction {}", mnemonic),
+                        *span,
+                    ))
+                }
+            }
+        }
+    };
+    Ok(imp)
+}
@@ -0,0 +1,237 @@
+//! Bitwise operators. Whitespace has no bit operations, so they are emulated with arithmetic
+//! on 32-bit two's complement integers. Only divisions of non-negative numbers are used, since
+//! interpreters round negative quotients differently.
+
+use crate::{
+    ast::Operation,
+    specs::whitespace::{
+        ArithmeticOperations, FlowControlOperations, HeapOperations, StackOperations, IMP,
+    },
+    transpiler::{state::State, VariableType},
+};
+
+use super::bi_math;
+
+/// Returns the label of the subroutine that implements a bitwise operator.
+/// Every subroutine takes the operands as [left right] and leaves the result.
+pub fn operator_subroutine(operator: &Operation, state: &mut State) -> i32 {
+    match operator {
+        Operation::BitAnd => state.subroutine("bit_and", and_subroutine),
+        Operation::BitOr => state.subroutine("bit_or", or_subroutine),
+        Operation::BitXor => state.subroutine("bit_xor", xor_subroutine),
+        Operation::ShiftLeft => state.subroutine("shift_left", shift_left_subroutine),
+        Operation::ShiftRight => state.subroutine("shift_right", shift_right_subroutine),
+        _ => unreachable!("{} is not a bitwise operator", operator),
+    }
+}
+
+/// [a b] -> [a & b]
+fn and_subroutine(state: &mut State) -> Vec<IMP> {
+    // Both bits set: (a + b) / 2
+    bit_loop(
+        state,
+        [
+            IMP::Stack(StackOperations::PushNumber(2)),
+            IMP::Arithmetic(ArithmeticOperations::DivideInteger),
+        ],
+    )
+}
+
+/// [a b] -> [a | b]
+fn or_subroutine(state: &mut State) -> Vec<IMP> {
+    // Any bit set: (a + b + 1) / 2
+    bit_loop(
+        state,
+        [
+            IMP::Stack(StackOperations::PushNumber(1)),
+            IMP::Arithmetic(ArithmeticOperations::Add),
+            IMP::Stack(StackOperations::PushNumber(2)),
+            IMP::Arithmetic(ArithmeticOperations::DivideInteger),
+        ],
+    )
+}
+
+/// [a b] -> [a ^ b]
+fn xor_subroutine(state: &mut State) -> Vec<IMP> {
+    // Exactly one bit set: (a + b) % 2
+    bit_loop(
+        state,
+        [
+            IMP::Stack(StackOperations::PushNumber(2)),
+            IMP::Arithmetic(ArithmeticOperations::Modulo),
+        ],
+    )
+}
+
+/// Combines the operands bit by bit, `combine` turns the sum of two bits into the result bit.
+/// The 31 low bits are processed first, then the sign bits with the weight of -2^31.
+fn bit_loop<const N: usize>(state: &mut State, combine: [IMP; N]) -> Vec<IMP> {
+    let left = state.heap_allocation_map.reserve(VariableType::Int);
+    let right = state.heap_allocation_map.reserve(VariableType::Int);
+    let left_sign = state.heap_allocation_map.reserve(VariableType::Int);
+    let right_sign = state.heap_allocation_map.reserve(VariableType::Int);
+    let result = state.heap_allocation_map.reserve(VariableType::Int);
+    let weight = state.heap_allocation_map.reserve(VariableType::Int);
+    let loop_label = state.get_label();
+    let sign_label = state.get_label();
+    let end_label = state.get_label();
+
+    let mut code = split_sign(state, right, right_sign);
+    code.extend(split_sign(state, left, left_sign));
+    code.extend([
+        IMP::Stack(StackOperations::PushNumber(result)),
+        IMP::Stack(StackOperations::PushNumber(0)),
+        IMP::Heap(HeapOperations::Store),
+        IMP::Stack(StackOperations::PushNumber(weight)),
+        IMP::Stack(StackOperations::PushNumber(1)),
+        IMP::Heap(HeapOperations::Store),
+        IMP::FlowControl(FlowControlOperations::SetLabel(loop_label)),
+        // result += combine(left % 2 + right % 2) * weight
+        IMP::Stack(StackOperations::PushNumber(result)),
+        IMP::Stack(StackOperations::PushNumber(left)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(2)),
+        IMP::Arithmetic(ArithmeticOperations::Modulo),
+        IMP::Stack(StackOperations::PushNumber(right)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(2)),
+        IMP::Arithmetic(ArithmeticOperations::Modulo),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+    ]);
+    code.extend(combine);
+    code.extend([
+        IMP::Stack(StackOperations::PushNumber(weight)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Arithmetic(ArithmeticOperations::Multiply),
+        IMP::Stack(StackOperations::PushNumber(result)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Heap(HeapOperations::Store),
+    ]);
+    code.extend(halve(left));
+    code.extend(halve(right));
+    code.extend([
+        // The sign bits were the last ones when the weight is negative
+        IMP::Stack(StackOperations::PushNumber(weight)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::FlowControl(FlowControlOperations::JumpIfNegative(end_label)),
+        IMP::Stack(StackOperations::PushNumber(weight)),
+        IMP::Stack(StackOperations::PushNumber(weight)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(2)),
+        IMP::Arithmetic(ArithmeticOperations::Multiply),
+        IMP::Heap(HeapOperations::Store),
+        // Continue with the low bits while the weight is below 2^31
+        IMP::Stack(StackOperations::PushNumber(i32::MAX)),
+        IMP::Stack(StackOperations::PushNumber(weight)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::FlowControl(FlowControlOperations::JumpIfNegative(sign_label)),
+        IMP::FlowControl(FlowControlOperations::Jump(loop_label)),
+        IMP::FlowControl(FlowControlOperations::SetLabel(sign_label)),
+        IMP::Stack(StackOperations::PushNumber(left)),
+        IMP::Stack(StackOperations::PushNumber(left_sign)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Heap(HeapOperations::Store),
+        IMP::Stack(StackOperations::PushNumber(right)),
+        IMP::Stack(StackOperations::PushNumber(right_sign)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Heap(HeapOperations::Store),
+        IMP::Stack(StackOperations::PushNumber(weight)),
+        IMP::Stack(StackOperations::PushNumber(i32::MIN)),
+        IMP::Heap(HeapOperations::Store),
+        IMP::FlowControl(FlowControlOperations::Jump(loop_label)),
+        IMP::FlowControl(FlowControlOperations::SetLabel(end_label)),
+        IMP::Stack(StackOperations::PushNumber(result)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::FlowControl(FlowControlOperations::Return),
+    ]);
+    code
+}
+
+/// [x] -> [], stores the 31 low bits of x into `low` and its sign bit into `sign`
+fn split_sign(state: &mut State, low: i32, sign: i32) -> Vec<IMP> {
+    let negative_label = state.get_label();
+    let store_label = state.get_label();
+    vec![
+        IMP::Stack(StackOperations::PushNumber(sign)),
+        IMP::Stack(StackOperations::PushNumber(0)),
+        IMP::Heap(HeapOperations::Store),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::FlowControl(FlowControlOperations::JumpIfNegative(negative_label)),
+        IMP::FlowControl(FlowControlOperations::Jump(store_label)),
+        // x + 2^31
+        IMP::FlowControl(FlowControlOperations::SetLabel(negative_label)),
+        IMP::Stack(StackOperations::PushNumber(i32::MAX)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Stack(StackOperations::PushNumber(1)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Stack(StackOperations::PushNumber(sign)),
+        IMP::Stack(StackOperations::PushNumber(1)),
+        IMP::Heap(HeapOperations::Store),
+        IMP::FlowControl(FlowControlOperations::SetLabel(store_label)),
+        IMP::Stack(StackOperations::PushNumber(low)),
+        IMP::Stack(StackOperations::Swap),
+        IMP::Heap(HeapOperations::Store),
+    ]
+}
+
+fn halve(cell: i32) -> [IMP; 6] {
+    [
+        IMP::Stack(StackOperations::PushNumber(cell)),
+        IMP::Stack(StackOperations::PushNumber(cell)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(2)),
+        IMP::Arithmetic(ArithmeticOperations::DivideInteger),
+        IMP::Heap(HeapOperations::Store),
+    ]
+}
+
+/// [a n] -> [a * 2^n], negative shift counts do not shift
+fn shift_left_subroutine(state: &mut State) -> Vec<IMP> {
+    let mut code = power_of_two(state);
+    code.extend([
+        IMP::Arithmetic(ArithmeticOperations::Multiply),
+        IMP::FlowControl(FlowControlOperations::Return),
+    ]);
+    code
+}
+
+/// [a n] -> [floor(a / 2^n)], negative shift counts do not shift
+fn shift_right_subroutine(state: &mut State) -> Vec<IMP> {
+    let negative_label = state.get_label();
+    let mut code = power_of_two(state);
+    code.extend([
+        IMP::Stack(StackOperations::Swap),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::FlowControl(FlowControlOperations::JumpIfNegative(negative_label)),
+        IMP::Stack(StackOperations::Swap),
+        IMP::Arithmetic(ArithmeticOperations::DivideInteger),
+        IMP::FlowControl(FlowControlOperations::Return),
+        // ~(~a / 2^n), so that only a non-negative number is divided
+        IMP::FlowControl(FlowControlOperations::SetLabel(negative_label)),
+        IMP::Stack(StackOperations::PushNumber(-1)),
+        IMP::Stack(StackOperations::Swap),
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::Stack(StackOperations::Swap),
+        IMP::Arithmetic(ArithmeticOperations::DivideInteger),
+        IMP::Stack(StackOperations::PushNumber(-1)),
+        IMP::Stack(StackOperations::Swap),
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::FlowControl(FlowControlOperations::Return),
+    ]);
+    code
+}
+
+/// [n] -> [2^max(n, 0)]
+fn power_of_two(state: &mut State) -> Vec<IMP> {
+    let max = state.subroutine("max", bi_math::max_subroutine);
+    let pow = state.subroutine("pow", bi_math::pow_subroutine);
+    vec![
+        IMP::Stack(StackOperations::PushNumber(0)),
+        IMP::FlowControl(FlowControlOperations::Call(max)),
+        IMP::Stack(StackOperations::PushNumber(2)),
+        IMP::Stack(StackOperations::Swap),
+        IMP::FlowControl(FlowControlOperations::Call(pow)),
+    ]
+}
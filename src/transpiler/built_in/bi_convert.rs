@@ -0,0 +1,249 @@
+use crate::{
+    ast::Expression,
+    diagnostic::Diagnostic,
+    lexer::Span,
+    specs::whitespace::{
+        ArithmeticOperations, FlowControlOperations, HeapOperations, StackOperations, IMP,
+    },
+    transpiler::{
+        emitter::{CodeEmitter, EvaluationResult},
+        state::State,
+        CodeOutput, HeapVariableSize, VariableType,
+    },
+};
+
+use super::{check_arity, evaluate_arguments, string_target, Parameter};
+
+/// Writes the decimal representation of an integer, with a leading `-` for negative
+/// numbers. The result is truncated to the capacity of the target.
+pub fn to_string(
+    state: &mut State,
+    args: &[Expression],
+    span: Span,
+    res: &mut CodeOutput,
+) -> Result<(), Diagnostic> {
+    res.add("".to_string(), "# to_string".to_string());
+    check_arity("to_string", 2, args, span)?;
+    let (target_addr, capacity) = string_target("to_string", &args[0], state)?;
+    let itoa = state.subroutine("itoa", itoa);
+    let mut emitter = CodeEmitter {};
+
+    res.append(emitter.emit(vec![IMP::Stack(StackOperations::PushNumber(target_addr))]));
+    let (code, debug_code) =
+        evaluate_arguments("to_string", &[Parameter::Int], &args[1..], span, state, 0)?;
+    res.add(code, debug_code.render());
+    res.append(emitter.emit(vec![
+        IMP::Stack(StackOperations::PushNumber(capacity as i32 - 1)),
+        IMP::FlowControl(FlowControlOperations::Call(itoa)),
+    ]));
+    Ok(())
+}
+
+/// Parses a decimal integer. Leading whitespace and a single sign are allowed, parsing stops
+/// at the first character that is not a digit. Strings without digits parse as 0.
+pub fn parse_int(
+    state: &mut State,
+    args: &[Expression],
+    span: Span,
+    level: i8,
+) -> Result<EvaluationResult, Diagnostic> {
+    let (mut code, mut debug_code) =
+        evaluate_arguments("parse_int", &[Parameter::String], args, span, state, level)?;
+    let atoi = state.subroutine("atoi", atoi);
+    CodeEmitter::emit_into(
+        &mut code,
+        &mut debug_code,
+        vec![IMP::FlowControl(FlowControlOperations::Call(atoi))],
+    );
+    Ok(EvaluationResult {
+        code,
+        debug_code,
+        type_: VariableType::Int,
+    })
+}
+
+/// [target number limit] -> [], writes at most `limit` characters and a terminator
+pub fn itoa(state: &mut State) -> Vec<IMP> {
+    // The digits are pushed onto the stack, the locals keep track of the output
+    let target = state.heap_allocation_map.reserve(VariableType::Int);
+    let remaining = state.heap_allocation_map.reserve(VariableType::Int);
+    let negative_label = state.get_label();
+    let positive_label = state.get_label();
+    let digits_label = state.get_label();
+    let digits_end_label = state.get_label();
+    let output_label = state.get_label();
+    let output_end_label = state.get_label();
+
+    let mut instructions = vec![
+        IMP::Stack(StackOperations::PushNumber(remaining)),
+        IMP::Stack(StackOperations::Swap),
+        IMP::Heap(HeapOperations::Store),
+        IMP::Stack(StackOperations::Swap),
+        IMP::Stack(StackOperations::PushNumber(target)),
+        IMP::Stack(StackOperations::Swap),
+        IMP::Heap(HeapOperations::Store),
+        // Write the sign and continue with the absolute value
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::FlowControl(FlowControlOperations::JumpIfNegative(negative_label)),
+        IMP::FlowControl(FlowControlOperations::Jump(positive_label)),
+        IMP::FlowControl(FlowControlOperations::SetLabel(negative_label)),
+        IMP::Stack(StackOperations::PushNumber('-' as i32)),
+    ];
+    instructions.extend(put_char(state, target, remaining));
+    instructions.extend([
+        IMP::Stack(StackOperations::PushNumber(-1)),
+        IMP::Arithmetic(ArithmeticOperations::Multiply),
+        IMP::FlowControl(FlowControlOperations::SetLabel(positive_label)),
+        // Push the digits starting with the last one, -1 marks the end
+        IMP::Stack(StackOperations::PushNumber(-1)),
+        IMP::Stack(StackOperations::Swap),
+        IMP::FlowControl(FlowControlOperations::SetLabel(digits_label)),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::Stack(StackOperations::PushNumber(10)),
+        IMP::Arithmetic(ArithmeticOperations::Modulo),
+        IMP::Stack(StackOperations::Swap),
+        IMP::Stack(StackOperations::PushNumber(10)),
+        IMP::Arithmetic(ArithmeticOperations::DivideInteger),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::FlowControl(FlowControlOperations::JumpIfZero(digits_end_label)),
+        IMP::FlowControl(FlowControlOperations::Jump(digits_label)),
+        IMP::FlowControl(FlowControlOperations::SetLabel(digits_end_label)),
+        IMP::Stack(StackOperations::Discard),
+        // Write them in order
+        IMP::FlowControl(FlowControlOperations::SetLabel(output_label)),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::FlowControl(FlowControlOperations::JumpIfNegative(output_end_label)),
+        IMP::Stack(StackOperations::PushNumber('0' as i32)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+    ]);
+    instructions.extend(put_char(state, target, remaining));
+    instructions.extend([
+        IMP::FlowControl(FlowControlOperations::Jump(output_label)),
+        IMP::FlowControl(FlowControlOperations::SetLabel(output_end_label)),
+        IMP::Stack(StackOperations::Discard),
+        // Add null terminator
+        IMP::Stack(StackOperations::PushNumber(target)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(0)),
+        IMP::Heap(HeapOperations::Store),
+        IMP::FlowControl(FlowControlOperations::Return),
+    ]);
+    instructions
+}
+
+/// [char] -> [], writes the char to the address stored at `target` unless there are no
+/// `remaining` characters left
+fn put_char(state: &mut State, target: i32, remaining: i32) -> Vec<IMP> {
+    let full_label = state.get_label();
+    let end_label = state.get_label();
+    vec![
+        IMP::Stack(StackOperations::PushNumber(remaining)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(1)),
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::FlowControl(FlowControlOperations::JumpIfNegative(full_label)),
+        IMP::Stack(StackOperations::PushNumber(target)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::Swap),
+        IMP::Heap(HeapOperations::Store),
+        IMP::Stack(StackOperations::PushNumber(target)),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(HeapVariableSize::Char.size())),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Heap(HeapOperations::Store),
+        IMP::Stack(StackOperations::PushNumber(remaining)),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(1)),
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::Heap(HeapOperations::Store),
+        IMP::FlowControl(FlowControlOperations::Jump(end_label)),
+        IMP::FlowControl(FlowControlOperations::SetLabel(full_label)),
+        IMP::Stack(StackOperations::Discard),
+        IMP::FlowControl(FlowControlOperations::SetLabel(end_label)),
+    ]
+}
+
+/// [addr] -> [number]
+pub fn atoi(state: &mut State) -> Vec<IMP> {
+    let whitespace_label = state.get_label();
+    let whitespace_next_label = state.get_label();
+    let sign_label = state.get_label();
+    let minus_label = state.get_label();
+    let plus_label = state.get_label();
+    let digits_label = state.get_label();
+    let loop_label = state.get_label();
+    let end_label = state.get_label();
+    vec![
+        // Skip leading whitespace
+        IMP::FlowControl(FlowControlOperations::SetLabel(whitespace_label)),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(1)),
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::FlowControl(FlowControlOperations::JumpIfNegative(sign_label)),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(' ' as i32 + 1)),
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::FlowControl(FlowControlOperations::JumpIfNegative(whitespace_next_label)),
+        IMP::FlowControl(FlowControlOperations::Jump(sign_label)),
+        IMP::FlowControl(FlowControlOperations::SetLabel(whitespace_next_label)),
+        IMP::Stack(StackOperations::PushNumber(HeapVariableSize::Char.size())),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::FlowControl(FlowControlOperations::Jump(whitespace_label)),
+        // Optional sign, kept below the pointer
+        IMP::FlowControl(FlowControlOperations::SetLabel(sign_label)),
+        IMP::Stack(StackOperations::PushNumber(1)),
+        IMP::Stack(StackOperations::Swap),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber('-' as i32)),
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::FlowControl(FlowControlOperations::JumpIfZero(minus_label)),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber('+' as i32)),
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::FlowControl(FlowControlOperations::JumpIfZero(plus_label)),
+        IMP::FlowControl(FlowControlOperations::Jump(digits_label)),
+        IMP::FlowControl(FlowControlOperations::SetLabel(minus_label)),
+        IMP::Stack(StackOperations::Swap),
+        IMP::Stack(StackOperations::PushNumber(-1)),
+        IMP::Arithmetic(ArithmeticOperations::Multiply),
+        IMP::Stack(StackOperations::Swap),
+        IMP::FlowControl(FlowControlOperations::SetLabel(plus_label)),
+        IMP::Stack(StackOperations::PushNumber(HeapVariableSize::Char.size())),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        // Accumulate digits
+        IMP::FlowControl(FlowControlOperations::SetLabel(digits_label)),
+        IMP::Stack(StackOperations::PushNumber(0)),
+        IMP::FlowControl(FlowControlOperations::SetLabel(loop_label)),
+        IMP::Stack(StackOperations::CopyNth(1)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber('0' as i32)),
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::FlowControl(FlowControlOperations::JumpIfNegative(end_label)),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::Stack(StackOperations::PushNumber(9)),
+        IMP::Stack(StackOperations::Swap),
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::FlowControl(FlowControlOperations::JumpIfNegative(end_label)),
+        IMP::Stack(StackOperations::Swap),
+        IMP::Stack(StackOperations::PushNumber(10)),
+        IMP::Arithmetic(ArithmeticOperations::Multiply),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Stack(StackOperations::Swap),
+        IMP::Stack(StackOperations::PushNumber(HeapVariableSize::Char.size())),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Stack(StackOperations::Swap),
+        IMP::FlowControl(FlowControlOperations::Jump(loop_label)),
+        IMP::FlowControl(FlowControlOperations::SetLabel(end_label)),
+        IMP::Stack(StackOperations::Discard),
+        IMP::Stack(StackOperations::Slide(1)),
+        IMP::Arithmetic(ArithmeticOperations::Multiply),
+        IMP::FlowControl(FlowControlOperations::Return),
+    ]
+}
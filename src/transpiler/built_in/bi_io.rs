@@ -1,18 +1,33 @@
 use crate::transpiler::emitter::CodeEmitter;
 
 use crate::{
-    ast::Expression,
+    ast::{Expression, ExpressionKind},
+    diagnostic::Diagnostic,
+    lexer::Span,
     specs::whitespace::{
         ArithmeticOperations, FlowControlOperations, HeapOperations, IOOperations, StackOperations,
         IMP,
     },
-    transpiler::{state::State, CodeOutput, HeapVariableSize, VariableType},
+    transpiler::{
+        emitter::EvaluationResult, state::State, CodeOutput, DebugCodeBlock, HeapVariableSize,
+        VariableType,
+    },
 };
 
-pub fn read(state: &mut State, args: &Vec<Expression>, res: &mut CodeOutput) {
-    let target = args.get(0).unwrap();
-    if let Expression::Variable(name) = target {
-        let variable = state.heap_allocation_map.get(&name).unwrap();
+use super::{bi_convert, bi_str, check_arity, str_variable, string_target};
+
+/// Reads a line into a string variable, see [`read_line`], or a number into an int variable.
+pub fn read(
+    state: &mut State,
+    args: &[Expression],
+    span: Span,
+    res: &mut CodeOutput,
+) -> Result<(), Diagnostic> {
+    let target = args
+        .first()
+        .ok_or_else(|| Diagnostic::new("read expects a variable to read into", span))?;
+    if let ExpressionKind::Variable(name) = &target.kind {
+        let variable = state.heap_allocation_map.lookup(name, target.span)?;
         let type_ = variable.type_();
         let addr = variable.offset();
         let mut emitter = CodeEmitter {};
@@ -20,46 +35,251 @@ pub fn read(state: &mut State, args: &Vec<Expression>, res: &mut CodeOutput) {
         res.append(emitter.emit(vec![IMP::Stack(StackOperations::PushNumber(addr))]));
 
         match type_ {
-            VariableType::Int => {
-                res.append(emitter.emit(vec![IMP::IO(IOOperations::ReadAsNumber)]))
+            VariableType::Int | VariableType::Enum(_) => {
+                let read_int = state.subroutine("read_int", read_int);
+                res.append(emitter.emit(vec![
+                    IMP::FlowControl(FlowControlOperations::Call(read_int)),
+                    IMP::Heap(HeapOperations::Store),
+                ]));
             }
-            VariableType::String(_) => {
-                let read_loop_start_label = state.get_label();
-                let read_loop_end_label = state.get_label();
+            VariableType::Char => {
+                let getc = state.subroutine("getc", getc);
                 res.append(emitter.emit(vec![
-                    IMP::FlowControl(FlowControlOperations::SetLabel(read_loop_start_label)),
-                    IMP::Stack(StackOperations::Duplicate),
-                    IMP::Stack(StackOperations::Duplicate),
-                    IMP::IO(IOOperations::ReadAsChar),
-                    // Condition
-                    IMP::Heap(HeapOperations::Retrieve),
-                    IMP::Stack(StackOperations::PushNumber(10)),
-                    IMP::Arithmetic(ArithmeticOperations::Subtract),
-                    IMP::FlowControl(FlowControlOperations::JumpIfZero(read_loop_end_label)),
-                    // Advance pointer
-                    IMP::Stack(StackOperations::PushNumber(HeapVariableSize::Char.size())),
-                    IMP::Arithmetic(ArithmeticOperations::Add),
-                    IMP::FlowControl(FlowControlOperations::Jump(read_loop_start_label)),
-                    IMP::FlowControl(FlowControlOperations::SetLabel(read_loop_end_label)),
-                    IMP::Stack(StackOperations::PushNumber(0)),
+                    IMP::FlowControl(FlowControlOperations::Call(getc)),
                     IMP::Heap(HeapOperations::Store),
                 ]));
             }
-            _ => {
-                panic!("Only integer and strings values are supported for now");
+            VariableType::Str => {
+                res.append(emitter.emit(bi_str::read_line(state)));
+                res.append(emitter.emit(vec![IMP::Stack(StackOperations::Discard)]));
+            }
+            VariableType::Pointer | VariableType::Map(_) => {
+                return Err(Diagnostic::new(
+                    format!("Cannot read into {} of type {}", name, type_),
+                    target.span,
+                ));
+            }
+            VariableType::String(capacity) => {
+                let readln = state.subroutine("readln", readln);
+                res.append(emitter.emit(vec![
+                    IMP::Stack(StackOperations::PushNumber(capacity as i32 - 1)),
+                    IMP::FlowControl(FlowControlOperations::Call(readln)),
+                    IMP::Stack(StackOperations::Discard),
+                ]));
             }
         }
+        Ok(())
+    } else {
+        Err(Diagnostic::new("Unsupported argument", target.span))
+    }
+}
+
+/// Reads a line into a string variable without the newline. Returns 0 when the whole line
+/// fit, 1 when it was truncated to the capacity of the variable and -1 at the end of input.
+/// The rest of a truncated line is skipped.
+pub fn read_line(
+    state: &mut State,
+    args: &[Expression],
+    span: Span,
+    level: i8,
+) -> Result<EvaluationResult, Diagnostic> {
+    check_arity("read_line", 1, args, span)?;
+    let instructions = match str_variable(&args[0], state) {
+        Some(addr) => {
+            let mut instructions = vec![IMP::Stack(StackOperations::PushNumber(addr))];
+            instructions.extend(bi_str::read_line(state));
+            instructions
+        }
+        None => {
+            let (addr, capacity) = string_target("read_line", &args[0], state)?;
+            let readln = state.subroutine("readln", readln);
+            vec![
+                IMP::Stack(StackOperations::PushNumber(addr)),
+                IMP::Stack(StackOperations::PushNumber(capacity as i32 - 1)),
+                IMP::FlowControl(FlowControlOperations::Call(readln)),
+            ]
+        }
+    };
+    let mut code = String::new();
+    let mut debug_code = DebugCodeBlock::new(None, level);
+    CodeEmitter::emit_into(&mut code, &mut debug_code, instructions);
+    Ok(EvaluationResult {
+        code,
+        debug_code,
+        type_: VariableType::Int,
+    })
+}
+
+/// Reads a single character, returns -1 at the end of input
+pub fn read_char(
+    state: &mut State,
+    args: &[Expression],
+    span: Span,
+    level: i8,
+) -> Result<EvaluationResult, Diagnostic> {
+    input_call("read_char", getc, state, args, span, level)
+}
+
+/// 1 when there is nothing left to read, 0 otherwise
+pub fn eof(
+    state: &mut State,
+    args: &[Expression],
+    span: Span,
+    level: i8,
+) -> Result<EvaluationResult, Diagnostic> {
+    input_call("eof", eof_subroutine, state, args, span, level)
+}
+
+fn input_call(
+    name: &'static str,
+    body: fn(&mut State) -> Vec<IMP>,
+    state: &mut State,
+    args: &[Expression],
+    span: Span,
+    level: i8,
+) -> Result<EvaluationResult, Diagnostic> {
+    check_arity(name, 0, args, span)?;
+    let label = state.subroutine(name, body);
+    let mut code = String::new();
+    let mut debug_code = DebugCodeBlock::new(None, level);
+    CodeEmitter::emit_into(
+        &mut code,
+        &mut debug_code,
+        vec![IMP::FlowControl(FlowControlOperations::Call(label))],
+    );
+    Ok(EvaluationResult {
+        code,
+        debug_code,
+        type_: VariableType::Int,
+    })
+}
+
+// Input goes through a single character of lookahead, which allows `eof` to look at the next
+// character without consuming it. Interpreters disagree on what reading past the end of
+// input does: some abort, some store -1 or 0 and some leave the cell untouched. The cell
+// is filled with -1 before every read, so anything below 1 afterwards is the end of input.
+// On interpreters that abort the program ends at the first read past the end.
+
+/// Lookahead is empty
+const LOOKAHEAD_EMPTY: i32 = -2;
+/// End of input, stays set once reached so that the input is never read again
+const LOOKAHEAD_EOF: i32 = -1;
+
+/// [] -> [next char or -1], does not consume the character
+pub fn peekc(state: &mut State) -> Vec<IMP> {
+    let lookahead = state.global("lookahead", LOOKAHEAD_EMPTY);
+    let fill_label = state.get_label();
+    let end_label = state.get_label();
+    vec![
+        IMP::Stack(StackOperations::PushNumber(lookahead)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(-LOOKAHEAD_EMPTY)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::FlowControl(FlowControlOperations::JumpIfZero(fill_label)),
+        IMP::Stack(StackOperations::PushNumber(lookahead)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::FlowControl(FlowControlOperations::Return),
+        IMP::FlowControl(FlowControlOperations::SetLabel(fill_label)),
+        IMP::Stack(StackOperations::PushNumber(lookahead)),
+        IMP::Stack(StackOperations::PushNumber(LOOKAHEAD_EOF)),
+        IMP::Heap(HeapOperations::Store),
+        IMP::Stack(StackOperations::PushNumber(lookahead)),
+        IMP::IO(IOOperations::ReadAsChar),
+        IMP::Stack(StackOperations::PushNumber(lookahead)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(1)),
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::FlowControl(FlowControlOperations::JumpIfNegative(end_label)),
+        IMP::Stack(StackOperations::PushNumber(lookahead)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::FlowControl(FlowControlOperations::Return),
+        IMP::FlowControl(FlowControlOperations::SetLabel(end_label)),
+        IMP::Stack(StackOperations::PushNumber(lookahead)),
+        IMP::Stack(StackOperations::PushNumber(LOOKAHEAD_EOF)),
+        IMP::Heap(HeapOperations::Store),
+        IMP::Stack(StackOperations::PushNumber(LOOKAHEAD_EOF)),
+        IMP::FlowControl(FlowControlOperations::Return),
+    ]
+}
+
+/// [] -> [char or -1]
+pub fn getc(state: &mut State) -> Vec<IMP> {
+    let peekc = state.subroutine("peekc", peekc);
+    let lookahead = state.global("lookahead", LOOKAHEAD_EMPTY);
+    let end_label = state.get_label();
+    vec![
+        IMP::FlowControl(FlowControlOperations::Call(peekc)),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::FlowControl(FlowControlOperations::JumpIfNegative(end_label)),
+        IMP::Stack(StackOperations::PushNumber(lookahead)),
+        IMP::Stack(StackOperations::PushNumber(LOOKAHEAD_EMPTY)),
+        IMP::Heap(HeapOperations::Store),
+        IMP::FlowControl(FlowControlOperations::SetLabel(end_label)),
+        IMP::FlowControl(FlowControlOperations::Return),
+    ]
+}
+
+pub fn print(
+    state: &mut State,
+    args: &[Expression],
+    span: Span,
+    res: &mut CodeOutput,
+) -> Result<(), Diagnostic> {
+    if args.is_empty() {
+        return Err(Diagnostic::new("print expects a value to print", span));
+    }
+    for arg in args {
+        print_value(state, arg, res)?;
+    }
+    Ok(())
+}
+
+/// Same as [`print`], followed by a newline
+pub fn println(
+    state: &mut State,
+    args: &[Expression],
+    _span: Span,
+    res: &mut CodeOutput,
+) -> Result<(), Diagnostic> {
+    for arg in args {
+        print_value(state, arg, res)?;
+    }
+    let mut emitter = CodeEmitter {};
+    res.append(emitter.emit(vec![
+        IMP::Stack(StackOperations::PushNumber('\n' as i32)),
+        IMP::IO(IOOperations::PrintAsChar),
+    ]));
+    Ok(())
+}
+
+/// Whitespace has a single output stream, so error output goes to the same place as
+/// [`print`]. It is only marked in the debug listing.
+pub fn eprint(
+    state: &mut State,
+    args: &[Expression],
+    span: Span,
+    res: &mut CodeOutput,
+    newline: bool,
+) -> Result<(), Diagnostic> {
+    res.add("".to_string(), "# stderr".to_string());
+    if newline {
+        println(state, args, span, res)?;
     } else {
-        panic!("Unsupported argument");
+        print(state, args, span, res)?;
     }
+    res.add("".to_string(), "# end of stderr".to_string());
+    Ok(())
 }
 
-pub fn print(state: &mut State, args: &Vec<Expression>, res: &mut CodeOutput) {
-    let expression = args.get(0).unwrap();
+fn print_value(
+    state: &mut State,
+    expression: &Expression,
+    res: &mut CodeOutput,
+) -> Result<(), Diagnostic> {
     let mut emitter = CodeEmitter {};
 
-    match expression {
-        Expression::Literal(value) => {
+    match &expression.kind {
+        ExpressionKind::Literal(value) => {
             for ch in value.chars() {
                 let CodeOutput { code, debug_code } =
                     IMP::Stack(StackOperations::PushNumber(ch as i32)).gen();
@@ -68,28 +288,21 @@ pub fn print(state: &mut State, args: &Vec<Expression>, res: &mut CodeOutput) {
                 res.add(code, debug_code);
             }
         }
-        Expression::Integer(value) => {
-            let CodeOutput { code, debug_code } =
-                IMP::Stack(StackOperations::PushNumber(*value)).gen();
-            res.add(code, debug_code);
-
-            let CodeOutput { code, debug_code } = IMP::IO(IOOperations::PrintAsNumber).gen();
-            res.add(code, debug_code);
-        }
-        Expression::Variable(name) => {
-            let variable = state.heap_allocation_map.get(&name).unwrap();
-            let type_ = variable.type_();
-            let offset = variable.offset();
-
-            let CodeOutput { code, debug_code } =
-                IMP::Stack(StackOperations::PushNumber(offset)).gen();
-            res.add(code, debug_code);
+        _ => {
+            let EvaluationResult {
+                code,
+                debug_code,
+                type_,
+            } = emitter.evaluate_expression(expression, state, 0)?;
+            res.add(code, debug_code.render());
             match type_ {
-                VariableType::Int => {
-                    res.append(emitter.emit(vec![IMP::Heap(HeapOperations::Retrieve)]));
-                    res.append(emitter.emit(vec![IMP::IO(IOOperations::PrintAsNumber)]));
+                VariableType::Int | VariableType::Enum(_) | VariableType::Pointer => {
+                    res.append(emitter.emit(vec![IMP::IO(IOOperations::PrintAsNumber)]))
+                }
+                VariableType::Char => {
+                    res.append(emitter.emit(vec![IMP::IO(IOOperations::PrintAsChar)]))
                 }
-                VariableType::String(_) => {
+                VariableType::String(_) | VariableType::Str => {
                     let print_loop_start_label = state.get_label();
                     let print_loop_end_label = state.get_label();
                     res.append(emitter.emit(vec![
@@ -108,23 +321,132 @@ pub fn print(state: &mut State, args: &Vec<Expression>, res: &mut CodeOutput) {
                         IMP::FlowControl(FlowControlOperations::Jump(print_loop_start_label)),
                         IMP::FlowControl(FlowControlOperations::SetLabel(print_loop_end_label)),
                         IMP::Stack(StackOperations::Discard),
+                        IMP::Stack(StackOperations::Discard),
                     ]));
                 }
-                _ => {
-                    panic!("Only integer and string values are supported for now");
-                }
+                VariableType::Map(_) => unreachable!("maps are not values"),
             }
         }
-        Expression::BinaryOp { .. } => {
-            let mut emitter = CodeEmitter {};
-            let (code, debug_code, ..) =
-                emitter.evaluate_expression(expression, &state.heap_allocation_map, 0);
-            res.add(code, debug_code.render());
-            let CodeOutput { code, debug_code } = IMP::IO(IOOperations::PrintAsNumber).gen();
-            res.add(code, debug_code);
-        }
-        _ => {
-            panic!("Unsupported expression");
-        }
     }
+    Ok(())
+}
+
+/// [] -> [1 or 0]
+fn eof_subroutine(state: &mut State) -> Vec<IMP> {
+    let peekc = state.subroutine("peekc", peekc);
+    let eof_label = state.get_label();
+    vec![
+        IMP::FlowControl(FlowControlOperations::Call(peekc)),
+        IMP::FlowControl(FlowControlOperations::JumpIfNegative(eof_label)),
+        IMP::Stack(StackOperations::PushNumber(0)),
+        IMP::FlowControl(FlowControlOperations::Return),
+        IMP::FlowControl(FlowControlOperations::SetLabel(eof_label)),
+        IMP::Stack(StackOperations::PushNumber(1)),
+        IMP::FlowControl(FlowControlOperations::Return),
+    ]
+}
+
+/// [addr limit] -> [0 when the line fit, 1 when it was truncated, -1 at the end of input]
+fn readln(state: &mut State) -> Vec<IMP> {
+    let peekc = state.subroutine("peekc", peekc);
+    let getc = state.subroutine("getc", getc);
+    let loop_label = state.get_label();
+    let end_label = state.get_label();
+    let full_label = state.get_label();
+    let skip_label = state.get_label();
+    let skip_end_label = state.get_label();
+    let truncated_label = state.get_label();
+    let eof_label = state.get_label();
+
+    // [addr limit count] -> [], adds the terminator
+    let terminate = || {
+        vec![
+            IMP::Stack(StackOperations::Slide(1)),
+            IMP::Arithmetic(ArithmeticOperations::Add),
+            IMP::Stack(StackOperations::PushNumber(0)),
+            IMP::Heap(HeapOperations::Store),
+        ]
+    };
+
+    let mut instructions = vec![
+        // Number of characters read
+        IMP::Stack(StackOperations::PushNumber(0)),
+        IMP::FlowControl(FlowControlOperations::Call(peekc)),
+        IMP::FlowControl(FlowControlOperations::JumpIfNegative(eof_label)),
+        IMP::FlowControl(FlowControlOperations::SetLabel(loop_label)),
+        IMP::FlowControl(FlowControlOperations::Call(getc)),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::FlowControl(FlowControlOperations::JumpIfNegative(end_label)),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::Stack(StackOperations::PushNumber('\n' as i32)),
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::FlowControl(FlowControlOperations::JumpIfZero(end_label)),
+        // Stop storing when the buffer is full
+        IMP::Stack(StackOperations::CopyNth(2)),
+        IMP::Stack(StackOperations::CopyNth(2)),
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::Stack(StackOperations::PushNumber(1)),
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::FlowControl(FlowControlOperations::JumpIfNegative(full_label)),
+        IMP::Stack(StackOperations::CopyNth(3)),
+        IMP::Stack(StackOperations::CopyNth(2)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Stack(StackOperations::Swap),
+        IMP::Heap(HeapOperations::Store),
+        IMP::Stack(StackOperations::PushNumber(HeapVariableSize::Char.size())),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::FlowControl(FlowControlOperations::Jump(loop_label)),
+        // Newline or end of input
+        IMP::FlowControl(FlowControlOperations::SetLabel(end_label)),
+        IMP::Stack(StackOperations::Discard),
+    ];
+    instructions.extend(terminate());
+    instructions.extend([
+        IMP::Stack(StackOperations::PushNumber(0)),
+        IMP::FlowControl(FlowControlOperations::Return),
+        // Skip the rest of the line
+        IMP::FlowControl(FlowControlOperations::SetLabel(full_label)),
+        IMP::Stack(StackOperations::Discard),
+        IMP::FlowControl(FlowControlOperations::SetLabel(skip_label)),
+        IMP::FlowControl(FlowControlOperations::Call(getc)),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::FlowControl(FlowControlOperations::JumpIfNegative(skip_end_label)),
+        IMP::Stack(StackOperations::PushNumber('\n' as i32)),
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::FlowControl(FlowControlOperations::JumpIfZero(truncated_label)),
+        IMP::FlowControl(FlowControlOperations::Jump(skip_label)),
+        IMP::FlowControl(FlowControlOperations::SetLabel(skip_end_label)),
+        IMP::Stack(StackOperations::Discard),
+        IMP::FlowControl(FlowControlOperations::SetLabel(truncated_label)),
+    ]);
+    instructions.extend(terminate());
+    instructions.extend([
+        IMP::Stack(StackOperations::PushNumber(1)),
+        IMP::FlowControl(FlowControlOperations::Return),
+        IMP::FlowControl(FlowControlOperations::SetLabel(eof_label)),
+    ]);
+    instructions.extend(terminate());
+    instructions.extend([
+        IMP::Stack(StackOperations::PushNumber(-1)),
+        IMP::FlowControl(FlowControlOperations::Return),
+    ]);
+    instructions
+}
+
+/// [] -> [number], reads a line and parses it like `parse_int`
+fn read_int(state: &mut State) -> Vec<IMP> {
+    // Long enough for any i32 with some surrounding whitespace
+    let buffer_type = VariableType::String(32);
+    let buffer = state.heap_allocation_map.reserve(buffer_type);
+    let readln = state.subroutine("readln", readln);
+    let atoi = state.subroutine("atoi", bi_convert::atoi);
+    vec![
+        IMP::Stack(StackOperations::PushNumber(buffer)),
+        IMP::Stack(StackOperations::PushNumber(31)),
+        IMP::FlowControl(FlowControlOperations::Call(readln)),
+        IMP::Stack(StackOperations::Discard),
+        IMP::Stack(StackOperations::PushNumber(buffer)),
+        IMP::FlowControl(FlowControlOperations::Call(atoi)),
+        IMP::FlowControl(FlowControlOperations::Return),
+    ]
 }
@@ -0,0 +1,966 @@
+//! Maps from integers or strings to integers. A map variable holds the address of a hash
+//! table from the allocator with open addressing and linear probing:
+//!
+//! ```text
+//! [count] [used] [capacity] [state key value] [state key value] ...
+//! ```
+//!
+//! `used` counts the live and the deleted slots, which both keep probes going. When an
+//! insert would fill more than 3/4 of the slots, the live entries move to a new table with
+//! room for four times as many entries. String keys are copied into blocks of their own.
+
+use crate::{
+    ast::{Expression, MapKey},
+    diagnostic::Diagnostic,
+    lexer::Span,
+    specs::whitespace::{
+        ArithmeticOperations, FlowControlOperations, HeapOperations, StackOperations, IMP,
+    },
+    transpiler::{
+        emitter::{CodeEmitter, EvaluationResult},
+        state::State,
+        CodeOutput, DebugCodeBlock, VariableType,
+    },
+};
+
+use super::{bi_math, bi_memory, bi_string, check_arity, Parameter};
+
+/// Offsets of the header fields from the start of the table
+const COUNT: i32 = 0;
+const USED: i32 = 1;
+const CAPACITY: i32 = 2;
+const SLOTS: i32 = 3;
+/// Offsets of the fields from the start of a slot
+const STATE: i32 = 0;
+const KEY: i32 = 1;
+const VALUE: i32 = 2;
+const SLOT_SIZE: i32 = 3;
+/// Slot states
+const EMPTY: i32 = 0;
+const LIVE: i32 = 1;
+const DELETED: i32 = 2;
+/// Slots of a new table
+const INITIAL_CAPACITY: i32 = 8;
+/// String hashes are kept below this prime, so that `hash * 31 + char` never overflows
+const HASH_MODULUS: i32 = 1_000_003;
+
+/// Sets up the map variable at `addr` when the declaration is reached. Like other
+/// variables, a declaration that runs again keeps the content.
+pub fn declare(state: &mut State, addr: i32) -> Vec<IMP> {
+    state.initialize(addr, 0);
+    let init = state.subroutine("map_init", init_subroutine);
+    vec![
+        IMP::Stack(StackOperations::PushNumber(addr)),
+        IMP::FlowControl(FlowControlOperations::Call(init)),
+    ]
+}
+
+/// `put(map, key, value)`, adds an entry or replaces the value of an existing key
+pub fn put(
+    state: &mut State,
+    args: &[Expression],
+    span: Span,
+    res: &mut CodeOutput,
+) -> Result<(), Diagnostic> {
+    check_arity("put", 3, args, span)?;
+    let (mut code, mut debug_code, key) = map_arguments("put", args, state, 0)?;
+    let mut emitter = CodeEmitter {};
+    let value = emitter.evaluate_expression(&args[2], state, 0)?;
+    if !value.type_.is_numeric() {
+        return Err(Diagnostic::new(
+            format!("put expects i32 as value, found {}", value.type_),
+            args[2].span,
+        ));
+    }
+    code.push_str(&value.code);
+    debug_code.append(value.debug_code);
+    res.add(code, debug_code.render());
+    let put = keyed(
+        state,
+        key,
+        ["map_put_int", "map_put_string"],
+        [
+            |state| put_subroutine(state, MapKey::Int),
+            |state| put_subroutine(state, MapKey::String),
+        ],
+    );
+    res.append(emitter.emit(vec![IMP::FlowControl(FlowControlOperations::Call(put))]));
+    Ok(())
+}
+
+/// `remove(map, key)`, does nothing when the key is missing
+pub fn remove(
+    state: &mut State,
+    args: &[Expression],
+    span: Span,
+    res: &mut CodeOutput,
+) -> Result<(), Diagnostic> {
+    check_arity("remove", 2, args, span)?;
+    let (code, debug_code, key) = map_arguments("remove", args, state, 0)?;
+    res.add(code, debug_code.render());
+    let remove = keyed(
+        state,
+        key,
+        ["map_remove_int", "map_remove_string"],
+        [
+            |state| remove_subroutine(state, MapKey::Int),
+            |state| remove_subroutine(state, MapKey::String),
+        ],
+    );
+    let mut emitter = CodeEmitter {};
+    res.append(emitter.emit(vec![IMP::FlowControl(FlowControlOperations::Call(remove))]));
+    Ok(())
+}
+
+/// `get(map, key)`, the value of the key or 0 when it is missing
+pub fn get(
+    state: &mut State,
+    args: &[Expression],
+    span: Span,
+    level: i8,
+) -> Result<EvaluationResult, Diagnostic> {
+    check_arity("get", 2, args, span)?;
+    let (mut code, mut debug_code, key) = map_arguments("get", args, state, level)?;
+    let get = keyed(
+        state,
+        key,
+        ["map_get_int", "map_get_string"],
+        [
+            |state| get_subroutine(state, MapKey::Int),
+            |state| get_subroutine(state, MapKey::String),
+        ],
+    );
+    CodeEmitter::emit_into(
+        &mut code,
+        &mut debug_code,
+        vec![IMP::FlowControl(FlowControlOperations::Call(get))],
+    );
+    Ok(EvaluationResult {
+        code,
+        debug_code,
+        type_: VariableType::Int,
+    })
+}
+
+/// `has(map, key)`, 1 when the key is in the map and 0 otherwise
+pub fn has(
+    state: &mut State,
+    args: &[Expression],
+    span: Span,
+    level: i8,
+) -> Result<EvaluationResult, Diagnostic> {
+    check_arity("has", 2, args, span)?;
+    let (mut code, mut debug_code, key) = map_arguments("has", args, state, level)?;
+    let has = keyed(
+        state,
+        key,
+        ["map_has_int", "map_has_string"],
+        [
+            |state| has_subroutine(state, MapKey::Int),
+            |state| has_subroutine(state, MapKey::String),
+        ],
+    );
+    CodeEmitter::emit_into(
+        &mut code,
+        &mut debug_code,
+        vec![IMP::FlowControl(FlowControlOperations::Call(has))],
+    );
+    Ok(EvaluationResult {
+        code,
+        debug_code,
+        type_: VariableType::Int,
+    })
+}
+
+/// Pushes the address of the map variable and the key, returns the key type of the map
+fn map_arguments(
+    name: &str,
+    args: &[Expression],
+    state: &mut State,
+    level: i8,
+) -> Result<(String, DebugCodeBlock, MapKey), Diagnostic> {
+    let error = || Diagnostic::new(format!("{} expects a map variable", name), args[0].span);
+    let crate::ast::ExpressionKind::Variable(variable) = &args[0].kind else {
+        return Err(error());
+    };
+    let variable = state.heap_allocation_map.lookup(variable, args[0].span)?;
+    let VariableType::Map(key) = variable.type_() else {
+        return Err(error());
+    };
+    let mut code = String::new();
+    let mut debug_code = DebugCodeBlock::new(None, level);
+    CodeEmitter::emit_into(
+        &mut code,
+        &mut debug_code,
+        vec![IMP::Stack(StackOperations::PushNumber(variable.offset()))],
+    );
+    let mut emitter = CodeEmitter {};
+    let result = emitter.evaluate_expression(&args[1], state, level)?;
+    let parameter = match key {
+        MapKey::Int => Parameter::Int,
+        MapKey::String => Parameter::String,
+    };
+    if !parameter.accepts(result.type_) {
+        return Err(Diagnostic::new(
+            format!(
+                "{} expects {} as key, found {}",
+                name, parameter, result.type_
+            ),
+            args[1].span,
+        ));
+    }
+    code.push_str(&result.code);
+    debug_code.append(result.debug_code);
+    Ok((code, debug_code, key))
+}
+
+/// Returns the label of a subroutine that is generated separately for every key type
+fn keyed(
+    state: &mut State,
+    key: MapKey,
+    names: [&'static str; 2],
+    bodies: [fn(&mut State) -> Vec<IMP>; 2],
+) -> i32 {
+    let index = match key {
+        MapKey::Int => 0,
+        MapKey::String => 1,
+    };
+    state.subroutine(names[index], bodies[index])
+}
+
+/// [key capacity] -> [index of the first slot to probe]
+fn hash(state: &mut State, key: MapKey) -> i32 {
+    keyed(
+        state,
+        key,
+        ["map_hash_int", "map_hash_string"],
+        [hash_int_subroutine, hash_string_subroutine],
+    )
+}
+
+/// [key key] -> [1 when they are equal, 0 otherwise]
+fn equals(state: &mut State, key: MapKey) -> i32 {
+    keyed(
+        state,
+        key,
+        ["map_equals_int", "map_equals_string"],
+        [equals_int_subroutine, equals_string_subroutine],
+    )
+}
+
+/// [variable key] -> [address of the slot of the key, 0 when it is missing]
+fn find(state: &mut State, key: MapKey) -> i32 {
+    keyed(
+        state,
+        key,
+        ["map_find_int", "map_find_string"],
+        [
+            |state| find_subroutine(state, MapKey::Int),
+            |state| find_subroutine(state, MapKey::String),
+        ],
+    )
+}
+
+/// [table key] -> [address of the first slot that is not live, starting at the hash of the key]
+fn free_slot(state: &mut State, key: MapKey) -> i32 {
+    keyed(
+        state,
+        key,
+        ["map_free_slot_int", "map_free_slot_string"],
+        [
+            |state| free_slot_subroutine(state, MapKey::Int),
+            |state| free_slot_subroutine(state, MapKey::String),
+        ],
+    )
+}
+
+/// [variable] -> [], moves the live entries to a new table
+fn rehash(state: &mut State, key: MapKey) -> i32 {
+    keyed(
+        state,
+        key,
+        ["map_rehash_int", "map_rehash_string"],
+        [
+            |state| rehash_subroutine(state, MapKey::Int),
+            |state| rehash_subroutine(state, MapKey::String),
+        ],
+    )
+}
+
+/// [] -> [value of the cell]
+fn load(cell: i32) -> [IMP; 2] {
+    [
+        IMP::Stack(StackOperations::PushNumber(cell)),
+        IMP::Heap(HeapOperations::Retrieve),
+    ]
+}
+
+/// [value] -> [], stores the value into the cell
+fn store(cell: i32) -> [IMP; 3] {
+    [
+        IMP::Stack(StackOperations::PushNumber(cell)),
+        IMP::Stack(StackOperations::Swap),
+        IMP::Heap(HeapOperations::Store),
+    ]
+}
+
+/// [] -> [address of a field of the table or the slot whose address is stored in the cell]
+fn field(cell: i32, offset: i32) -> [IMP; 4] {
+    [
+        IMP::Stack(StackOperations::PushNumber(cell)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(offset)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+    ]
+}
+
+/// [] -> [], stores the end of the slots of the table in `table` into `end`
+fn slots_end(table: i32, end: i32) -> Vec<IMP> {
+    let mut code = vec![IMP::Stack(StackOperations::PushNumber(end))];
+    code.extend(field(table, SLOTS));
+    code.extend(field(table, CAPACITY));
+    code.extend([
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(SLOT_SIZE)),
+        IMP::Arithmetic(ArithmeticOperations::Multiply),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Heap(HeapOperations::Store),
+    ]);
+    code
+}
+
+/// [index] -> [], stores the address of the slot at the index into `slot`
+fn slot_at(table: i32, slot: i32) -> Vec<IMP> {
+    let mut code = vec![
+        IMP::Stack(StackOperations::PushNumber(SLOT_SIZE)),
+        IMP::Arithmetic(ArithmeticOperations::Multiply),
+    ];
+    code.extend(field(table, SLOTS));
+    code.extend([IMP::Arithmetic(ArithmeticOperations::Add)]);
+    code.extend(store(slot));
+    code
+}
+
+/// Moves `slot` to the next slot, wrapping around at `end`, and jumps to `loop_label`
+fn next_slot(table: i32, slot: i32, end: i32, loop_label: i32) -> Vec<IMP> {
+    let mut code = vec![IMP::Stack(StackOperations::PushNumber(slot))];
+    code.extend(field(slot, SLOT_SIZE));
+    code.push(IMP::Heap(HeapOperations::Store));
+    code.extend(load(slot));
+    code.extend(load(end));
+    code.extend([
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::FlowControl(FlowControlOperations::JumpIfNegative(loop_label)),
+        IMP::Stack(StackOperations::PushNumber(slot)),
+    ]);
+    code.extend(field(table, SLOTS));
+    code.extend([
+        IMP::Heap(HeapOperations::Store),
+        IMP::FlowControl(FlowControlOperations::Jump(loop_label)),
+    ]);
+    code
+}
+
+/// [variable] -> [], gives the variable an empty table unless it already has one
+fn init_subroutine(state: &mut State) -> Vec<IMP> {
+    let new_table = state.subroutine("map_new_table", new_table_subroutine);
+    let new_label = state.get_label();
+    vec![
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::FlowControl(FlowControlOperations::JumpIfZero(new_label)),
+        IMP::Stack(StackOperations::Discard),
+        IMP::FlowControl(FlowControlOperations::Return),
+        IMP::FlowControl(FlowControlOperations::SetLabel(new_label)),
+        IMP::Stack(StackOperations::PushNumber(INITIAL_CAPACITY)),
+        IMP::FlowControl(FlowControlOperations::Call(new_table)),
+        IMP::Heap(HeapOperations::Store),
+        IMP::FlowControl(FlowControlOperations::Return),
+    ]
+}
+
+/// [capacity] -> [address of an empty table]
+fn new_table_subroutine(state: &mut State) -> Vec<IMP> {
+    let alloc = state.subroutine("alloc", bi_memory::alloc_subroutine);
+    let capacity = state.heap_allocation_map.reserve(VariableType::Int);
+    let table = state.heap_allocation_map.reserve(VariableType::Int);
+    let slot = state.heap_allocation_map.reserve(VariableType::Int);
+    let end = state.heap_allocation_map.reserve(VariableType::Int);
+    let loop_label = state.get_label();
+    let clear_label = state.get_label();
+    let mut code = Vec::from(store(capacity));
+    code.extend(load(capacity));
+    code.extend([
+        IMP::Stack(StackOperations::PushNumber(SLOT_SIZE)),
+        IMP::Arithmetic(ArithmeticOperations::Multiply),
+        IMP::Stack(StackOperations::PushNumber(SLOTS)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::FlowControl(FlowControlOperations::Call(alloc)),
+    ]);
+    code.extend(store(table));
+    for (offset, value) in [(COUNT, None), (USED, None), (CAPACITY, Some(capacity))] {
+        code.extend(field(table, offset));
+        match value {
+            Some(cell) => code.extend(load(cell)),
+            None => code.push(IMP::Stack(StackOperations::PushNumber(0))),
+        }
+        code.push(IMP::Heap(HeapOperations::Store));
+    }
+    code.extend(slots_end(table, end));
+    code.extend(field(table, SLOTS));
+    code.extend(store(slot));
+    // Mark every slot as empty
+    code.push(IMP::FlowControl(FlowControlOperations::SetLabel(
+        loop_label,
+    )));
+    code.extend(load(slot));
+    code.extend(load(end));
+    code.extend([
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::FlowControl(FlowControlOperations::JumpIfNegative(clear_label)),
+    ]);
+    code.extend(load(table));
+    code.extend([
+        IMP::FlowControl(FlowControlOperations::Return),
+        IMP::FlowControl(FlowControlOperations::SetLabel(clear_label)),
+    ]);
+    code.extend(load(slot));
+    code.extend([
+        IMP::Stack(StackOperations::PushNumber(EMPTY)),
+        IMP::Heap(HeapOperations::Store),
+        IMP::Stack(StackOperations::PushNumber(slot)),
+    ]);
+    code.extend(field(slot, SLOT_SIZE));
+    code.extend([
+        IMP::Heap(HeapOperations::Store),
+        IMP::FlowControl(FlowControlOperations::Jump(loop_label)),
+    ]);
+    code
+}
+
+/// [key capacity] -> [index]. `key % capacity` is between `-capacity` and `capacity` with
+/// both rounding modes of the interpreters, so adding the capacity makes it non-negative.
+fn hash_int_subroutine(_: &mut State) -> Vec<IMP> {
+    vec![
+        IMP::Stack(StackOperations::CopyNth(1)),
+        IMP::Stack(StackOperations::CopyNth(1)),
+        IMP::Arithmetic(ArithmeticOperations::Modulo),
+        IMP::Stack(StackOperations::CopyNth(1)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Stack(StackOperations::Swap),
+        IMP::Arithmetic(ArithmeticOperations::Modulo),
+        IMP::Stack(StackOperations::Slide(1)),
+        IMP::FlowControl(FlowControlOperations::Return),
+    ]
+}
+
+/// [addr capacity] -> [index], polynomial hash of the characters
+fn hash_string_subroutine(state: &mut State) -> Vec<IMP> {
+    let capacity = state.heap_allocation_map.reserve(VariableType::Int);
+    let addr = state.heap_allocation_map.reserve(VariableType::Int);
+    let loop_label = state.get_label();
+    let end_label = state.get_label();
+    let mut code = Vec::from(store(capacity));
+    code.extend(store(addr));
+    code.extend([
+        IMP::Stack(StackOperations::PushNumber(0)),
+        IMP::FlowControl(FlowControlOperations::SetLabel(loop_label)),
+    ]);
+    code.extend(load(addr));
+    code.extend([
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::FlowControl(FlowControlOperations::JumpIfZero(end_label)),
+        IMP::Stack(StackOperations::Swap),
+        IMP::Stack(StackOperations::PushNumber(31)),
+        IMP::Arithmetic(ArithmeticOperations::Multiply),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Stack(StackOperations::PushNumber(HASH_MODULUS)),
+        IMP::Arithmetic(ArithmeticOperations::Modulo),
+        IMP::Stack(StackOperations::PushNumber(addr)),
+    ]);
+    code.extend(field(addr, 1));
+    code.extend([
+        IMP::Heap(HeapOperations::Store),
+        IMP::FlowControl(FlowControlOperations::Jump(loop_label)),
+        IMP::FlowControl(FlowControlOperations::SetLabel(end_label)),
+        IMP::Stack(StackOperations::Discard),
+    ]);
+    code.extend(load(capacity));
+    code.extend([
+        IMP::Arithmetic(ArithmeticOperations::Modulo),
+        IMP::FlowControl(FlowControlOperations::Return),
+    ]);
+    code
+}
+
+/// [a b] -> [a == b]
+fn equals_int_subroutine(state: &mut State) -> Vec<IMP> {
+    let equal_label = state.get_label();
+    vec![
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::FlowControl(FlowControlOperations::JumpIfZero(equal_label)),
+        IMP::Stack(StackOperations::PushNumber(0)),
+        IMP::FlowControl(FlowControlOperations::Return),
+        IMP::FlowControl(FlowControlOperations::SetLabel(equal_label)),
+        IMP::Stack(StackOperations::PushNumber(1)),
+        IMP::FlowControl(FlowControlOperations::Return),
+    ]
+}
+
+/// [a b] -> [1 when the strings are equal, 0 otherwise]
+fn equals_string_subroutine(state: &mut State) -> Vec<IMP> {
+    let loop_label = state.get_label();
+    let same_label = state.get_label();
+    let equal_label = state.get_label();
+    vec![
+        IMP::FlowControl(FlowControlOperations::SetLabel(loop_label)),
+        IMP::Stack(StackOperations::CopyNth(1)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::CopyNth(1)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::FlowControl(FlowControlOperations::JumpIfZero(same_label)),
+        IMP::Stack(StackOperations::Discard),
+        IMP::Stack(StackOperations::Discard),
+        IMP::Stack(StackOperations::PushNumber(0)),
+        IMP::FlowControl(FlowControlOperations::Return),
+        // Equal when both ended
+        IMP::FlowControl(FlowControlOperations::SetLabel(same_label)),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::FlowControl(FlowControlOperations::JumpIfZero(equal_label)),
+        IMP::Stack(StackOperations::PushNumber(1)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Stack(StackOperations::Swap),
+        IMP::Stack(StackOperations::PushNumber(1)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Stack(StackOperations::Swap),
+        IMP::FlowControl(FlowControlOperations::Jump(loop_label)),
+        IMP::FlowControl(FlowControlOperations::SetLabel(equal_label)),
+        IMP::Stack(StackOperations::Discard),
+        IMP::Stack(StackOperations::Discard),
+        IMP::Stack(StackOperations::PushNumber(1)),
+        IMP::FlowControl(FlowControlOperations::Return),
+    ]
+}
+
+/// [variable key] -> [slot or 0]. The load factor keeps an empty slot in every table, which
+/// ends the probes for missing keys.
+fn find_subroutine(state: &mut State, key_type: MapKey) -> Vec<IMP> {
+    let hash = hash(state, key_type);
+    let equals = equals(state, key_type);
+    let key = state.heap_allocation_map.reserve(VariableType::Int);
+    let table = state.heap_allocation_map.reserve(VariableType::Int);
+    let slot = state.heap_allocation_map.reserve(VariableType::Int);
+    let end = state.heap_allocation_map.reserve(VariableType::Int);
+    let loop_label = state.get_label();
+    let compare_label = state.get_label();
+    let next_label = state.get_label();
+    let missing_label = state.get_label();
+    let mut code = Vec::from(store(key));
+    code.push(IMP::Heap(HeapOperations::Retrieve));
+    code.extend(store(table));
+    code.extend(slots_end(table, end));
+    code.extend(load(key));
+    code.extend(field(table, CAPACITY));
+    code.extend([
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::FlowControl(FlowControlOperations::Call(hash)),
+    ]);
+    code.extend(slot_at(table, slot));
+    code.push(IMP::FlowControl(FlowControlOperations::SetLabel(
+        loop_label,
+    )));
+    code.extend(field(slot, STATE));
+    code.extend([
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::FlowControl(FlowControlOperations::JumpIfZero(missing_label)),
+        IMP::Stack(StackOperations::PushNumber(LIVE)),
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::FlowControl(FlowControlOperations::JumpIfZero(compare_label)),
+        IMP::FlowControl(FlowControlOperations::Jump(next_label)),
+        IMP::FlowControl(FlowControlOperations::SetLabel(compare_label)),
+    ]);
+    code.extend(field(slot, KEY));
+    code.push(IMP::Heap(HeapOperations::Retrieve));
+    code.extend(load(key));
+    code.extend([
+        IMP::FlowControl(FlowControlOperations::Call(equals)),
+        IMP::FlowControl(FlowControlOperations::JumpIfZero(next_label)),
+    ]);
+    code.extend(load(slot));
+    code.extend([
+        IMP::FlowControl(FlowControlOperations::Return),
+        IMP::FlowControl(FlowControlOperations::SetLabel(next_label)),
+    ]);
+    code.extend(next_slot(table, slot, end, loop_label));
+    // The state of the empty slot is the result
+    code.extend([
+        IMP::FlowControl(FlowControlOperations::SetLabel(missing_label)),
+        IMP::FlowControl(FlowControlOperations::Return),
+    ]);
+    code
+}
+
+/// [table key] -> [slot]
+fn free_slot_subroutine(state: &mut State, key_type: MapKey) -> Vec<IMP> {
+    let hash = hash(state, key_type);
+    let table = state.heap_allocation_map.reserve(VariableType::Int);
+    let slot = state.heap_allocation_map.reserve(VariableType::Int);
+    let end = state.heap_allocation_map.reserve(VariableType::Int);
+    let loop_label = state.get_label();
+    let next_label = state.get_label();
+    let mut code = vec![IMP::Stack(StackOperations::Swap)];
+    code.extend(store(table));
+    code.extend(slots_end(table, end));
+    code.extend(field(table, CAPACITY));
+    code.extend([
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::FlowControl(FlowControlOperations::Call(hash)),
+    ]);
+    code.extend(slot_at(table, slot));
+    code.push(IMP::FlowControl(FlowControlOperations::SetLabel(
+        loop_label,
+    )));
+    code.extend(field(slot, STATE));
+    code.extend([
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(LIVE)),
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::FlowControl(FlowControlOperations::JumpIfZero(next_label)),
+    ]);
+    code.extend(load(slot));
+    code.extend([
+        IMP::FlowControl(FlowControlOperations::Return),
+        IMP::FlowControl(FlowControlOperations::SetLabel(next_label)),
+    ]);
+    code.extend(next_slot(table, slot, end, loop_label));
+    code
+}
+
+/// [variable] -> []
+fn rehash_subroutine(state: &mut State, key_type: MapKey) -> Vec<IMP> {
+    let new_table = state.subroutine("map_new_table", new_table_subroutine);
+    let free = state.subroutine("free", bi_memory::free_subroutine);
+    let max = state.subroutine("max", bi_math::max_subroutine);
+    let free_slot = free_slot(state, key_type);
+    let variable = state.heap_allocation_map.reserve(VariableType::Int);
+    let old = state.heap_allocation_map.reserve(VariableType::Int);
+    let new = state.heap_allocation_map.reserve(VariableType::Int);
+    let slot = state.heap_allocation_map.reserve(VariableType::Int);
+    let end = state.heap_allocation_map.reserve(VariableType::Int);
+    let loop_label = state.get_label();
+    let move_label = state.get_label();
+    let next_label = state.get_label();
+    let mut code = vec![IMP::Stack(StackOperations::Duplicate)];
+    code.extend(store(variable));
+    code.push(IMP::Heap(HeapOperations::Retrieve));
+    code.extend(store(old));
+    // Room for four times the entries, counting the one about to be added
+    code.extend(field(old, COUNT));
+    code.extend([
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(1)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Stack(StackOperations::PushNumber(4)),
+        IMP::Arithmetic(ArithmeticOperations::Multiply),
+        IMP::Stack(StackOperations::PushNumber(INITIAL_CAPACITY)),
+        IMP::FlowControl(FlowControlOperations::Call(max)),
+        IMP::FlowControl(FlowControlOperations::Call(new_table)),
+    ]);
+    code.extend(store(new));
+    for offset in [COUNT, USED] {
+        code.extend(field(new, offset));
+        code.extend(field(old, COUNT));
+        code.extend([
+            IMP::Heap(HeapOperations::Retrieve),
+            IMP::Heap(HeapOperations::Store),
+        ]);
+    }
+    code.extend(slots_end(old, end));
+    code.extend(field(old, SLOTS));
+    code.extend(store(slot));
+    code.push(IMP::FlowControl(FlowControlOperations::SetLabel(
+        loop_label,
+    )));
+    code.extend(load(slot));
+    code.extend(load(end));
+    code.extend([
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::FlowControl(FlowControlOperations::JumpIfNegative(move_label)),
+    ]);
+    code.extend(load(old));
+    code.push(IMP::FlowControl(FlowControlOperations::Call(free)));
+    code.extend(load(variable));
+    code.extend(load(new));
+    code.extend([
+        IMP::Heap(HeapOperations::Store),
+        IMP::FlowControl(FlowControlOperations::Return),
+        // Live entries keep their key, a string key keeps its block
+        IMP::FlowControl(FlowControlOperations::SetLabel(move_label)),
+    ]);
+    code.extend(field(slot, STATE));
+    code.extend([
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(LIVE)),
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::FlowControl(FlowControlOperations::JumpIfZero(next_label)),
+        IMP::Stack(StackOperations::PushNumber(slot)),
+    ]);
+    code.extend(field(slot, SLOT_SIZE));
+    code.extend([
+        IMP::Heap(HeapOperations::Store),
+        IMP::FlowControl(FlowControlOperations::Jump(loop_label)),
+        IMP::FlowControl(FlowControlOperations::SetLabel(next_label)),
+    ]);
+    code.extend(load(new));
+    code.extend(field(slot, KEY));
+    code.extend([
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::FlowControl(FlowControlOperations::Call(free_slot)),
+    ]);
+    for (offset, value) in [(STATE, None), (KEY, Some(KEY)), (VALUE, Some(VALUE))] {
+        code.extend([
+            IMP::Stack(StackOperations::Duplicate),
+            IMP::Stack(StackOperations::PushNumber(offset)),
+            IMP::Arithmetic(ArithmeticOperations::Add),
+        ]);
+        match value {
+            Some(offset) => {
+                code.extend(field(slot, offset));
+                code.push(IMP::Heap(HeapOperations::Retrieve));
+            }
+            None => code.push(IMP::Stack(StackOperations::PushNumber(LIVE))),
+        }
+        code.push(IMP::Heap(HeapOperations::Store));
+    }
+    code.extend([
+        IMP::Stack(StackOperations::Discard),
+        IMP::Stack(StackOperations::PushNumber(slot)),
+    ]);
+    code.extend(field(slot, SLOT_SIZE));
+    code.extend([
+        IMP::Heap(HeapOperations::Store),
+        IMP::FlowControl(FlowControlOperations::Jump(loop_label)),
+    ]);
+    code
+}
+
+/// [variable key value] -> []
+fn put_subroutine(state: &mut State, key_type: MapKey) -> Vec<IMP> {
+    let find = find(state, key_type);
+    let rehash = rehash(state, key_type);
+    let free_slot = free_slot(state, key_type);
+    let variable = state.heap_allocation_map.reserve(VariableType::Int);
+    let key = state.heap_allocation_map.reserve(VariableType::Int);
+    let value = state.heap_allocation_map.reserve(VariableType::Int);
+    let table = state.heap_allocation_map.reserve(VariableType::Int);
+    let slot = state.heap_allocation_map.reserve(VariableType::Int);
+    let new_label = state.get_label();
+    let grow_label = state.get_label();
+    let insert_label = state.get_label();
+    let fill_label = state.get_label();
+    let mut code = Vec::from(store(value));
+    code.extend(store(key));
+    code.extend(store(variable));
+    code.extend(load(variable));
+    code.extend(load(key));
+    code.extend([
+        IMP::FlowControl(FlowControlOperations::Call(find)),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::FlowControl(FlowControlOperations::JumpIfZero(new_label)),
+        IMP::Stack(StackOperations::PushNumber(VALUE)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+    ]);
+    code.extend(load(value));
+    code.extend([
+        IMP::Heap(HeapOperations::Store),
+        IMP::FlowControl(FlowControlOperations::Return),
+        IMP::FlowControl(FlowControlOperations::SetLabel(new_label)),
+        IMP::Stack(StackOperations::Discard),
+    ]);
+    // Grow when (used + 1) * 4 > capacity * 3
+    code.extend(load(variable));
+    code.push(IMP::Heap(HeapOperations::Retrieve));
+    code.extend(store(table));
+    code.extend(field(table, CAPACITY));
+    code.extend([
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(3)),
+        IMP::Arithmetic(ArithmeticOperations::Multiply),
+    ]);
+    code.extend(field(table, USED));
+    code.extend([
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(1)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Stack(StackOperations::PushNumber(4)),
+        IMP::Arithmetic(ArithmeticOperations::Multiply),
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::FlowControl(FlowControlOperations::JumpIfNegative(grow_label)),
+        IMP::FlowControl(FlowControlOperations::Jump(insert_label)),
+        IMP::FlowControl(FlowControlOperations::SetLabel(grow_label)),
+    ]);
+    code.extend(load(variable));
+    code.push(IMP::FlowControl(FlowControlOperations::Call(rehash)));
+    code.extend(load(variable));
+    code.push(IMP::Heap(HeapOperations::Retrieve));
+    code.extend(store(table));
+    code.push(IMP::FlowControl(FlowControlOperations::SetLabel(
+        insert_label,
+    )));
+    code.extend(load(table));
+    code.extend(load(key));
+    code.push(IMP::FlowControl(FlowControlOperations::Call(free_slot)));
+    code.extend(store(slot));
+    // A deleted slot that is reused is already counted as used
+    code.extend(field(slot, STATE));
+    code.extend([
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::FlowControl(FlowControlOperations::JumpIfZero(fill_label)),
+    ]);
+    code.extend(field(table, USED));
+    code.extend([
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(1)),
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::Heap(HeapOperations::Store),
+        IMP::FlowControl(FlowControlOperations::SetLabel(fill_label)),
+    ]);
+    code.extend(field(table, USED));
+    code.extend([
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(1)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Heap(HeapOperations::Store),
+    ]);
+    code.extend(field(table, COUNT));
+    code.extend([
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(1)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Heap(HeapOperations::Store),
+    ]);
+    code.extend(field(slot, STATE));
+    code.extend([
+        IMP::Stack(StackOperations::PushNumber(LIVE)),
+        IMP::Heap(HeapOperations::Store),
+    ]);
+    code.extend(field(slot, KEY));
+    match key_type {
+        MapKey::Int => code.extend(load(key)),
+        MapKey::String => {
+            // The key may change later, so the map keeps a copy
+            let alloc = state.subroutine("alloc", bi_memory::alloc_subroutine);
+            let strlen = state.subroutine("strlen", bi_string::strlen);
+            let strncpy = state.subroutine("strncpy", bi_string::strncpy);
+            code.extend(load(key));
+            code.extend([
+                IMP::FlowControl(FlowControlOperations::Call(strlen)),
+                IMP::Stack(StackOperations::Duplicate),
+                IMP::Stack(StackOperations::PushNumber(1)),
+                IMP::Arithmetic(ArithmeticOperations::Add),
+                IMP::FlowControl(FlowControlOperations::Call(alloc)),
+                // [slot key, length, copy] -> [slot key, copy, copy, key, length]
+                IMP::Stack(StackOperations::Swap),
+                IMP::Stack(StackOperations::CopyNth(1)),
+                IMP::Stack(StackOperations::Swap),
+            ]);
+            code.extend(load(key));
+            code.extend([
+                IMP::Stack(StackOperations::Swap),
+                IMP::FlowControl(FlowControlOperations::Call(strncpy)),
+            ]);
+        }
+    }
+    code.push(IMP::Heap(HeapOperations::Store));
+    code.extend(field(slot, VALUE));
+    code.extend(load(value));
+    code.extend([
+        IMP::Heap(HeapOperations::Store),
+        IMP::FlowControl(FlowControlOperations::Return),
+    ]);
+    code
+}
+
+/// [variable key] -> []
+fn remove_subroutine(state: &mut State, key_type: MapKey) -> Vec<IMP> {
+    let find = find(state, key_type);
+    let missing_label = state.get_label();
+    let mut code = vec![
+        IMP::Stack(StackOperations::CopyNth(1)),
+        IMP::Stack(StackOperations::Swap),
+        IMP::FlowControl(FlowControlOperations::Call(find)),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::FlowControl(FlowControlOperations::JumpIfZero(missing_label)),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::Stack(StackOperations::PushNumber(STATE)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Stack(StackOperations::PushNumber(DELETED)),
+        IMP::Heap(HeapOperations::Store),
+    ];
+    if key_type == MapKey::String {
+        let free = state.subroutine("free", bi_memory::free_subroutine);
+        code.extend([
+            IMP::Stack(StackOperations::Duplicate),
+            IMP::Stack(StackOperations::PushNumber(KEY)),
+            IMP::Arithmetic(ArithmeticOperations::Add),
+            IMP::Heap(HeapOperations::Retrieve),
+            IMP::FlowControl(FlowControlOperations::Call(free)),
+        ]);
+    }
+    code.extend([
+        IMP::Stack(StackOperations::Discard),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(COUNT)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(1)),
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::Heap(HeapOperations::Store),
+        IMP::FlowControl(FlowControlOperations::Return),
+        IMP::FlowControl(FlowControlOperations::SetLabel(missing_label)),
+        IMP::Stack(StackOperations::Discard),
+        IMP::Stack(StackOperations::Discard),
+        IMP::FlowControl(FlowControlOperations::Return),
+    ]);
+    code
+}
+
+/// [variable key] -> [value or 0]
+fn get_subroutine(state: &mut State, key_type: MapKey) -> Vec<IMP> {
+    let find = find(state, key_type);
+    let missing_label = state.get_label();
+    vec![
+        IMP::FlowControl(FlowControlOperations::Call(find)),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::FlowControl(FlowControlOperations::JumpIfZero(missing_label)),
+        IMP::Stack(StackOperations::PushNumber(VALUE)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Heap(HeapOperations::Retrieve),
+        // The missing slot is 0, which is the result
+        IMP::FlowControl(FlowControlOperations::SetLabel(missing_label)),
+        IMP::FlowControl(FlowControlOperations::Return),
+    ]
+}
+
+/// [variable key] -> [1 or 0]
+fn has_subroutine(state: &mut State, key_type: MapKey) -> Vec<IMP> {
+    let find = find(state, key_type);
+    let missing_label = state.get_label();
+    vec![
+        IMP::FlowControl(FlowControlOperations::Call(find)),
+        IMP::FlowControl(FlowControlOperations::JumpIfZero(missing_label)),
+        IMP::Stack(StackOperations::PushNumber(1)),
+        IMP::FlowControl(FlowControlOperations::Return),
+        IMP::FlowControl(FlowControlOperations::SetLabel(missing_label)),
+        IMP::Stack(StackOperations::PushNumber(0)),
+        IMP::FlowControl(FlowControlOperations::Return),
+    ]
+}
@@ -0,0 +1,323 @@
+use crate::{
+    ast::Expression,
+    diagnostic::Diagnostic,
+    lexer::Span,
+    specs::whitespace::{
+        ArithmeticOperations, FlowControlOperations, HeapOperations, StackOperations, IMP,
+    },
+    transpiler::{
+        emitter::{CodeEmitter, EvaluationResult},
+        state::State,
+        VariableType,
+    },
+};
+
+use super::{evaluate_arguments, Parameter};
+
+pub fn abs(
+    state: &mut State,
+    args: &[Expression],
+    span: Span,
+    level: i8,
+) -> Result<EvaluationResult, Diagnostic> {
+    math_call("abs", 1, abs_subroutine, state, args, span, level)
+}
+
+/// -1, 0 or 1 depending on the sign of the argument
+pub fn sign(
+    state: &mut State,
+    args: &[Expression],
+    span: Span,
+    level: i8,
+) -> Result<EvaluationResult, Diagnostic> {
+    math_call("sign", 1, sign_subroutine, state, args, span, level)
+}
+
+pub fn min(
+    state: &mut State,
+    args: &[Expression],
+    span: Span,
+    level: i8,
+) -> Result<EvaluationResult, Diagnostic> {
+    math_call("min", 2, min_subroutine, state, args, span, level)
+}
+
+pub fn max(
+    state: &mut State,
+    args: &[Expression],
+    span: Span,
+    level: i8,
+) -> Result<EvaluationResult, Diagnostic> {
+    math_call("max", 2, max_subroutine, state, args, span, level)
+}
+
+/// Integer power, negative exponents give 0
+pub fn pow(
+    state: &mut State,
+    args: &[Expression],
+    span: Span,
+    level: i8,
+) -> Result<EvaluationResult, Diagnostic> {
+    math_call("pow", 2, pow_subroutine, state, args, span, level)
+}
+
+/// Square root rounded down, -1 for negative numbers
+pub fn isqrt(
+    state: &mut State,
+    args: &[Expression],
+    span: Span,
+    level: i8,
+) -> Result<EvaluationResult, Diagnostic> {
+    math_call("isqrt", 1, isqrt_subroutine, state, args, span, level)
+}
+
+/// Greatest common divisor, never negative. gcd(0, 0) is 0.
+pub fn gcd(
+    state: &mut State,
+    args: &[Expression],
+    span: Span,
+    level: i8,
+) -> Result<EvaluationResult, Diagnostic> {
+    math_call("gcd", 2, gcd_subroutine, state, args, span, level)
+}
+
+/// Least common multiple, never negative. 0 when either argument is 0.
+pub fn lcm(
+    state: &mut State,
+    args: &[Expression],
+    span: Span,
+    level: i8,
+) -> Result<EvaluationResult, Diagnostic> {
+    math_call("lcm", 2, lcm_subroutine, state, args, span, level)
+}
+
+/// Calls a subroutine that replaces its integer arguments with the result
+fn math_call(
+    name: &'static str,
+    arity: usize,
+    body: fn(&mut State) -> Vec<IMP>,
+    state: &mut State,
+    args: &[Expression],
+    span: Span,
+    level: i8,
+) -> Result<EvaluationResult, Diagnostic> {
+    let parameters = vec![Parameter::Int; arity];
+    let (mut code, mut debug_code) =
+        evaluate_arguments(name, &parameters, args, span, state, level)?;
+    let label = state.subroutine(name, body);
+    CodeEmitter::emit_into(
+        &mut code,
+        &mut debug_code,
+        vec![IMP::FlowControl(FlowControlOperations::Call(label))],
+    );
+    Ok(EvaluationResult {
+        code,
+        debug_code,
+        type_: VariableType::Int,
+    })
+}
+
+/// [x] -> [|x|]
+pub fn abs_subroutine(state: &mut State) -> Vec<IMP> {
+    let negative_label = state.get_label();
+    vec![
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::FlowControl(FlowControlOperations::JumpIfNegative(negative_label)),
+        IMP::FlowControl(FlowControlOperations::Return),
+        IMP::FlowControl(FlowControlOperations::SetLabel(negative_label)),
+        IMP::Stack(StackOperations::PushNumber(-1)),
+        IMP::Arithmetic(ArithmeticOperations::Multiply),
+        IMP::FlowControl(FlowControlOperations::Return),
+    ]
+}
+
+/// [x] -> [-1, 0 or 1]
+fn sign_subroutine(state: &mut State) -> Vec<IMP> {
+    let zero_label = state.get_label();
+    let negative_label = state.get_label();
+    vec![
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::FlowControl(FlowControlOperations::JumpIfZero(zero_label)),
+        IMP::FlowControl(FlowControlOperations::JumpIfNegative(negative_label)),
+        IMP::Stack(StackOperations::PushNumber(1)),
+        IMP::FlowControl(FlowControlOperations::Return),
+        IMP::FlowControl(FlowControlOperations::SetLabel(negative_label)),
+        IMP::Stack(StackOperations::PushNumber(-1)),
+        IMP::FlowControl(FlowControlOperations::Return),
+        // Zero is its own sign
+        IMP::FlowControl(FlowControlOperations::SetLabel(zero_label)),
+        IMP::FlowControl(FlowControlOperations::Return),
+    ]
+}
+
+/// [a b] -> [min(a, b)]
+fn min_subroutine(state: &mut State) -> Vec<IMP> {
+    let first_label = state.get_label();
+    vec![
+        IMP::Stack(StackOperations::CopyNth(1)),
+        IMP::Stack(StackOperations::CopyNth(1)),
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::FlowControl(FlowControlOperations::JumpIfNegative(first_label)),
+        IMP::Stack(StackOperations::Slide(1)),
+        IMP::FlowControl(FlowControlOperations::Return),
+        IMP::FlowControl(FlowControlOperations::SetLabel(first_label)),
+        IMP::Stack(StackOperations::Discard),
+        IMP::FlowControl(FlowControlOperations::Return),
+    ]
+}
+
+/// [a b] -> [max(a, b)]
+pub fn max_subroutine(state: &mut State) -> Vec<IMP> {
+    let second_label = state.get_label();
+    vec![
+        IMP::Stack(StackOperations::CopyNth(1)),
+        IMP::Stack(StackOperations::CopyNth(1)),
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::FlowControl(FlowControlOperations::JumpIfNegative(second_label)),
+        IMP::Stack(StackOperations::Discard),
+        IMP::FlowControl(FlowControlOperations::Return),
+        IMP::FlowControl(FlowControlOperations::SetLabel(second_label)),
+        IMP::Stack(StackOperations::Slide(1)),
+        IMP::FlowControl(FlowControlOperations::Return),
+    ]
+}
+
+/// [base exponent] -> [base ^ exponent], by squaring
+pub fn pow_subroutine(state: &mut State) -> Vec<IMP> {
+    let result = state.heap_allocation_map.reserve(VariableType::Int);
+    let negative_label = state.get_label();
+    let loop_label = state.get_label();
+    let even_label = state.get_label();
+    let end_label = state.get_label();
+    vec![
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::FlowControl(FlowControlOperations::JumpIfNegative(negative_label)),
+        IMP::Stack(StackOperations::PushNumber(result)),
+        IMP::Stack(StackOperations::PushNumber(1)),
+        IMP::Heap(HeapOperations::Store),
+        IMP::FlowControl(FlowControlOperations::SetLabel(loop_label)),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::FlowControl(FlowControlOperations::JumpIfZero(end_label)),
+        // Multiply the result by the base for odd exponents
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::Stack(StackOperations::PushNumber(2)),
+        IMP::Arithmetic(ArithmeticOperations::Modulo),
+        IMP::FlowControl(FlowControlOperations::JumpIfZero(even_label)),
+        IMP::Stack(StackOperations::PushNumber(result)),
+        IMP::Stack(StackOperations::PushNumber(result)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::CopyNth(3)),
+        IMP::Arithmetic(ArithmeticOperations::Multiply),
+        IMP::Heap(HeapOperations::Store),
+        IMP::FlowControl(FlowControlOperations::SetLabel(even_label)),
+        // Halve the exponent and square the base
+        IMP::Stack(StackOperations::PushNumber(2)),
+        IMP::Arithmetic(ArithmeticOperations::DivideInteger),
+        IMP::Stack(StackOperations::Swap),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::Arithmetic(ArithmeticOperations::Multiply),
+        IMP::Stack(StackOperations::Swap),
+        IMP::FlowControl(FlowControlOperations::Jump(loop_label)),
+        IMP::FlowControl(FlowControlOperations::SetLabel(end_label)),
+        IMP::Stack(StackOperations::Discard),
+        IMP::Stack(StackOperations::Discard),
+        IMP::Stack(StackOperations::PushNumber(result)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::FlowControl(FlowControlOperations::Return),
+        IMP::FlowControl(FlowControlOperations::SetLabel(negative_label)),
+        IMP::Stack(StackOperations::Discard),
+        IMP::Stack(StackOperations::Discard),
+        IMP::Stack(StackOperations::PushNumber(0)),
+        IMP::FlowControl(FlowControlOperations::Return),
+    ]
+}
+
+/// [n] -> [floor(sqrt(n))], Newton's method
+fn isqrt_subroutine(state: &mut State) -> Vec<IMP> {
+    let negative_label = state.get_label();
+    let loop_label = state.get_label();
+    let next_label = state.get_label();
+    vec![
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::FlowControl(FlowControlOperations::JumpIfNegative(negative_label)),
+        // x = n, y = (x + 1) / 2
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::Stack(StackOperations::PushNumber(1)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Stack(StackOperations::PushNumber(2)),
+        IMP::Arithmetic(ArithmeticOperations::DivideInteger),
+        // Stop once y is not smaller than x
+        IMP::FlowControl(FlowControlOperations::SetLabel(loop_label)),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::Stack(StackOperations::CopyNth(2)),
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::FlowControl(FlowControlOperations::JumpIfNegative(next_label)),
+        IMP::Stack(StackOperations::Discard),
+        IMP::Stack(StackOperations::Slide(1)),
+        IMP::FlowControl(FlowControlOperations::Return),
+        // x = y, y = (x + n / x) / 2
+        IMP::FlowControl(FlowControlOperations::SetLabel(next_label)),
+        IMP::Stack(StackOperations::Slide(1)),
+        IMP::Stack(StackOperations::CopyNth(1)),
+        IMP::Stack(StackOperations::CopyNth(1)),
+        IMP::Arithmetic(ArithmeticOperations::DivideInteger),
+        IMP::Stack(StackOperations::CopyNth(1)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Stack(StackOperations::PushNumber(2)),
+        IMP::Arithmetic(ArithmeticOperations::DivideInteger),
+        IMP::FlowControl(FlowControlOperations::Jump(loop_label)),
+        IMP::FlowControl(FlowControlOperations::SetLabel(negative_label)),
+        IMP::Stack(StackOperations::Discard),
+        IMP::Stack(StackOperations::PushNumber(-1)),
+        IMP::FlowControl(FlowControlOperations::Return),
+    ]
+}
+
+/// [a b] -> [gcd(|a|, |b|)], Euclid's algorithm
+fn gcd_subroutine(state: &mut State) -> Vec<IMP> {
+    let abs = state.subroutine("abs", abs_subroutine);
+    let loop_label = state.get_label();
+    let end_label = state.get_label();
+    vec![
+        IMP::FlowControl(FlowControlOperations::Call(abs)),
+        IMP::Stack(StackOperations::Swap),
+        IMP::FlowControl(FlowControlOperations::Call(abs)),
+        IMP::Stack(StackOperations::Swap),
+        IMP::FlowControl(FlowControlOperations::SetLabel(loop_label)),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::FlowControl(FlowControlOperations::JumpIfZero(end_label)),
+        IMP::Stack(StackOperations::Swap),
+        IMP::Stack(StackOperations::CopyNth(1)),
+        IMP::Arithmetic(ArithmeticOperations::Modulo),
+        IMP::FlowControl(FlowControlOperations::Jump(loop_label)),
+        IMP::FlowControl(FlowControlOperations::SetLabel(end_label)),
+        IMP::Stack(StackOperations::Discard),
+        IMP::FlowControl(FlowControlOperations::Return),
+    ]
+}
+
+/// [a b] -> [|a * b| / gcd(a, b)]
+fn lcm_subroutine(state: &mut State) -> Vec<IMP> {
+    let gcd = state.subroutine("gcd", gcd_subroutine);
+    let abs = state.subroutine("abs", abs_subroutine);
+    let zero_label = state.get_label();
+    vec![
+        IMP::Stack(StackOperations::CopyNth(1)),
+        IMP::Stack(StackOperations::CopyNth(1)),
+        IMP::FlowControl(FlowControlOperations::Call(gcd)),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::FlowControl(FlowControlOperations::JumpIfZero(zero_label)),
+        // Divide before multiplying to keep the intermediate value small
+        IMP::Stack(StackOperations::CopyNth(2)),
+        IMP::Stack(StackOperations::Swap),
+        IMP::Arithmetic(ArithmeticOperations::DivideInteger),
+        IMP::Arithmetic(ArithmeticOperations::Multiply),
+        IMP::Stack(StackOperations::Slide(1)),
+        IMP::FlowControl(FlowControlOperations::Call(abs)),
+        IMP::FlowControl(FlowControlOperations::Return),
+        IMP::FlowControl(FlowControlOperations::SetLabel(zero_label)),
+        IMP::Stack(StackOperations::Slide(2)),
+        IMP::FlowControl(FlowControlOperations::Return),
+    ]
+}
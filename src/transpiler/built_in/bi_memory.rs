@@ -0,0 +1,266 @@
+//! Runtime allocator for the heap cells after the static data. Every block starts with a
+//! header cell that holds the number of cells in the block, the pointer handed out is the
+//! address right after the header. Freed blocks are kept in a list; the first cell of a free
+//! block holds the header address of the next free block, -1 ends the list.
+
+use crate::{
+    ast::Expression,
+    diagnostic::Diagnostic,
+    lexer::Span,
+    specs::whitespace::{
+        ArithmeticOperations, FlowControlOperations, HeapOperations, StackOperations, IMP,
+    },
+    transpiler::{
+        emitter::{CodeEmitter, EvaluationResult},
+        state::State,
+        CodeOutput, DebugCodeBlock, VariableType,
+    },
+};
+
+use super::{bi_math, evaluate_arguments, Parameter};
+
+/// End of the free list
+const NONE: i32 = -1;
+
+/// Allocates a block of `n` heap cells and returns its address. The cells are not cleared.
+pub fn alloc(
+    state: &mut State,
+    args: &[Expression],
+    span: Span,
+    level: i8,
+) -> Result<EvaluationResult, Diagnostic> {
+    let (mut code, mut debug_code) =
+        evaluate_arguments("alloc", &[Parameter::Int], args, span, state, level)?;
+    let alloc = state.subroutine("alloc", alloc_subroutine);
+    CodeEmitter::emit_into(
+        &mut code,
+        &mut debug_code,
+        vec![IMP::FlowControl(FlowControlOperations::Call(alloc))],
+    );
+    Ok(EvaluationResult {
+        code,
+        debug_code,
+        type_: VariableType::Pointer,
+    })
+}
+
+/// Returns a block to the allocator. Freeing 0 does nothing.
+pub fn free(
+    state: &mut State,
+    args: &[Expression],
+    span: Span,
+    res: &mut CodeOutput,
+) -> Result<(), Diagnostic> {
+    let (code, debug_code) =
+        evaluate_arguments("free", &[Parameter::Pointer], args, span, state, 0)?;
+    res.add(code, debug_code.render());
+    let free = state.subroutine("free", free_subroutine);
+    let mut emitter = CodeEmitter {};
+    res.append(emitter.emit(vec![IMP::FlowControl(FlowControlOperations::Call(free))]));
+    Ok(())
+}
+
+/// Pushes the address of `pointer[index]`
+pub fn element_address(
+    pointer: &str,
+    index: &Expression,
+    span: Span,
+    state: &mut State,
+    level: i8,
+) -> Result<(String, DebugCodeBlock), Diagnostic> {
+    let variable = state.heap_allocation_map.lookup(pointer, span)?;
+    if variable.type_() != VariableType::Pointer {
+        return Err(Diagnostic::new(
+            format!("Only pointers can be indexed, found {}", variable.type_()),
+            span,
+        ));
+    }
+    let mut code = String::new();
+    let mut debug_code = DebugCodeBlock::new(None, level);
+    CodeEmitter::emit_into(
+        &mut code,
+        &mut debug_code,
+        vec![
+            IMP::Stack(StackOperations::PushNumber(variable.offset())),
+            IMP::Heap(HeapOperations::Retrieve),
+        ],
+    );
+    let mut emitter = CodeEmitter {};
+    let result = emitter.evaluate_expression(index, state, level)?;
+    if !result.type_.is_numeric() {
+        return Err(Diagnostic::new(
+            format!("Index must be i32, found {}", result.type_),
+            index.span,
+        ));
+    }
+    code.push_str(&result.code);
+    debug_code.append(result.debug_code);
+    CodeEmitter::emit_into(
+        &mut code,
+        &mut debug_code,
+        vec![IMP::Arithmetic(ArithmeticOperations::Add)],
+    );
+    Ok((code, debug_code))
+}
+
+/// [n] -> [address], first fit. The rest of a larger free block is split off when it has
+/// room for a header and at least one cell, otherwise the whole block is used.
+pub fn alloc_subroutine(state: &mut State) -> Vec<IMP> {
+    let free_list = state.global("free_list", NONE);
+    let heap_top = state.heap_top();
+    let max = state.subroutine("max", bi_math::max_subroutine);
+    let size = state.heap_allocation_map.reserve(VariableType::Int);
+    // Address of the cell that points to the current block
+    let link = state.heap_allocation_map.reserve(VariableType::Int);
+    let current = state.heap_allocation_map.reserve(VariableType::Int);
+    let rest = state.heap_allocation_map.reserve(VariableType::Int);
+    let loop_label = state.get_label();
+    let next_label = state.get_label();
+    let whole_label = state.get_label();
+    let found_label = state.get_label();
+    let grow_label = state.get_label();
+    vec![
+        // A free block has to hold the link to the next one
+        IMP::Stack(StackOperations::PushNumber(1)),
+        IMP::FlowControl(FlowControlOperations::Call(max)),
+        IMP::Stack(StackOperations::PushNumber(size)),
+        IMP::Stack(StackOperations::Swap),
+        IMP::Heap(HeapOperations::Store),
+        IMP::Stack(StackOperations::PushNumber(link)),
+        IMP::Stack(StackOperations::PushNumber(free_list)),
+        IMP::Heap(HeapOperations::Store),
+        IMP::FlowControl(FlowControlOperations::SetLabel(loop_label)),
+        IMP::Stack(StackOperations::PushNumber(current)),
+        IMP::Stack(StackOperations::PushNumber(link)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Heap(HeapOperations::Store),
+        IMP::Stack(StackOperations::PushNumber(current)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::FlowControl(FlowControlOperations::JumpIfNegative(grow_label)),
+        // Spare cells of the current block
+        IMP::Stack(StackOperations::PushNumber(current)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(size)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::FlowControl(FlowControlOperations::JumpIfNegative(next_label)),
+        IMP::Stack(StackOperations::PushNumber(2)),
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::FlowControl(FlowControlOperations::JumpIfNegative(whole_label)),
+        // Split: the rest starts after the allocated cells and takes the place in the list
+        IMP::Stack(StackOperations::PushNumber(rest)),
+        IMP::Stack(StackOperations::PushNumber(current)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(1)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Stack(StackOperations::PushNumber(size)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Heap(HeapOperations::Store),
+        IMP::Stack(StackOperations::PushNumber(rest)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(current)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(size)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::Stack(StackOperations::PushNumber(1)),
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::Heap(HeapOperations::Store),
+        IMP::Stack(StackOperations::PushNumber(rest)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(1)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Stack(StackOperations::PushNumber(current)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(1)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Heap(HeapOperations::Store),
+        IMP::Stack(StackOperations::PushNumber(link)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(rest)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Heap(HeapOperations::Store),
+        IMP::Stack(StackOperations::PushNumber(current)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(size)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Heap(HeapOperations::Store),
+        IMP::FlowControl(FlowControlOperations::Jump(found_label)),
+        // Unlink the whole block
+        IMP::FlowControl(FlowControlOperations::SetLabel(whole_label)),
+        IMP::Stack(StackOperations::PushNumber(link)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(current)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(1)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Heap(HeapOperations::Store),
+        IMP::FlowControl(FlowControlOperations::SetLabel(found_label)),
+        IMP::Stack(StackOperations::PushNumber(current)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(1)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::FlowControl(FlowControlOperations::Return),
+        // Too small, move on to the next free block
+        IMP::FlowControl(FlowControlOperations::SetLabel(next_label)),
+        IMP::Stack(StackOperations::Discard),
+        IMP::Stack(StackOperations::PushNumber(link)),
+        IMP::Stack(StackOperations::PushNumber(current)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(1)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Heap(HeapOperations::Store),
+        IMP::FlowControl(FlowControlOperations::Jump(loop_label)),
+        // No free block fits, take new cells from the top of the heap
+        IMP::FlowControl(FlowControlOperations::SetLabel(grow_label)),
+        IMP::Stack(StackOperations::PushNumber(current)),
+        IMP::Stack(StackOperations::PushNumber(heap_top)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Heap(HeapOperations::Store),
+        IMP::Stack(StackOperations::PushNumber(current)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(size)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Heap(HeapOperations::Store),
+        IMP::Stack(StackOperations::PushNumber(heap_top)),
+        IMP::Stack(StackOperations::PushNumber(current)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(1)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Stack(StackOperations::PushNumber(size)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Heap(HeapOperations::Store),
+        IMP::FlowControl(FlowControlOperations::Jump(found_label)),
+    ]
+}
+
+/// [address] -> [], pushes the block to the front of the free list
+pub fn free_subroutine(state: &mut State) -> Vec<IMP> {
+    let free_list = state.global("free_list", NONE);
+    let null_label = state.get_label();
+    vec![
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::FlowControl(FlowControlOperations::JumpIfZero(null_label)),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::Stack(StackOperations::PushNumber(free_list)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Heap(HeapOperations::Store),
+        IMP::Stack(StackOperations::PushNumber(1)),
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::Stack(StackOperations::PushNumber(free_list)),
+        IMP::Stack(StackOperations::Swap),
+        IMP::Heap(HeapOperations::Store),
+        IMP::FlowControl(FlowControlOperations::Return),
+        IMP::FlowControl(FlowControlOperations::SetLabel(null_label)),
+        IMP::Stack(StackOperations::Discard),
+        IMP::FlowControl(FlowControlOperations::Return),
+    ]
+}
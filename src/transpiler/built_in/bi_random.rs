@@ -0,0 +1,136 @@
+//! Pseudo-random numbers from the MINSTD linear congruential generator,
+//! `state = state * 48271 mod (2^31 - 1)`. The multiplication uses Schrage's method, so no
+//! intermediate value needs more than 31 bits and every interpreter produces the same sequence.
+
+use crate::{
+    ast::Expression,
+    diagnostic::Diagnostic,
+    lexer::Span,
+    specs::whitespace::{
+        ArithmeticOperations, FlowControlOperations, HeapOperations, StackOperations, IMP,
+    },
+    transpiler::{
+        emitter::{CodeEmitter, EvaluationResult},
+        state::State,
+        CodeOutput, VariableType,
+    },
+};
+
+use super::{bi_math, evaluate_arguments, Parameter};
+
+const MODULUS: i32 = i32::MAX;
+const MULTIPLIER: i32 = 48271;
+/// Schrage's decomposition of the modulus, `MODULUS = MULTIPLIER * QUOTIENT + REMAINDER`
+const QUOTIENT: i32 = MODULUS / MULTIPLIER;
+const REMAINDER: i32 = MODULUS % MULTIPLIER;
+/// State of a generator that was never seeded
+const DEFAULT_SEED: i32 = 1;
+
+/// Restarts the sequence. Seeds with the same absolute value give the same sequence.
+pub fn seed(
+    state: &mut State,
+    args: &[Expression],
+    span: Span,
+    res: &mut CodeOutput,
+) -> Result<(), Diagnostic> {
+    let (code, debug_code) = evaluate_arguments("seed", &[Parameter::Int], args, span, state, 0)?;
+    res.add(code, debug_code.render());
+    let seed = state.subroutine("seed", seed_subroutine);
+    let mut emitter = CodeEmitter {};
+    res.append(emitter.emit(vec![IMP::FlowControl(FlowControlOperations::Call(seed))]));
+    Ok(())
+}
+
+/// Next number between 0 and `max - 1`, 0 when `max` is not positive
+pub fn rand(
+    state: &mut State,
+    args: &[Expression],
+    span: Span,
+    level: i8,
+) -> Result<EvaluationResult, Diagnostic> {
+    let (mut code, mut debug_code) =
+        evaluate_arguments("rand", &[Parameter::Int], args, span, state, level)?;
+    let rand = state.subroutine("rand", rand_subroutine);
+    CodeEmitter::emit_into(
+        &mut code,
+        &mut debug_code,
+        vec![IMP::FlowControl(FlowControlOperations::Call(rand))],
+    );
+    Ok(EvaluationResult {
+        code,
+        debug_code,
+        type_: VariableType::Int,
+    })
+}
+
+/// [seed] -> [], maps the seed into the valid range 1..MODULUS
+fn seed_subroutine(state: &mut State) -> Vec<IMP> {
+    let generator = state.global("rand_state", DEFAULT_SEED);
+    let abs = state.subroutine("abs", bi_math::abs_subroutine);
+    vec![
+        IMP::FlowControl(FlowControlOperations::Call(abs)),
+        IMP::Stack(StackOperations::PushNumber(MODULUS - 1)),
+        IMP::Arithmetic(ArithmeticOperations::Modulo),
+        IMP::Stack(StackOperations::PushNumber(1)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Stack(StackOperations::PushNumber(generator)),
+        IMP::Stack(StackOperations::Swap),
+        IMP::Heap(HeapOperations::Store),
+        IMP::FlowControl(FlowControlOperations::Return),
+    ]
+}
+
+/// [] -> [next state]
+fn next_subroutine(state: &mut State) -> Vec<IMP> {
+    let generator = state.global("rand_state", DEFAULT_SEED);
+    let store_label = state.get_label();
+    let wrap_label = state.get_label();
+    vec![
+        // MULTIPLIER * (x % QUOTIENT) - REMAINDER * (x / QUOTIENT)
+        IMP::Stack(StackOperations::PushNumber(generator)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::Stack(StackOperations::PushNumber(QUOTIENT)),
+        IMP::Arithmetic(ArithmeticOperations::Modulo),
+        IMP::Stack(StackOperations::PushNumber(MULTIPLIER)),
+        IMP::Arithmetic(ArithmeticOperations::Multiply),
+        IMP::Stack(StackOperations::Swap),
+        IMP::Stack(StackOperations::PushNumber(QUOTIENT)),
+        IMP::Arithmetic(ArithmeticOperations::DivideInteger),
+        IMP::Stack(StackOperations::PushNumber(REMAINDER)),
+        IMP::Arithmetic(ArithmeticOperations::Multiply),
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::FlowControl(FlowControlOperations::JumpIfNegative(wrap_label)),
+        IMP::FlowControl(FlowControlOperations::Jump(store_label)),
+        IMP::FlowControl(FlowControlOperations::SetLabel(wrap_label)),
+        IMP::Stack(StackOperations::PushNumber(MODULUS)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::FlowControl(FlowControlOperations::SetLabel(store_label)),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::Stack(StackOperations::PushNumber(generator)),
+        IMP::Stack(StackOperations::Swap),
+        IMP::Heap(HeapOperations::Store),
+        IMP::FlowControl(FlowControlOperations::Return),
+    ]
+}
+
+/// [max] -> [number]
+fn rand_subroutine(state: &mut State) -> Vec<IMP> {
+    let next = state.subroutine("rand_next", next_subroutine);
+    let empty_label = state.get_label();
+    vec![
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::Stack(StackOperations::PushNumber(1)),
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::FlowControl(FlowControlOperations::JumpIfNegative(empty_label)),
+        IMP::FlowControl(FlowControlOperations::Call(next)),
+        IMP::Stack(StackOperations::Swap),
+        IMP::Arithmetic(ArithmeticOperations::Modulo),
+        IMP::FlowControl(FlowControlOperations::Return),
+        IMP::FlowControl(FlowControlOperations::SetLabel(empty_label)),
+        IMP::Stack(StackOperations::Discard),
+        IMP::Stack(StackOperations::PushNumber(0)),
+        IMP::FlowControl(FlowControlOperations::Return),
+    ]
+}
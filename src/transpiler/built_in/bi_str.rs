@@ -0,0 +1,408 @@
+//! Growable strings. A `str` variable holds the address of a block from the allocator:
+//!
+//! ```text
+//! [length] [capacity] [char 0] ... [char length - 1] [0] ...
+//! ```
+//!
+//! Values of the type are the address of the first character, like fixed strings, so every
+//! built-in that reads strings takes them as well. When an update needs more room, the
+//! content moves to a new block with at least twice the capacity and the old one is freed.
+
+use crate::specs::whitespace::{
+    ArithmeticOperations, FlowControlOperations, HeapOperations, StackOperations, IMP,
+};
+use crate::transpiler::{state::State, VariableType};
+
+use super::{bi_io, bi_math, bi_memory, bi_string};
+
+/// Offsets of the fields from the start of the block
+const LENGTH: i32 = 0;
+const CAPACITY: i32 = 1;
+const CHARS: i32 = 2;
+/// Capacity of a new string, not counting the terminator
+const INITIAL_CAPACITY: i32 = 15;
+
+/// Sets up the variable at `addr` when the declaration is reached. The variable starts
+/// without a block, so a declaration that runs again, e.g. in a loop, keeps the content
+/// like other variables do instead of allocating another block.
+pub fn declare(state: &mut State, addr: i32) -> Vec<IMP> {
+    state.initialize(addr, 0);
+    let init = state.subroutine("str_init", init_subroutine);
+    vec![
+        IMP::Stack(StackOperations::PushNumber(addr)),
+        IMP::FlowControl(FlowControlOperations::Call(init)),
+    ]
+}
+
+/// [variable] -> [address of the first character]
+pub fn chars() -> Vec<IMP> {
+    vec![
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(CHARS)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+    ]
+}
+
+/// [chars] -> [length], reads the stored length instead of looking for the terminator
+pub fn len() -> Vec<IMP> {
+    vec![
+        IMP::Stack(StackOperations::PushNumber(LENGTH - CHARS)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Heap(HeapOperations::Retrieve),
+    ]
+}
+
+/// Empties the variable at `addr`
+pub fn clear(addr: i32) -> Vec<IMP> {
+    let mut code = vec![IMP::Stack(StackOperations::PushNumber(addr))];
+    code.extend(clear_variable());
+    code
+}
+
+/// [variable] -> []
+fn clear_variable() -> [IMP; 10] {
+    [
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(LENGTH)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::Stack(StackOperations::PushNumber(0)),
+        IMP::Heap(HeapOperations::Store),
+        IMP::Stack(StackOperations::PushNumber(CHARS - LENGTH)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Stack(StackOperations::PushNumber(0)),
+        IMP::Heap(HeapOperations::Store),
+    ]
+}
+
+/// [variable source] -> [], replaces the content with a copy of the source string
+pub fn assign(state: &mut State) -> Vec<IMP> {
+    let assign = state.subroutine("str_assign", assign_subroutine);
+    vec![IMP::FlowControl(FlowControlOperations::Call(assign))]
+}
+
+/// [variable source] -> [], adds a copy of the source string to the end
+pub fn append(state: &mut State) -> Vec<IMP> {
+    let append = state.subroutine("str_append", append_subroutine);
+    vec![IMP::FlowControl(FlowControlOperations::Call(append))]
+}
+
+/// [variable char] -> [], adds a single character to the end
+pub fn push_char(state: &mut State) -> Vec<IMP> {
+    let push_char = state.subroutine("str_push_char", push_char_subroutine);
+    vec![IMP::FlowControl(FlowControlOperations::Call(push_char))]
+}
+
+/// [variable] -> [0, or -1 at the end of input], reads a line without the newline. Lines
+/// are never truncated.
+pub fn read_line(state: &mut State) -> Vec<IMP> {
+    let read_line = state.subroutine("str_read_line", read_line_subroutine);
+    vec![IMP::FlowControl(FlowControlOperations::Call(read_line))]
+}
+
+/// [variable] -> [], gives the variable an empty block unless it already has one
+fn init_subroutine(state: &mut State) -> Vec<IMP> {
+    let alloc = state.subroutine("alloc", bi_memory::alloc_subroutine);
+    let new_label = state.get_label();
+    vec![
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::FlowControl(FlowControlOperations::JumpIfZero(new_label)),
+        IMP::Stack(StackOperations::Discard),
+        IMP::FlowControl(FlowControlOperations::Return),
+        IMP::FlowControl(FlowControlOperations::SetLabel(new_label)),
+        IMP::Stack(StackOperations::PushNumber(CHARS + INITIAL_CAPACITY + 1)),
+        IMP::FlowControl(FlowControlOperations::Call(alloc)),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::Stack(StackOperations::PushNumber(LENGTH)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Stack(StackOperations::PushNumber(0)),
+        IMP::Heap(HeapOperations::Store),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::Stack(StackOperations::PushNumber(CAPACITY)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Stack(StackOperations::PushNumber(INITIAL_CAPACITY)),
+        IMP::Heap(HeapOperations::Store),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::Stack(StackOperations::PushNumber(CHARS)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Stack(StackOperations::PushNumber(0)),
+        IMP::Heap(HeapOperations::Store),
+        IMP::Heap(HeapOperations::Store),
+        IMP::FlowControl(FlowControlOperations::Return),
+    ]
+}
+
+/// [variable length] -> [], makes room for `length` characters
+fn reserve_subroutine(state: &mut State) -> Vec<IMP> {
+    let alloc = state.subroutine("alloc", bi_memory::alloc_subroutine);
+    let free = state.subroutine("free", bi_memory::free_subroutine);
+    let max = state.subroutine("max", bi_math::max_subroutine);
+    let strncpy = state.subroutine("strncpy", bi_string::strncpy);
+    let variable = state.heap_allocation_map.reserve(VariableType::Int);
+    let needed = state.heap_allocation_map.reserve(VariableType::Int);
+    let old = state.heap_allocation_map.reserve(VariableType::Int);
+    let new = state.heap_allocation_map.reserve(VariableType::Int);
+    let grow_label = state.get_label();
+    vec![
+        IMP::Stack(StackOperations::PushNumber(needed)),
+        IMP::Stack(StackOperations::Swap),
+        IMP::Heap(HeapOperations::Store),
+        IMP::Stack(StackOperations::PushNumber(variable)),
+        IMP::Stack(StackOperations::Swap),
+        IMP::Heap(HeapOperations::Store),
+        IMP::Stack(StackOperations::PushNumber(old)),
+        IMP::Stack(StackOperations::PushNumber(variable)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Heap(HeapOperations::Store),
+        // Nothing to do while the capacity is enough
+        IMP::Stack(StackOperations::PushNumber(old)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(CAPACITY)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(needed)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::FlowControl(FlowControlOperations::JumpIfNegative(grow_label)),
+        IMP::FlowControl(FlowControlOperations::Return),
+        // New capacity: max(length, 2 * capacity)
+        IMP::FlowControl(FlowControlOperations::SetLabel(grow_label)),
+        IMP::Stack(StackOperations::PushNumber(needed)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(old)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(CAPACITY)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(2)),
+        IMP::Arithmetic(ArithmeticOperations::Multiply),
+        IMP::FlowControl(FlowControlOperations::Call(max)),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::Stack(StackOperations::PushNumber(CHARS + 1)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::FlowControl(FlowControlOperations::Call(alloc)),
+        IMP::Stack(StackOperations::PushNumber(new)),
+        IMP::Stack(StackOperations::Swap),
+        IMP::Heap(HeapOperations::Store),
+        IMP::Stack(StackOperations::PushNumber(new)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(CAPACITY)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Stack(StackOperations::Swap),
+        IMP::Heap(HeapOperations::Store),
+        // Move the length and the characters
+        IMP::Stack(StackOperations::PushNumber(new)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(LENGTH)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Stack(StackOperations::PushNumber(old)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(LENGTH)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Heap(HeapOperations::Store),
+        IMP::Stack(StackOperations::PushNumber(new)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(CHARS)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Stack(StackOperations::PushNumber(old)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(CHARS)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Stack(StackOperations::PushNumber(old)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(LENGTH)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::FlowControl(FlowControlOperations::Call(strncpy)),
+        IMP::Stack(StackOperations::PushNumber(old)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::FlowControl(FlowControlOperations::Call(free)),
+        IMP::Stack(StackOperations::PushNumber(variable)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(new)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Heap(HeapOperations::Store),
+        IMP::FlowControl(FlowControlOperations::Return),
+    ]
+}
+
+/// [variable source] -> []. The source may be the variable itself: a freed block keeps its
+/// characters, and the new block is allocated before the old one is freed.
+fn append_subroutine(state: &mut State) -> Vec<IMP> {
+    let reserve = state.subroutine("str_reserve", reserve_subroutine);
+    let strlen = state.subroutine("strlen", bi_string::strlen);
+    let strncpy = state.subroutine("strncpy", bi_string::strncpy);
+    let variable = state.heap_allocation_map.reserve(VariableType::Int);
+    let source = state.heap_allocation_map.reserve(VariableType::Int);
+    let count = state.heap_allocation_map.reserve(VariableType::Int);
+    let mut code = vec![
+        IMP::Stack(StackOperations::PushNumber(source)),
+        IMP::Stack(StackOperations::Swap),
+        IMP::Heap(HeapOperations::Store),
+        IMP::Stack(StackOperations::PushNumber(variable)),
+        IMP::Stack(StackOperations::Swap),
+        IMP::Heap(HeapOperations::Store),
+        IMP::Stack(StackOperations::PushNumber(count)),
+        IMP::Stack(StackOperations::PushNumber(source)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::FlowControl(FlowControlOperations::Call(strlen)),
+        IMP::Heap(HeapOperations::Store),
+        // Room for length + count characters
+        IMP::Stack(StackOperations::PushNumber(variable)),
+        IMP::Heap(HeapOperations::Retrieve),
+    ];
+    code.extend(end_of(variable));
+    code.extend([
+        IMP::Stack(StackOperations::Slide(1)),
+        IMP::Stack(StackOperations::PushNumber(count)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::FlowControl(FlowControlOperations::Call(reserve)),
+    ]);
+    // Copy to the end and update the length
+    code.extend(end_of(variable));
+    code.extend([
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Stack(StackOperations::PushNumber(CHARS - LENGTH)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Stack(StackOperations::PushNumber(source)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(count)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::FlowControl(FlowControlOperations::Call(strncpy)),
+    ]);
+    code.extend(end_of(variable));
+    code.extend([
+        IMP::Stack(StackOperations::PushNumber(count)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Heap(HeapOperations::Store),
+        IMP::FlowControl(FlowControlOperations::Return),
+    ]);
+    code
+}
+
+/// [] -> [length address, length] of the string whose variable address is stored in `cell`
+fn end_of(cell: i32) -> [IMP; 7] {
+    [
+        IMP::Stack(StackOperations::PushNumber(cell)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(LENGTH)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::Heap(HeapOperations::Retrieve),
+    ]
+}
+
+/// [variable source] -> []
+fn assign_subroutine(state: &mut State) -> Vec<IMP> {
+    let append = state.subroutine("str_append", append_subroutine);
+    // Only the length is reset, so the source may still be the variable itself
+    vec![
+        IMP::Stack(StackOperations::CopyNth(1)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(LENGTH)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Stack(StackOperations::PushNumber(0)),
+        IMP::Heap(HeapOperations::Store),
+        IMP::FlowControl(FlowControlOperations::Call(append)),
+        IMP::FlowControl(FlowControlOperations::Return),
+    ]
+}
+
+/// [variable char] -> []
+fn push_char_subroutine(state: &mut State) -> Vec<IMP> {
+    let reserve = state.subroutine("str_reserve", reserve_subroutine);
+    let variable = state.heap_allocation_map.reserve(VariableType::Int);
+    let char = state.heap_allocation_map.reserve(VariableType::Int);
+    let mut code = vec![
+        IMP::Stack(StackOperations::PushNumber(char)),
+        IMP::Stack(StackOperations::Swap),
+        IMP::Heap(HeapOperations::Store),
+        IMP::Stack(StackOperations::PushNumber(variable)),
+        IMP::Stack(StackOperations::Swap),
+        IMP::Heap(HeapOperations::Store),
+        IMP::Stack(StackOperations::PushNumber(variable)),
+        IMP::Heap(HeapOperations::Retrieve),
+    ];
+    code.extend(end_of(variable));
+    code.extend([
+        IMP::Stack(StackOperations::Slide(1)),
+        IMP::Stack(StackOperations::PushNumber(1)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::FlowControl(FlowControlOperations::Call(reserve)),
+    ]);
+    // Store the character and a new terminator, then update the length
+    code.extend(end_of(variable));
+    code.extend([
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Stack(StackOperations::PushNumber(CHARS - LENGTH)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::Stack(StackOperations::PushNumber(char)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Heap(HeapOperations::Store),
+        IMP::Stack(StackOperations::PushNumber(1)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Stack(StackOperations::PushNumber(0)),
+        IMP::Heap(HeapOperations::Store),
+    ]);
+    code.extend(end_of(variable));
+    code.extend([
+        IMP::Stack(StackOperations::PushNumber(1)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Heap(HeapOperations::Store),
+        IMP::FlowControl(FlowControlOperations::Return),
+    ]);
+    code
+}
+
+/// [variable] -> [status]
+fn read_line_subroutine(state: &mut State) -> Vec<IMP> {
+    let peekc = state.subroutine("peekc", bi_io::peekc);
+    let getc = state.subroutine("getc", bi_io::getc);
+    let push_char = state.subroutine("str_push_char", push_char_subroutine);
+    let variable = state.heap_allocation_map.reserve(VariableType::Int);
+    let loop_label = state.get_label();
+    let end_label = state.get_label();
+    let eof_label = state.get_label();
+    let mut code = vec![
+        IMP::Stack(StackOperations::PushNumber(variable)),
+        IMP::Stack(StackOperations::Swap),
+        IMP::Heap(HeapOperations::Store),
+        IMP::Stack(StackOperations::PushNumber(variable)),
+        IMP::Heap(HeapOperations::Retrieve),
+    ];
+    // The variable is emptied even at the end of input, like fixed strings are
+    code.extend(clear_variable());
+    code.extend([
+        IMP::FlowControl(FlowControlOperations::Call(peekc)),
+        IMP::FlowControl(FlowControlOperations::JumpIfNegative(eof_label)),
+        IMP::FlowControl(FlowControlOperations::SetLabel(loop_label)),
+        IMP::FlowControl(FlowControlOperations::Call(getc)),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::FlowControl(FlowControlOperations::JumpIfNegative(end_label)),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::Stack(StackOperations::PushNumber('\n' as i32)),
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::FlowControl(FlowControlOperations::JumpIfZero(end_label)),
+        IMP::Stack(StackOperations::PushNumber(variable)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::Swap),
+        IMP::FlowControl(FlowControlOperations::Call(push_char)),
+        IMP::FlowControl(FlowControlOperations::Jump(loop_label)),
+        // Newline or end of input
+        IMP::FlowControl(FlowControlOperations::SetLabel(end_label)),
+        IMP::Stack(StackOperations::Discard),
+        IMP::Stack(StackOperations::PushNumber(0)),
+        IMP::FlowControl(FlowControlOperations::Return),
+        IMP::FlowControl(FlowControlOperations::SetLabel(eof_label)),
+        IMP::Stack(StackOperations::PushNumber(-1)),
+        IMP::FlowControl(FlowControlOperations::Return),
+    ]);
+    code
+}
@@ -1,78 +1,939 @@
 use crate::{
-    ast::Expression,
+    ast::{Expression, ExpressionKind, InterpolationPart},
+    diagnostic::Diagnostic,
+    lexer::Span,
     specs::whitespace::{
         ArithmeticOperations, FlowControlOperations, HeapOperations, StackOperations, IMP,
     },
-    transpiler::{emitter::CodeEmitter, state::State, CodeOutput, HeapVariableSize, VariableType},
+    transpiler::{
+        emitter::{CodeEmitter, EvaluationResult},
+        state::State,
+        CodeOutput, DebugCodeBlock, HeapVariableSize, VariableType,
+    },
+};
+
+use super::{
+    bi_convert, bi_str, check_arity, evaluate_arguments, str_variable, string_target, Parameter,
 };
 
-pub fn concat(state: &mut State, args: &Vec<Expression>, res: &mut CodeOutput) {
+pub fn concat(
+    state: &mut State,
+    args: &[Expression],
+    span: Span,
+    res: &mut CodeOutput,
+) -> Result<(), Diagnostic> {
     res.add("".to_string(), "# concat".to_string());
-    let target = args.get(0).unwrap();
-    let source = args.get(1).unwrap();
-    if let (Expression::Variable(target_name), Expression::Variable(source_name)) = (target, source)
-    {
-        let target_variable = state.heap_allocation_map.get(&target_name).unwrap();
-        let source_variable = state.heap_allocation_map.get(&source_name).unwrap();
-        let target_type = target_variable.type_();
-        let source_type = source_variable.type_();
-        let target_addr = target_variable.offset();
-        let source_addr = source_variable.offset();
-
-        match (target_type, source_type) {
-            (VariableType::String(_), VariableType::String(_)) => {
-                let seek_start_label = state.get_label();
-                let seek_end_label = state.get_label();
-                let copy_start_label = state.get_label();
-                let copy_end_label = state.get_label();
-
-                let mut emitter = CodeEmitter {};
-
-                res.append(emitter.emit(vec![
-                    // Set pointer to the end of the target string
-                    IMP::Stack(StackOperations::PushNumber(target_addr)),
-                    IMP::FlowControl(FlowControlOperations::SetLabel(seek_start_label)),
-                    IMP::Stack(StackOperations::Duplicate),
-                    IMP::Heap(HeapOperations::Retrieve),
-                    IMP::FlowControl(FlowControlOperations::JumpIfZero(seek_end_label)),
-                    // Advance pointer
-                    IMP::Stack(StackOperations::PushNumber(HeapVariableSize::Char.size())),
-                    IMP::Arithmetic(ArithmeticOperations::Add),
-                    IMP::FlowControl(FlowControlOperations::Jump(seek_start_label)),
-                    IMP::FlowControl(FlowControlOperations::SetLabel(seek_end_label)),
-                    // Copy source string
-                    IMP::Stack(StackOperations::PushNumber(source_addr)),
-                    IMP::FlowControl(FlowControlOperations::SetLabel(copy_start_label)),
-                    IMP::Stack(StackOperations::Duplicate),
-                    IMP::Heap(HeapOperations::Retrieve),
-                    IMP::FlowControl(FlowControlOperations::JumpIfZero(copy_end_label)),
-                    // Copy char
+    let (target, source) = match args {
+        [target, source] => (target, source),
+        _ => return Err(Diagnostic::new("concat expects 2 arguments", span)),
+    };
+    // Growable strings take any string, they make room for it
+    if let Some(target_addr) = str_variable(target, state) {
+        let mut emitter = CodeEmitter {};
+        let EvaluationResult {
+            code,
+            debug_code,
+            type_,
+        } = emitter.evaluate_expression(source, state, 0)?;
+        if !type_.is_string() {
+            return Err(Diagnostic::new(
+                format!("concat expects String as argument, found {}", type_),
+                source.span,
+            ));
+        }
+        res.append(emitter.emit(vec![IMP::Stack(StackOperations::PushNumber(target_addr))]));
+        res.add(code, debug_code.render());
+        res.append(emitter.emit(bi_str::append(state)));
+        return Ok(());
+    }
+    let ExpressionKind::Variable(target_name) = &target.kind else {
+        return Err(Diagnostic::new(
+            "concat expects a string variable as target",
+            span,
+        ));
+    };
+    let target_variable = state.heap_allocation_map.lookup(target_name, target.span)?;
+    let target_type = target_variable.type_();
+    let target_addr = target_variable.offset();
+    let VariableType::String(target_length) = target_type else {
+        return Err(Diagnostic::new(
+            "Only string values are supported for now",
+            span,
+        ));
+    };
+    // Literals are written character by character, variables are copied in a loop
+    let (source_name, source_type, copy) = match &source.kind {
+        ExpressionKind::Literal(value) => {
+            let mut copy = vec![];
+            for ch in value.chars() {
+                copy.extend([
                     IMP::Stack(StackOperations::Duplicate),
-                    IMP::Heap(HeapOperations::Retrieve),
-                    IMP::Stack(StackOperations::CopyNth(2)),
-                    IMP::Stack(StackOperations::Swap),
+                    IMP::Stack(StackOperations::PushNumber(ch as i32)),
                     IMP::Heap(HeapOperations::Store),
-                    // Advance target pointer
-                    IMP::Stack(StackOperations::PushNumber(HeapVariableSize::Char.size())),
-                    IMP::Arithmetic(ArithmeticOperations::Add),
-                    IMP::Stack(StackOperations::Swap),
-                    // Advance source pointer
                     IMP::Stack(StackOperations::PushNumber(HeapVariableSize::Char.size())),
                     IMP::Arithmetic(ArithmeticOperations::Add),
-                    IMP::Stack(StackOperations::Swap),
-                    IMP::FlowControl(FlowControlOperations::Jump(copy_start_label)),
-                    IMP::FlowControl(FlowControlOperations::SetLabel(copy_end_label)),
-                    // Add null terminator
-                    IMP::Stack(StackOperations::Swap),
-                    IMP::Stack(StackOperations::PushNumber(0)),
-                    IMP::Heap(HeapOperations::Store),
-                    // Clean up
-                    IMP::Stack(StackOperations::Discard),
-                ]));
+                ]);
+            }
+            copy.extend([
+                IMP::Stack(StackOperations::PushNumber(0)),
+                IMP::Heap(HeapOperations::Store),
+            ]);
+            let literal_type = VariableType::String(value.chars().count() + 1);
+            (format!("{:?}", value), literal_type, copy)
+        }
+        ExpressionKind::Variable(source_name) => {
+            let source_variable = state.heap_allocation_map.lookup(source_name, source.span)?;
+            let source_type = source_variable.type_();
+            if !source_type.is_string() {
+                return Err(Diagnostic::new(
+                    "Only string values are supported for now",
+                    span,
+                ));
             }
-            _ => {
-                panic!("Only string values are supported for now");
+            // Address of the first character of the source
+            let mut copy = vec![IMP::Stack(StackOperations::PushNumber(
+                source_variable.offset(),
+            ))];
+            if source_type == VariableType::Str {
+                copy.extend(bi_str::chars());
             }
+            let copy_start_label = state.get_label();
+            let copy_end_label = state.get_label();
+            copy.extend([
+                IMP::FlowControl(FlowControlOperations::SetLabel(copy_start_label)),
+                IMP::Stack(StackOperations::Duplicate),
+                IMP::Heap(HeapOperations::Retrieve),
+                IMP::FlowControl(FlowControlOperations::JumpIfZero(copy_end_label)),
+                // Copy char
+                IMP::Stack(StackOperations::Duplicate),
+                IMP::Heap(HeapOperations::Retrieve),
+                IMP::Stack(StackOperations::CopyNth(2)),
+                IMP::Stack(StackOperations::Swap),
+                IMP::Heap(HeapOperations::Store),
+                // Advance target pointer
+                IMP::Stack(StackOperations::PushNumber(HeapVariableSize::Char.size())),
+                IMP::Arithmetic(ArithmeticOperations::Add),
+                IMP::Stack(StackOperations::Swap),
+                // Advance source pointer
+                IMP::Stack(StackOperations::PushNumber(HeapVariableSize::Char.size())),
+                IMP::Arithmetic(ArithmeticOperations::Add),
+                IMP::Stack(StackOperations::Swap),
+                IMP::FlowControl(FlowControlOperations::Jump(copy_start_label)),
+                IMP::FlowControl(FlowControlOperations::SetLabel(copy_end_label)),
+                // Add null terminator
+                IMP::Stack(StackOperations::Swap),
+                IMP::Stack(StackOperations::PushNumber(0)),
+                IMP::Heap(HeapOperations::Store),
+                // Clean up
+                IMP::Stack(StackOperations::Discard),
+            ]);
+            (source_name.clone(), source_type, copy)
+        }
+        _ => {
+            return Err(Diagnostic::new(
+                "concat expects a string variable or literal as source",
+                span,
+            ))
+        }
+    };
+    // The characters are appended without a bound. Warn when the target cannot take the
+    // longest string of the source next to at least one character of its own, the size of a
+    // str source is not known.
+    if let VariableType::String(source_length) = source_type {
+        if source_length + 1 > target_length {
+            state.warnings.push(Diagnostic::new(
+                format!(
+                    "{} of type {} may not have room for {} of type {}",
+                    target_name, target_type, source_name, source_type
+                ),
+                span,
+            ));
         }
     }
+    let seek_start_label = state.get_label();
+    let seek_end_label = state.get_label();
+
+    let mut emitter = CodeEmitter {};
+
+    let mut instructions = vec![
+        // Set pointer to the end of the target string
+        IMP::Stack(StackOperations::PushNumber(target_addr)),
+        IMP::FlowControl(FlowControlOperations::SetLabel(seek_start_label)),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::FlowControl(FlowControlOperations::JumpIfZero(seek_end_label)),
+        // Advance pointer
+        IMP::Stack(StackOperations::PushNumber(HeapVariableSize::Char.size())),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::FlowControl(FlowControlOperations::Jump(seek_start_label)),
+        IMP::FlowControl(FlowControlOperations::SetLabel(seek_end_label)),
+    ];
+    // Copy source string
+    instructions.extend(copy);
+    res.append(emitter.emit(instructions));
+    Ok(())
+}
+
+/// Copies a null-terminated string, writing at most `limit - 1` characters followed by a
+/// terminator. Expects the target and the source addresses on the stack, consumes both.
+pub fn copy(state: &mut State, limit: usize) -> Vec<IMP> {
+    let strncpy = state.subroutine("strncpy", strncpy);
+    vec![
+        IMP::Stack(StackOperations::PushNumber(limit as i32 - 1)),
+        IMP::FlowControl(FlowControlOperations::Call(strncpy)),
+    ]
+}
+
+pub fn len(
+    state: &mut State,
+    args: &[Expression],
+    span: Span,
+    level: i8,
+) -> Result<EvaluationResult, Diagnostic> {
+    let (mut code, mut debug_code) =
+        evaluate_arguments("len", &[Parameter::String], args, span, state, level)?;
+    // Variables of growable strings know their length
+    let instructions = match str_variable(&args[0], state) {
+        Some(_) => bi_str::len(),
+        None => {
+            let strlen = state.subroutine("strlen", strlen);
+            vec![IMP::FlowControl(FlowControlOperations::Call(strlen))]
+        }
+    };
+    CodeEmitter::emit_into(&mut code, &mut debug_code, instructions);
+    Ok(EvaluationResult {
+        code,
+        debug_code,
+        type_: VariableType::Int,
+    })
+}
+
+/// Character at the given index, or 0 when the index is out of range
+pub fn char_at(
+    state: &mut State,
+    args: &[Expression],
+    span: Span,
+    level: i8,
+) -> Result<EvaluationResult, Diagnostic> {
+    let (mut code, mut debug_code) = evaluate_arguments(
+        "char_at",
+        &[Parameter::String, Parameter::Int],
+        args,
+        span,
+        state,
+        level,
+    )?;
+    let char_at = state.subroutine("char_at", char_at_subroutine);
+    CodeEmitter::emit_into(
+        &mut code,
+        &mut debug_code,
+        vec![IMP::FlowControl(FlowControlOperations::Call(char_at))],
+    );
+    Ok(EvaluationResult {
+        code,
+        debug_code,
+        type_: VariableType::Char,
+    })
+}
+
+/// Index of the first occurrence of the needle, or -1 when there is none
+pub fn index_of(
+    state: &mut State,
+    args: &[Expression],
+    span: Span,
+    level: i8,
+) -> Result<EvaluationResult, Diagnostic> {
+    let (mut code, mut debug_code) = evaluate_arguments(
+        "index_of",
+        &[Parameter::String, Parameter::String],
+        args,
+        span,
+        state,
+        level,
+    )?;
+    let index_of = state.subroutine("index_of", index_of_subroutine);
+    CodeEmitter::emit_into(
+        &mut code,
+        &mut debug_code,
+        vec![IMP::FlowControl(FlowControlOperations::Call(index_of))],
+    );
+    Ok(EvaluationResult {
+        code,
+        debug_code,
+        type_: VariableType::Int,
+    })
+}
+
+/// 1 when the string starts with the prefix, 0 otherwise
+pub fn starts_with(
+    state: &mut State,
+    args: &[Expression],
+    span: Span,
+    level: i8,
+) -> Result<EvaluationResult, Diagnostic> {
+    let (mut code, mut debug_code) = evaluate_arguments(
+        "starts_with",
+        &[Parameter::String, Parameter::String],
+        args,
+        span,
+        state,
+        level,
+    )?;
+    let starts_with = state.subroutine("starts_with", starts_with_subroutine);
+    CodeEmitter::emit_into(
+        &mut code,
+        &mut debug_code,
+        vec![IMP::FlowControl(FlowControlOperations::Call(starts_with))],
+    );
+    Ok(EvaluationResult {
+        code,
+        debug_code,
+        type_: VariableType::Int,
+    })
+}
+
+/// Longest decimal representation of an i32, "-2147483648"
+const INT_WIDTH: usize = 11;
+
+/// Builds an interpolated string in a temporary buffer that is large enough for any value
+/// of the placeholders and pushes its address.
+pub fn interpolate(
+    parts: &[InterpolationPart],
+    state: &mut State,
+    level: i8,
+) -> Result<EvaluationResult, Diagnostic> {
+    let mut emitter = CodeEmitter {};
+    let mut values = vec![];
+    for part in parts {
+        if let InterpolationPart::Value(value) = part {
+            values.push(emitter.evaluate_expression(value, state, level)?);
+        }
+    }
+    if values.iter().any(|value| value.type_ == VariableType::Str) {
+        return Ok(interpolate_str(parts, values, state, level));
+    }
+    let mut capacity = 1;
+    let mut types = values.iter().map(|value| value.type_);
+    for part in parts {
+        capacity += match part {
+            InterpolationPart::Text(text) => text.chars().count(),
+            InterpolationPart::Value(_) => match types.next().expect("every value was evaluated") {
+                VariableType::Char => 1,
+                VariableType::String(length) => length - 1,
+                _ => INT_WIDTH,
+            },
+        };
+    }
+    let type_ = VariableType::String(capacity);
+    let buffer = state.heap_allocation_map.reserve(type_);
+
+    let mut code = String::new();
+    let mut debug_code = DebugCodeBlock::new(None, level);
+    // The stack holds the end of the string built so far
+    CodeEmitter::emit_into(
+        &mut code,
+        &mut debug_code,
+        vec![IMP::Stack(StackOperations::PushNumber(buffer))],
+    );
+    let mut values = values.into_iter();
+    for part in parts {
+        let instructions = match part {
+            InterpolationPart::Text(text) => text
+                .chars()
+                .flat_map(|ch| {
+                    [
+                        IMP::Stack(StackOperations::Duplicate),
+                        IMP::Stack(StackOperations::PushNumber(ch as i32)),
+                        IMP::Heap(HeapOperations::Store),
+                        IMP::Stack(StackOperations::PushNumber(HeapVariableSize::Char.size())),
+                        IMP::Arithmetic(ArithmeticOperations::Add),
+                    ]
+                })
+                .collect(),
+            InterpolationPart::Value(_) => {
+                let value = values.next().expect("every value was evaluated");
+                CodeEmitter::emit_into(
+                    &mut code,
+                    &mut debug_code,
+                    vec![IMP::Stack(StackOperations::Duplicate)],
+                );
+                code.push_str(&value.code);
+                debug_code.append(value.debug_code);
+                match value.type_ {
+                    VariableType::Char => vec![
+                        IMP::Heap(HeapOperations::Store),
+                        IMP::Stack(StackOperations::PushNumber(HeapVariableSize::Char.size())),
+                        IMP::Arithmetic(ArithmeticOperations::Add),
+                    ],
+                    type_ => {
+                        let (limit, label) = match type_ {
+                            VariableType::String(length) => {
+                                (length as i32 - 1, state.subroutine("strncpy", strncpy))
+                            }
+                            _ => (INT_WIDTH as i32, state.subroutine("itoa", bi_convert::itoa)),
+                        };
+                        let strlen = state.subroutine("strlen", strlen);
+                        vec![
+                            IMP::Stack(StackOperations::PushNumber(limit)),
+                            IMP::FlowControl(FlowControlOperations::Call(label)),
+                            // Move to the new end
+                            IMP::Stack(StackOperations::Duplicate),
+                            IMP::FlowControl(FlowControlOperations::Call(strlen)),
+                            IMP::Arithmetic(ArithmeticOperations::Add),
+                        ]
+                    }
+                }
+            }
+        };
+        CodeEmitter::emit_into(&mut code, &mut debug_code, instructions);
+    }
+    CodeEmitter::emit_into(
+        &mut code,
+        &mut debug_code,
+        vec![
+            IMP::Stack(StackOperations::PushNumber(0)),
+            IMP::Heap(HeapOperations::Store),
+            IMP::Stack(StackOperations::PushNumber(buffer)),
+        ],
+    );
+    Ok(EvaluationResult {
+        code,
+        debug_code,
+        type_,
+    })
+}
+
+/// Same as [`interpolate`] for values that include growable strings, whose length is not
+/// known in advance. The string is built in a temporary `str` that is reused every time the
+/// expression is evaluated.
+fn interpolate_str(
+    parts: &[InterpolationPart],
+    values: Vec<EvaluationResult>,
+    state: &mut State,
+    level: i8,
+) -> EvaluationResult {
+    let buffer = state.heap_allocation_map.reserve(VariableType::Str);
+    // Numbers are formatted here before they are added
+    let digits = state
+        .heap_allocation_map
+        .reserve(VariableType::String(INT_WIDTH + 1));
+    let itoa = state.subroutine("itoa", bi_convert::itoa);
+
+    let mut code = String::new();
+    let mut debug_code = DebugCodeBlock::new(None, level);
+    let mut instructions = bi_str::declare(state, buffer);
+    instructions.extend(bi_str::clear(buffer));
+    CodeEmitter::emit_into(&mut code, &mut debug_code, instructions);
+    let mut values = values.into_iter();
+    for part in parts {
+        let instructions = match part {
+            InterpolationPart::Text(text) => {
+                let mut instructions = vec![];
+                for ch in text.chars() {
+                    instructions.extend([
+                        IMP::Stack(StackOperations::PushNumber(buffer)),
+                        IMP::Stack(StackOperations::PushNumber(ch as i32)),
+                    ]);
+                    instructions.extend(bi_str::push_char(state));
+                }
+                instructions
+            }
+            InterpolationPart::Value(_) => {
+                let value = values.next().expect("every value was evaluated");
+                let mut instructions = vec![IMP::Stack(StackOperations::PushNumber(buffer))];
+                if !matches!(value.type_, VariableType::Char) && !value.type_.is_string() {
+                    instructions.push(IMP::Stack(StackOperations::PushNumber(digits)));
+                }
+                CodeEmitter::emit_into(&mut code, &mut debug_code, instructions);
+                code.push_str(&value.code);
+                debug_code.append(value.debug_code);
+                match value.type_ {
+                    VariableType::Char => bi_str::push_char(state),
+                    type_ if type_.is_string() => bi_str::append(state),
+                    _ => {
+                        let mut instructions = vec![
+                            IMP::Stack(StackOperations::PushNumber(INT_WIDTH as i32)),
+                            IMP::FlowControl(FlowControlOperations::Call(itoa)),
+                            IMP::Stack(StackOperations::PushNumber(digits)),
+                        ];
+                        instructions.extend(bi_str::append(state));
+                        instructions
+                    }
+                }
+            }
+        };
+        CodeEmitter::emit_into(&mut code, &mut debug_code, instructions);
+    }
+    let mut instructions = vec![IMP::Stack(StackOperations::PushNumber(buffer))];
+    instructions.extend(bi_str::chars());
+    CodeEmitter::emit_into(&mut code, &mut debug_code, instructions);
+    EvaluationResult {
+        code,
+        debug_code,
+        type_: VariableType::Str,
+    }
+}
+
+/// Copies `count` characters of `src` starting at `start` into `dst`. The range is clamped
+/// to the source string and the result is truncated to the capacity of `dst`.
+pub fn substr(
+    state: &mut State,
+    args: &[Expression],
+    span: Span,
+    res: &mut CodeOutput,
+) -> Result<(), Diagnostic> {
+    res.add("".to_string(), "# substr".to_string());
+    check_arity("substr", 4, args, span)?;
+    let (target_addr, capacity) = string_target("substr", &args[0], state)?;
+    let strlen = state.subroutine("strlen", strlen);
+    let clamp = state.subroutine("clamp", clamp);
+    let strncpy = state.subroutine("strncpy", strncpy);
+    let mut emitter = CodeEmitter {};
+
+    res.append(emitter.emit(vec![IMP::Stack(StackOperations::PushNumber(target_addr))]));
+    let (code, debug_code) = evaluate_arguments(
+        "substr",
+        &[Parameter::String, Parameter::Int],
+        &args[1..3],
+        span,
+        state,
+        0,
+    )?;
+    res.add(code, debug_code.render());
+    res.append(emitter.emit(vec![
+        // Clamp start to the source string
+        IMP::Stack(StackOperations::PushNumber(0)),
+        IMP::Stack(StackOperations::CopyNth(2)),
+        IMP::FlowControl(FlowControlOperations::Call(strlen)),
+        IMP::FlowControl(FlowControlOperations::Call(clamp)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+    ]));
+    let (code, debug_code) =
+        evaluate_arguments("substr", &[Parameter::Int], &args[3..], span, state, 0)?;
+    res.add(code, debug_code.render());
+    res.append(emitter.emit(vec![
+        // Clamp count to the target capacity
+        IMP::Stack(StackOperations::PushNumber(0)),
+        IMP::Stack(StackOperations::PushNumber(capacity as i32 - 1)),
+        IMP::FlowControl(FlowControlOperations::Call(clamp)),
+        IMP::FlowControl(FlowControlOperations::Call(strncpy)),
+    ]));
+    Ok(())
+}
+
+pub fn to_upper(
+    state: &mut State,
+    args: &[Expression],
+    span: Span,
+    res: &mut CodeOutput,
+) -> Result<(), Diagnostic> {
+    in_place("to_upper", to_upper_subroutine, state, args, span, res)
+}
+
+pub fn to_lower(
+    state: &mut State,
+    args: &[Expression],
+    span: Span,
+    res: &mut CodeOutput,
+) -> Result<(), Diagnostic> {
+    in_place("to_lower", to_lower_subroutine, state, args, span, res)
+}
+
+pub fn reverse(
+    state: &mut State,
+    args: &[Expression],
+    span: Span,
+    res: &mut CodeOutput,
+) -> Result<(), Diagnostic> {
+    in_place("reverse", reverse_subroutine, state, args, span, res)
+}
+
+/// Removes leading and trailing whitespace, which is any character up to and including space
+pub fn trim(
+    state: &mut State,
+    args: &[Expression],
+    span: Span,
+    res: &mut CodeOutput,
+) -> Result<(), Diagnostic> {
+    in_place("trim", trim_subroutine, state, args, span, res)
+}
+
+/// Calls a subroutine that modifies the string variable passed as the only argument
+fn in_place(
+    name: &'static str,
+    body: fn(&mut State) -> Vec<IMP>,
+    state: &mut State,
+    args: &[Expression],
+    span: Span,
+    res: &mut CodeOutput,
+) -> Result<(), Diagnostic> {
+    res.add("".to_string(), format!("# {}", name));
+    check_arity(name, 1, args, span)?;
+    let (addr, _) = string_target(name, &args[0], state)?;
+    let label = state.subroutine(name, body);
+    let mut emitter = CodeEmitter {};
+    res.append(emitter.emit(vec![
+        IMP::Stack(StackOperations::PushNumber(addr)),
+        IMP::FlowControl(FlowControlOperations::Call(label)),
+    ]));
+    Ok(())
+}
+
+// Subroutines take their arguments from the stack and replace them with the result, if any
+
+/// [addr] -> [length]
+pub fn strlen(state: &mut State) -> Vec<IMP> {
+    let loop_label = state.get_label();
+    let end_label = state.get_label();
+    vec![
+        IMP::Stack(StackOperations::PushNumber(0)),
+        IMP::FlowControl(FlowControlOperations::SetLabel(loop_label)),
+        IMP::Stack(StackOperations::CopyNth(1)),
+        IMP::Stack(StackOperations::CopyNth(1)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::FlowControl(FlowControlOperations::JumpIfZero(end_label)),
+        IMP::Stack(StackOperations::PushNumber(HeapVariableSize::Char.size())),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::FlowControl(FlowControlOperations::Jump(loop_label)),
+        IMP::FlowControl(FlowControlOperations::SetLabel(end_label)),
+        IMP::Stack(StackOperations::Slide(1)),
+        IMP::FlowControl(FlowControlOperations::Return),
+    ]
+}
+
+/// [value low high] -> [value limited to low..=high]
+pub fn clamp(state: &mut State) -> Vec<IMP> {
+    let below_high_label = state.get_label();
+    let below_low_label = state.get_label();
+    vec![
+        IMP::Stack(StackOperations::CopyNth(2)),
+        IMP::Stack(StackOperations::CopyNth(1)),
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::FlowControl(FlowControlOperations::JumpIfNegative(below_high_label)),
+        IMP::Stack(StackOperations::Slide(2)),
+        IMP::FlowControl(FlowControlOperations::Return),
+        IMP::FlowControl(FlowControlOperations::SetLabel(below_high_label)),
+        IMP::Stack(StackOperations::Discard),
+        IMP::Stack(StackOperations::CopyNth(1)),
+        IMP::Stack(StackOperations::CopyNth(1)),
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::FlowControl(FlowControlOperations::JumpIfNegative(below_low_label)),
+        IMP::Stack(StackOperations::Discard),
+        IMP::FlowControl(FlowControlOperations::Return),
+        IMP::FlowControl(FlowControlOperations::SetLabel(below_low_label)),
+        IMP::Stack(StackOperations::Slide(1)),
+        IMP::FlowControl(FlowControlOperations::Return),
+    ]
+}
+
+/// [target source count] -> [], copies at most `count` characters and adds a terminator
+pub fn strncpy(state: &mut State) -> Vec<IMP> {
+    let loop_label = state.get_label();
+    let end_label = state.get_label();
+    let stop_label = state.get_label();
+    vec![
+        // Index of the character being copied
+        IMP::Stack(StackOperations::PushNumber(0)),
+        IMP::FlowControl(FlowControlOperations::SetLabel(loop_label)),
+        // Stop when count characters were copied
+        IMP::Stack(StackOperations::CopyNth(1)),
+        IMP::Stack(StackOperations::CopyNth(1)),
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::Stack(StackOperations::PushNumber(1)),
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::FlowControl(FlowControlOperations::JumpIfNegative(stop_label)),
+        // Load source char, stop at the terminator
+        IMP::Stack(StackOperations::CopyNth(2)),
+        IMP::Stack(StackOperations::CopyNth(1)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::FlowControl(FlowControlOperations::JumpIfZero(end_label)),
+        // Store it at the same index of the target
+        IMP::Stack(StackOperations::CopyNth(4)),
+        IMP::Stack(StackOperations::CopyNth(2)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Stack(StackOperations::Swap),
+        IMP::Heap(HeapOperations::Store),
+        IMP::Stack(StackOperations::PushNumber(HeapVariableSize::Char.size())),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::FlowControl(FlowControlOperations::Jump(loop_label)),
+        IMP::FlowControl(FlowControlOperations::SetLabel(end_label)),
+        IMP::Stack(StackOperations::Discard),
+        IMP::FlowControl(FlowControlOperations::SetLabel(stop_label)),
+        // Add null terminator
+        IMP::Stack(StackOperations::Slide(2)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Stack(StackOperations::PushNumber(0)),
+        IMP::Heap(HeapOperations::Store),
+        IMP::FlowControl(FlowControlOperations::Return),
+    ]
+}
+
+/// [addr index] -> [char], 0 when the index is out of range
+fn char_at_subroutine(state: &mut State) -> Vec<IMP> {
+    let strlen = state.subroutine("strlen", strlen);
+    let out_of_range_label = state.get_label();
+    vec![
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::FlowControl(FlowControlOperations::JumpIfNegative(out_of_range_label)),
+        IMP::Stack(StackOperations::CopyNth(1)),
+        IMP::FlowControl(FlowControlOperations::Call(strlen)),
+        IMP::Stack(StackOperations::CopyNth(1)),
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::Stack(StackOperations::PushNumber(1)),
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::FlowControl(FlowControlOperations::JumpIfNegative(out_of_range_label)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::FlowControl(FlowControlOperations::Return),
+        IMP::FlowControl(FlowControlOperations::SetLabel(out_of_range_label)),
+        IMP::Stack(StackOperations::Discard),
+        IMP::Stack(StackOperations::Discard),
+        IMP::Stack(StackOperations::PushNumber(0)),
+        IMP::FlowControl(FlowControlOperations::Return),
+    ]
+}
+
+/// [addr prefix] -> [1 or 0]
+fn starts_with_subroutine(state: &mut State) -> Vec<IMP> {
+    let loop_label = state.get_label();
+    let next_label = state.get_label();
+    let match_label = state.get_label();
+    vec![
+        IMP::FlowControl(FlowControlOperations::SetLabel(loop_label)),
+        // The whole prefix matched
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::FlowControl(FlowControlOperations::JumpIfZero(match_label)),
+        IMP::Stack(StackOperations::CopyNth(1)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::CopyNth(1)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::FlowControl(FlowControlOperations::JumpIfZero(next_label)),
+        IMP::Stack(StackOperations::Discard),
+        IMP::Stack(StackOperations::Discard),
+        IMP::Stack(StackOperations::PushNumber(0)),
+        IMP::FlowControl(FlowControlOperations::Return),
+        // Advance both pointers
+        IMP::FlowControl(FlowControlOperations::SetLabel(next_label)),
+        IMP::Stack(StackOperations::PushNumber(HeapVariableSize::Char.size())),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Stack(StackOperations::Swap),
+        IMP::Stack(StackOperations::PushNumber(HeapVariableSize::Char.size())),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Stack(StackOperations::Swap),
+        IMP::FlowControl(FlowControlOperations::Jump(loop_label)),
+        IMP::FlowControl(FlowControlOperations::SetLabel(match_label)),
+        IMP::Stack(StackOperations::Discard),
+        IMP::Stack(StackOperations::Discard),
+        IMP::Stack(StackOperations::PushNumber(1)),
+        IMP::FlowControl(FlowControlOperations::Return),
+    ]
+}
+
+/// [addr needle] -> [index or -1]
+fn index_of_subroutine(state: &mut State) -> Vec<IMP> {
+    let starts_with = state.subroutine("starts_with", starts_with_subroutine);
+    let loop_label = state.get_label();
+    let mismatch_label = state.get_label();
+    let not_found_label = state.get_label();
+    vec![
+        IMP::Stack(StackOperations::PushNumber(0)),
+        IMP::FlowControl(FlowControlOperations::SetLabel(loop_label)),
+        // Check whether the needle starts at the current index
+        IMP::Stack(StackOperations::CopyNth(2)),
+        IMP::Stack(StackOperations::CopyNth(1)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Stack(StackOperations::CopyNth(2)),
+        IMP::FlowControl(FlowControlOperations::Call(starts_with)),
+        IMP::FlowControl(FlowControlOperations::JumpIfZero(mismatch_label)),
+        IMP::Stack(StackOperations::Slide(2)),
+        IMP::FlowControl(FlowControlOperations::Return),
+        IMP::FlowControl(FlowControlOperations::SetLabel(mismatch_label)),
+        // Give up at the end of the string
+        IMP::Stack(StackOperations::CopyNth(2)),
+        IMP::Stack(StackOperations::CopyNth(1)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::FlowControl(FlowControlOperations::JumpIfZero(not_found_label)),
+        IMP::Stack(StackOperations::PushNumber(HeapVariableSize::Char.size())),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::FlowControl(FlowControlOperations::Jump(loop_label)),
+        IMP::FlowControl(FlowControlOperations::SetLabel(not_found_label)),
+        IMP::Stack(StackOperations::Slide(2)),
+        IMP::Stack(StackOperations::Discard),
+        IMP::Stack(StackOperations::PushNumber(-1)),
+        IMP::FlowControl(FlowControlOperations::Return),
+    ]
+}
+
+fn to_upper_subroutine(state: &mut State) -> Vec<IMP> {
+    map_range(state, 'a', 'z', 'A' as i32 - 'a' as i32)
+}
+
+fn to_lower_subroutine(state: &mut State) -> Vec<IMP> {
+    map_range(state, 'A', 'Z', 'a' as i32 - 'A' as i32)
+}
+
+/// [addr] -> [], adds `delta` to every character between `low` and `high`
+fn map_range(state: &mut State, low: char, high: char, delta: i32) -> Vec<IMP> {
+    let loop_label = state.get_label();
+    let skip_label = state.get_label();
+    let next_label = state.get_label();
+    let end_label = state.get_label();
+    vec![
+        IMP::FlowControl(FlowControlOperations::SetLabel(loop_label)),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::FlowControl(FlowControlOperations::JumpIfZero(end_label)),
+        // Skip characters outside of the range
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::Stack(StackOperations::PushNumber(low as i32)),
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::FlowControl(FlowControlOperations::JumpIfNegative(skip_label)),
+        IMP::Stack(StackOperations::PushNumber(high as i32)),
+        IMP::Stack(StackOperations::CopyNth(1)),
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::FlowControl(FlowControlOperations::JumpIfNegative(skip_label)),
+        IMP::Stack(StackOperations::PushNumber(delta)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Stack(StackOperations::CopyNth(1)),
+        IMP::Stack(StackOperations::Swap),
+        IMP::Heap(HeapOperations::Store),
+        IMP::FlowControl(FlowControlOperations::Jump(next_label)),
+        IMP::FlowControl(FlowControlOperations::SetLabel(skip_label)),
+        IMP::Stack(StackOperations::Discard),
+        IMP::FlowControl(FlowControlOperations::SetLabel(next_label)),
+        IMP::Stack(StackOperations::PushNumber(HeapVariableSize::Char.size())),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::FlowControl(FlowControlOperations::Jump(loop_label)),
+        IMP::FlowControl(FlowControlOperations::SetLabel(end_label)),
+        IMP::Stack(StackOperations::Discard),
+        IMP::Stack(StackOperations::Discard),
+        IMP::FlowControl(FlowControlOperations::Return),
+    ]
+}
+
+/// [addr] -> []
+fn reverse_subroutine(state: &mut State) -> Vec<IMP> {
+    let strlen = state.subroutine("strlen", strlen);
+    let loop_label = state.get_label();
+    let swap_label = state.get_label();
+    vec![
+        // Pointer to the last character
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::FlowControl(FlowControlOperations::Call(strlen)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Stack(StackOperations::PushNumber(HeapVariableSize::Char.size())),
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        // Swap characters until the pointers meet
+        IMP::FlowControl(FlowControlOperations::SetLabel(loop_label)),
+        IMP::Stack(StackOperations::CopyNth(1)),
+        IMP::Stack(StackOperations::CopyNth(1)),
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::FlowControl(FlowControlOperations::JumpIfNegative(swap_label)),
+        IMP::Stack(StackOperations::Discard),
+        IMP::Stack(StackOperations::Discard),
+        IMP::FlowControl(FlowControlOperations::Return),
+        IMP::FlowControl(FlowControlOperations::SetLabel(swap_label)),
+        IMP::Stack(StackOperations::CopyNth(1)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::CopyNth(1)),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::CopyNth(3)),
+        IMP::Stack(StackOperations::Swap),
+        IMP::Heap(HeapOperations::Store),
+        IMP::Stack(StackOperations::CopyNth(1)),
+        IMP::Stack(StackOperations::Swap),
+        IMP::Heap(HeapOperations::Store),
+        // Move both pointers towards the middle
+        IMP::Stack(StackOperations::PushNumber(HeapVariableSize::Char.size())),
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::Stack(StackOperations::Swap),
+        IMP::Stack(StackOperations::PushNumber(HeapVariableSize::Char.size())),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Stack(StackOperations::Swap),
+        IMP::FlowControl(FlowControlOperations::Jump(loop_label)),
+    ]
+}
+
+/// [addr] -> []
+fn trim_subroutine(state: &mut State) -> Vec<IMP> {
+    let strlen = state.subroutine("strlen", strlen);
+    let skip_label = state.get_label();
+    let skip_next_label = state.get_label();
+    let blank_label = state.get_label();
+    let shift_label = state.get_label();
+    let shift_end_label = state.get_label();
+    let strip_label = state.get_label();
+    let strip_next_label = state.get_label();
+    vec![
+        // Find the first character that is not whitespace
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::FlowControl(FlowControlOperations::SetLabel(skip_label)),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::FlowControl(FlowControlOperations::JumpIfZero(blank_label)),
+        IMP::Stack(StackOperations::PushNumber(' ' as i32 + 1)),
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::FlowControl(FlowControlOperations::JumpIfNegative(skip_next_label)),
+        // Shift the rest of the string to the start, including the terminator
+        IMP::Stack(StackOperations::CopyNth(1)),
+        IMP::Stack(StackOperations::Swap),
+        IMP::FlowControl(FlowControlOperations::SetLabel(shift_label)),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::CopyNth(2)),
+        IMP::Stack(StackOperations::CopyNth(1)),
+        IMP::Heap(HeapOperations::Store),
+        IMP::FlowControl(FlowControlOperations::JumpIfZero(shift_end_label)),
+        IMP::Stack(StackOperations::PushNumber(HeapVariableSize::Char.size())),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Stack(StackOperations::Swap),
+        IMP::Stack(StackOperations::PushNumber(HeapVariableSize::Char.size())),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::Stack(StackOperations::Swap),
+        IMP::FlowControl(FlowControlOperations::Jump(shift_label)),
+        IMP::FlowControl(FlowControlOperations::SetLabel(skip_next_label)),
+        IMP::Stack(StackOperations::PushNumber(HeapVariableSize::Char.size())),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::FlowControl(FlowControlOperations::Jump(skip_label)),
+        // The string is empty or whitespace only
+        IMP::FlowControl(FlowControlOperations::SetLabel(blank_label)),
+        IMP::Stack(StackOperations::Discard),
+        IMP::Stack(StackOperations::Discard),
+        IMP::Stack(StackOperations::PushNumber(0)),
+        IMP::Heap(HeapOperations::Store),
+        IMP::FlowControl(FlowControlOperations::Return),
+        // Move the terminator before the trailing whitespace
+        IMP::FlowControl(FlowControlOperations::SetLabel(shift_end_label)),
+        IMP::Stack(StackOperations::Discard),
+        IMP::Stack(StackOperations::Discard),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::FlowControl(FlowControlOperations::Call(strlen)),
+        IMP::Arithmetic(ArithmeticOperations::Add),
+        IMP::FlowControl(FlowControlOperations::SetLabel(strip_label)),
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::Stack(StackOperations::PushNumber(HeapVariableSize::Char.size())),
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::Heap(HeapOperations::Retrieve),
+        IMP::Stack(StackOperations::PushNumber(' ' as i32 + 1)),
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::FlowControl(FlowControlOperations::JumpIfNegative(strip_next_label)),
+        IMP::Stack(StackOperations::PushNumber(0)),
+        IMP::Heap(HeapOperations::Store),
+        IMP::Stack(StackOperations::Discard),
+        IMP::FlowControl(FlowControlOperations::Return),
+        IMP::FlowControl(FlowControlOperations::SetLabel(strip_next_label)),
+        IMP::Stack(StackOperations::PushNumber(HeapVariableSize::Char.size())),
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::FlowControl(FlowControlOperations::Jump(strip_label)),
+    ]
 }
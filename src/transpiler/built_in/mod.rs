@@ -1,2 +1,166 @@
+pub mod bi_bitwise;
+pub mod bi_convert;
 pub mod bi_io;
+pub mod bi_map;
+pub mod bi_math;
+pub mod bi_memory;
+pub mod bi_random;
+pub mod bi_str;
 pub mod bi_string;
+
+use crate::{
+    ast::{Expression, ExpressionKind},
+    diagnostic::Diagnostic,
+    lexer::Span,
+};
+
+use super::{emitter::CodeEmitter, emitter::EvaluationResult, state::State};
+use super::{DebugCodeBlock, VariableType};
+
+/// Type of a built-in function parameter
+#[derive(Clone, Copy)]
+pub enum Parameter {
+    Int,
+    String,
+    Pointer,
+}
+
+impl Parameter {
+    pub fn accepts(&self, type_: VariableType) -> bool {
+        match self {
+            Parameter::Int => type_.is_numeric(),
+            Parameter::String => type_.is_string(),
+            Parameter::Pointer => type_ == VariableType::Pointer,
+        }
+    }
+}
+
+impl std::fmt::Display for Parameter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Parameter::Int => write!(f, "i32"),
+            Parameter::String => write!(f, "String"),
+            Parameter::Pointer => write!(f, "ptr"),
+        }
+    }
+}
+
+pub fn check_arity(
+    name: &str,
+    expected: usize,
+    args: &[Expression],
+    span: Span,
+) -> Result<(), Diagnostic> {
+    if args.len() != expected {
+        return Err(Diagnostic::new(
+            format!(
+                "{} expects {} arguments, found {}",
+                name,
+                expected,
+                args.len()
+            ),
+            span,
+        ));
+    }
+    Ok(())
+}
+
+/// Evaluates the arguments of a built-in function and checks them against its parameters.
+/// The values are pushed in order, so the last argument ends up on top of the stack.
+pub fn evaluate_arguments(
+    name: &str,
+    parameters: &[Parameter],
+    args: &[Expression],
+    span: Span,
+    state: &mut State,
+    level: i8,
+) -> Result<(String, DebugCodeBlock), Diagnostic> {
+    check_arity(name, parameters.len(), args, span)?;
+    let mut emitter = CodeEmitter {};
+    let mut code = String::new();
+    let mut debug_code = DebugCodeBlock::new(None, level);
+    for (parameter, arg) in parameters.iter().zip(args) {
+        let result = emitter.evaluate_expression(arg, state, level)?;
+        if !parameter.accepts(result.type_) {
+            return Err(Diagnostic::new(
+                format!(
+                    "{} expects {} as argument, found {}",
+                    name, parameter, result.type_
+                ),
+                arg.span,
+            ));
+        }
+        code.push_str(&result.code);
+        debug_code.append(result.debug_code);
+    }
+    Ok((code, debug_code))
+}
+
+/// Returns the address and the capacity of a string variable that a built-in writes to
+pub fn string_target(
+    name: &str,
+    arg: &Expression,
+    state: &State,
+) -> Result<(i32, usize), Diagnostic> {
+    let error = || Diagnostic::new(format!("{} expects a string variable", name), arg.span);
+    let ExpressionKind::Variable(variable) = &arg.kind else {
+        return Err(error());
+    };
+    let variable = state.heap_allocation_map.lookup(variable, arg.span)?;
+    match variable.type_() {
+        VariableType::String(capacity) => Ok((variable.offset(), capacity)),
+        VariableType::Str => Err(Diagnostic::new(
+            format!("{} does not support str variables", name),
+            arg.span,
+        )),
+        _ => Err(error()),
+    }
+}
+
+/// Returns the address of a `str` variable, `None` when the argument is anything else
+pub fn str_variable(arg: &Expression, state: &State) -> Option<i32> {
+    let ExpressionKind::Variable(variable) = &arg.kind else {
+        return None;
+    };
+    state
+        .heap_allocation_map
+        .get(variable)
+        .filter(|variable| variable.type_() == VariableType::Str)
+        .map(|variable| variable.offset())
+}
+
+/// Evaluates a call of a built-in function that returns a value
+pub fn evaluate_call(
+    name: &str,
+    args: &[Expression],
+    span: Span,
+    state: &mut State,
+    level: i8,
+) -> Result<EvaluationResult, Diagnostic> {
+    match name {
+        "len" => bi_string::len(state, args, span, level),
+        "char_at" => bi_string::char_at(state, args, span, level),
+        "index_of" => bi_string::index_of(state, args, span, level),
+        "starts_with" => bi_string::starts_with(state, args, span, level),
+        "parse_int" => bi_convert::parse_int(state, args, span, level),
+        "read_line" => bi_io::read_line(state, args, span, level),
+        "read_char" => bi_io::read_char(state, args, span, level),
+        "eof" => bi_io::eof(state, args, span, level),
+        "abs" => bi_math::abs(state, args, span, level),
+        "sign" => bi_math::sign(state, args, span, level),
+        "min" => bi_math::min(state, args, span, level),
+        "max" => bi_math::max(state, args, span, level),
+        "pow" => bi_math::pow(state, args, span, level),
+        "isqrt" => bi_math::isqrt(state, args, span, level),
+        "gcd" => bi_math::gcd(state, args, span, level),
+        "lcm" => bi_math::lcm(state, args, span, level),
+        "rand" => bi_random::rand(state, args, span, level),
+        "alloc" => bi_memory::alloc(state, args, span, level),
+        "get" => bi_map::get(state, args, span, level),
+        "has" => bi_map::has(state, args, span, level),
+        _ if state.prelude_function(name).is_some() => {
+            super::function::evaluate(name, args, span, state, level)
+        }
+        _ => Err(Diagnostic::new(format!("Unknown function {}", name), span)),
+    }
+}
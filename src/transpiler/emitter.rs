@@ -1,14 +1,17 @@
 use crate::{
-    ast::{Expression, Operation},
+    ast::{Expression, ExpressionKind, Operation},
+    diagnostic::Diagnostic,
     specs::whitespace::*,
 };
 
-use super::{state, CodeOutput, DebugCodeBlock, HeapVariableSize, VariableType};
+use super::{
+    built_in, function, state, CodeOutput, DebugCodeBlock, HeapVariableSize, VariableType,
+};
 
-struct EvaluationResult {
-    code: String,
-    debug_code: DebugCodeBlock,
-    type_: VariableType,
+pub struct EvaluationResult {
+    pub code: String,
+    pub debug_code: DebugCodeBlock,
+    pub type_: VariableType,
 }
 
 pub struct CodeEmitter {}
@@ -23,33 +26,68 @@ impl CodeEmitter {
         res
     }
 
+    pub fn emit_into(code: &mut String, debug_code: &mut DebugCodeBlock, expression: Vec<IMP>) {
+        for imp in expression {
+            let CodeOutput {
+                code: c,
+                debug_code: dc,
+            } = imp.gen();
+            code.push_str(&c);
+            debug_code.push(dc);
+        }
+    }
+
     // TODO: Calculation can leave a value on the stack, which might not be desirable
     // Need to implement a way to keep stack clean
     pub fn evaluate_expression(
         &mut self,
         expression: &Expression,
-        heap_allocation_map: &state::HeapAllocation,
+        state: &mut state::State,
         level: i8,
-    ) -> (String, DebugCodeBlock, VariableType, i32) {
+    ) -> Result<EvaluationResult, Diagnostic> {
         let mut code = String::new();
         let mut debug_code = DebugCodeBlock::new(Some(expression.to_string()), level);
-        match expression {
-            Expression::Integer(value) => {
+        match &expression.kind {
+            ExpressionKind::Integer(value) => {
                 let CodeOutput {
                     code: c,
                     debug_code: dc,
                 } = IMP::Stack(StackOperations::PushNumber(*value)).gen();
                 code.push_str(&c);
                 debug_code.push(dc);
-                (
+                Ok(EvaluationResult {
                     code,
                     debug_code,
-                    VariableType::Int,
-                    HeapVariableSize::Int.size(),
-                )
+                    type_: VariableType::Int,
+                })
+            }
+            ExpressionKind::Character(value) => {
+                Self::emit_into(
+                    &mut code,
+                    &mut debug_code,
+                    vec![IMP::Stack(StackOperations::PushNumber(*value as i32))],
+                );
+                Ok(EvaluationResult {
+                    code,
+                    debug_code,
+                    type_: VariableType::Char,
+                })
+            }
+            ExpressionKind::EnumVariant(name, variant) => {
+                let (type_, value) = state.lookup_variant(name, variant, expression.span)?;
+                Self::emit_into(
+                    &mut code,
+                    &mut debug_code,
+                    vec![IMP::Stack(StackOperations::PushNumber(value))],
+                );
+                Ok(EvaluationResult {
+                    code,
+                    debug_code,
+                    type_,
+                })
             }
-            Expression::Variable(name) => {
-                let variable = heap_allocation_map.get(name).unwrap();
+            ExpressionKind::Variable(name) => {
+                let variable = state.heap_allocation_map.lookup(name, expression.span)?;
                 let type_ = variable.type_();
                 let offset = variable.offset();
                 let CodeOutput {
@@ -59,26 +97,69 @@ impl CodeEmitter {
                 code.push_str(&c);
                 debug_code.push(dc);
                 match type_ {
-                    VariableType::Int => {
+                    VariableType::Int
+                    | VariableType::Char
+                    | VariableType::Enum(_)
+                    | VariableType::Pointer => {
                         let CodeOutput {
                             code: c2,
                             debug_code: dc2,
                         } = IMP::Heap(HeapOperations::Retrieve).gen();
                         code.push_str(&c2);
                         debug_code.push(dc2);
-                        (
+                        Ok(EvaluationResult {
                             code,
                             debug_code,
-                            VariableType::Int,
-                            HeapVariableSize::Int.size(),
-                        )
+                            type_,
+                        })
                     }
-                    _ => {
-                        panic!("Only integer values are supported for now");
+                    // Strings are passed around by the address of their first character
+                    VariableType::String(_) => Ok(EvaluationResult {
+                        code,
+                        debug_code,
+                        type_,
+                    }),
+                    VariableType::Map(_) => Err(Diagnostic::new(
+                        format!(
+                            "Map {} can only be used with put, get, has and remove",
+                            name
+                        ),
+                        expression.span,
+                    )),
+                    VariableType::Str => {
+                        Self::emit_into(&mut code, &mut debug_code, built_in::bi_str::chars());
+                        Ok(EvaluationResult {
+                            code,
+                            debug_code,
+                            type_,
+                        })
                     }
                 }
             }
-            Expression::BinaryOp {
+            ExpressionKind::Literal(value) => {
+                // Literals used as values are written to a temporary buffer every time
+                // the expression is evaluated
+                let type_ = VariableType::String(value.chars().count() + 1);
+                let addr = state.heap_allocation_map.reserve(type_);
+                let mut instructions = vec![];
+                for (i, ch) in value.chars().chain(std::iter::once('\0')).enumerate() {
+                    instructions.extend([
+                        IMP::Stack(StackOperations::PushNumber(
+                            addr + i as i32 * HeapVariableSize::Char.size(),
+                        )),
+                        IMP::Stack(StackOperations::PushNumber(ch as i32)),
+                        IMP::Heap(HeapOperations::Store),
+                    ]);
+                }
+                instructions.push(IMP::Stack(StackOperations::PushNumber(addr)));
+                Self::emit_into(&mut code, &mut debug_code, instructions);
+                Ok(EvaluationResult {
+                    code,
+                    debug_code,
+                    type_,
+                })
+            }
+            ExpressionKind::BinaryOp {
                 operator,
                 left,
                 right,
@@ -86,112 +167,416 @@ impl CodeEmitter {
                 let EvaluationResult {
                     code: c,
                     debug_code: dc,
-                    ..
-                } = self.evaluate_binary_op(operator, left, right, heap_allocation_map, level);
+                    type_,
+                } = if operator.is_comparison() {
+                    self.evaluate_comparison_value(operator, left, right, state, level)?
+                } else {
+                    self.evaluate_binary_op(operator, left, right, state, level)?
+                };
                 code.push_str(&c);
                 debug_code.append(dc);
-                (
+                Ok(EvaluationResult {
                     code,
                     debug_code,
-                    VariableType::Int,
-                    HeapVariableSize::Int.size(),
-                )
+                    type_,
+                })
             }
-            _ => {
-                panic!("Unsupported expression");
-            }
-        }
-    }
-
-    fn evaluate_binary_op(
-        &mut self,
-        operator: &Operation,
-        left: &Expression,
-        right: &Expression,
-        heap_allocation_map: &state::HeapAllocation,
-        level: i8,
-    ) -> EvaluationResult {
-        let mut code = String::new();
-        let mut debug_code = DebugCodeBlock::new(None, level);
-
-        let (left_code, left_debug_code, left_type, left_size) =
-            self.evaluate_expression(left, heap_allocation_map, level + 1);
-        let (right_code, right_debug_code, right_type, right_size) =
-            self.evaluate_expression(right, heap_allocation_map, level + 1);
-        code.push_str(&left_code);
-        code.push_str(&right_code);
-        debug_code.append(left_debug_code);
-        debug_code.append(right_debug_code);
-
-        match operator {
-            Operation::Add => {
-                let CodeOutput {
+            ExpressionKind::Interpolation(parts) => {
+                let EvaluationResult {
                     code: c,
                     debug_code: dc,
-                } = IMP::Arithmetic(ArithmeticOperations::Add).gen();
+                    type_,
+                } = built_in::bi_string::interpolate(parts, state, level + 1)?;
                 code.push_str(&c);
-                debug_code.push(dc);
-                EvaluationResult {
+                debug_code.append(dc);
+                Ok(EvaluationResult {
                     code,
                     debug_code,
-                    type_: VariableType::Int,
-                }
+                    type_,
+                })
             }
-            Operation::Sub => {
-                let CodeOutput {
+            ExpressionKind::Conditional {
+                condition,
+                then,
+                otherwise,
+            } => {
+                let EvaluationResult {
                     code: c,
                     debug_code: dc,
-                } = IMP::Arithmetic(ArithmeticOperations::Subtract).gen();
+                    type_,
+                } = self.evaluate_conditional(condition, then, otherwise, state, level + 1)?;
                 code.push_str(&c);
-                debug_code.push(dc);
-                EvaluationResult {
+                debug_code.append(dc);
+                Ok(EvaluationResult {
                     code,
                     debug_code,
-                    type_: VariableType::Int,
-                }
+                    type_,
+                })
             }
-            Operation::Mul => {
-                let CodeOutput {
-                    code: c,
-                    debug_code: dc,
-                } = IMP::Arithmetic(ArithmeticOperations::Multiply).gen();
+            ExpressionKind::Index(name, index) => {
+                let (c, dc) = built_in::bi_memory::element_address(
+                    name,
+                    index,
+                    expression.span,
+                    state,
+                    level + 1,
+                )?;
                 code.push_str(&c);
-                debug_code.push(dc);
-                EvaluationResult {
+                debug_code.append(dc);
+                Self::emit_into(
+                    &mut code,
+                    &mut debug_code,
+                    vec![IMP::Heap(HeapOperations::Retrieve)],
+                );
+                Ok(EvaluationResult {
                     code,
                     debug_code,
                     type_: VariableType::Int,
-                }
+                })
             }
-            Operation::Div => {
-                let CodeOutput {
+            ExpressionKind::Call(name, args) => {
+                // Functions of the program take precedence over the built-ins
+                let EvaluationResult {
                     code: c,
                     debug_code: dc,
-                } = IMP::Arithmetic(ArithmeticOperations::DivideInteger).gen();
+                    type_,
+                } = if state.function(name).is_some() {
+                    function::evaluate(name, args, expression.span, state, level + 1)?
+                } else {
+                    built_in::evaluate_call(name, args, expression.span, state, level + 1)?
+                };
                 code.push_str(&c);
-                debug_code.push(dc);
-                EvaluationResult {
+                debug_code.append(dc);
+                Ok(EvaluationResult {
                     code,
                     debug_code,
-                    type_: VariableType::Int,
-                }
+                    type_,
+                })
             }
-            Operation::Mod => {
-                let CodeOutput {
-                    code: c,
-                    debug_code: dc,
-                } = IMP::Arithmetic(ArithmeticOperations::Modulo).gen();
-                code.push_str(&c);
-                debug_code.push(dc);
-                EvaluationResult {
-                    code,
-                    debug_code,
-                    type_: VariableType::Int,
-                }
+        }
+    }
+
+    /// Jumps over the branch that is not taken, both branches leave a value of the same type
+    fn evaluate_conditional(
+        &mut self,
+        condition: &Expression,
+        then: &Expression,
+        otherwise: &Expression,
+        state: &mut state::State,
+        level: i8,
+    ) -> Result<EvaluationResult, Diagnostic> {
+        let then_label = state.get_label();
+        let end_label = state.get_label();
+        let (mut code, mut debug_code) =
+            self.evaluate_condition_block(condition, state, then_label, level)?;
+        let otherwise_result = self.evaluate_expression(otherwise, state, level)?;
+        let then_result = self.evaluate_expression(then, state, level)?;
+        let type_ = match (then_result.type_, otherwise_result.type_) {
+            (left, right) if left == right && left.is_numeric() => left,
+            (left, right) if left.is_numeric() && right.is_numeric() => VariableType::Int,
+            (VariableType::String(left), VariableType::String(right)) => {
+                VariableType::String(left.max(right))
             }
-            _ => {
-                panic!("Unsupported operator");
+            (VariableType::Str, VariableType::Str) => VariableType::Str,
+            (left, right) => {
+                return Err(Diagnostic::new(
+                    format!(
+                        "Branches of ?: have different types: {} and {}",
+                        left, right
+                    ),
+                    otherwise.span,
+                ))
+            }
+        };
+        code.push_str(&otherwise_result.code);
+        debug_code.append(otherwise_result.debug_code);
+        Self::emit_into(
+            &mut code,
+            &mut debug_code,
+            vec![
+                IMP::FlowControl(FlowControlOperations::Jump(end_label)),
+                IMP::FlowControl(FlowControlOperations::SetLabel(then_label)),
+            ],
+        );
+        code.push_str(&then_result.code);
+        debug_code.append(then_result.debug_code);
+        Self::emit_into(
+            &mut code,
+            &mut debug_code,
+            vec![IMP::FlowControl(FlowControlOperations::SetLabel(end_label))],
+        );
+        Ok(EvaluationResult {
+            code,
+            debug_code,
+            type_,
+        })
+    }
+
+    fn evaluate_binary_op(
+        &mut self,
+        operator: &Operation,
+        left: &Expression,
+        right: &Expression,
+        state: &mut state::State,
+        level: i8,
+    ) -> Result<EvaluationResult, Diagnostic> {
+        let mut code = String::new();
+        let mut debug_code = DebugCodeBlock::new(None, level);
+
+        let operation = match operator {
+            Operation::Add => IMP::Arithmetic(ArithmeticOperations::Add),
+            Operation::Sub => IMP::Arithmetic(ArithmeticOperations::Subtract),
+            Operation::Mul => IMP::Arithmetic(ArithmeticOperations::Multiply),
+            Operation::Div => IMP::Arithmetic(ArithmeticOperations::DivideInteger),
+            Operation::Mod => IMP::Arithmetic(ArithmeticOperations::Modulo),
+            _ => IMP::FlowControl(FlowControlOperations::Call(
+                built_in::bi_bitwise::operator_subroutine(operator, state),
+            )),
+        };
+
+        let left_result = self.evaluate_expression(left, state, level + 1)?;
+        let right_result = self.evaluate_expression(right, state, level + 1)?;
+        for (operand, result) in [(left, &left_result), (right, &right_result)] {
+            if !result.type_.is_numeric() {
+                return Err(Diagnostic::new(
+                    format!(
+                        "Operator {} is not supported for {}",
+                        operator, result.type_
+                    ),
+                    operand.span,
+                ));
             }
         }
+        code.push_str(&left_result.code);
+        code.push_str(&right_result.code);
+        debug_code.append(left_result.debug_code);
+        debug_code.append(right_result.debug_code);
+
+        let CodeOutput {
+            code: c,
+            debug_code: dc,
+        } = operation.gen();
+        code.push_str(&c);
+        debug_code.push(dc);
+        Ok(EvaluationResult {
+            code,
+            debug_code,
+            type_: VariableType::Int,
+        })
+    }
+
+    /// Pushes a number whose sign tells how the operands compare: `left - right` for integers,
+    /// the difference of the first mismatching characters for strings.
+    fn evaluate_comparison(
+        &mut self,
+        operator: &Operation,
+        left: &Expression,
+        right: &Expression,
+        state: &mut state::State,
+        level: i8,
+    ) -> Result<EvaluationResult, Diagnostic> {
+        let mut code = String::new();
+        let mut debug_code = DebugCodeBlock::new(None, level);
+
+        let left_result = self.evaluate_expression(left, state, level + 1)?;
+        let right_result = self.evaluate_expression(right, state, level + 1)?;
+        code.push_str(&left_result.code);
+        code.push_str(&right_result.code);
+        debug_code.append(left_result.debug_code);
+        debug_code.append(right_result.debug_code);
+
+        match (left_result.type_, right_result.type_) {
+            (left_type, right_type) if left_type.is_numeric() && right_type.is_numeric() => {
+                Self::emit_into(
+                    &mut code,
+                    &mut debug_code,
+                    vec![IMP::Arithmetic(ArithmeticOperations::Subtract)],
+                );
+            }
+            (left_type, right_type) if left_type.is_string() && right_type.is_string() => {
+                let compare_start_label = state.get_label();
+                let compare_same_label = state.get_label();
+                let compare_equal_label = state.get_label();
+                let compare_end_label = state.get_label();
+                Self::emit_into(
+                    &mut code,
+                    &mut debug_code,
+                    vec![
+                        IMP::FlowControl(FlowControlOperations::SetLabel(compare_start_label)),
+                        // Subtract the current characters
+                        IMP::Stack(StackOperations::CopyNth(1)),
+                        IMP::Heap(HeapOperations::Retrieve),
+                        IMP::Stack(StackOperations::CopyNth(1)),
+                        IMP::Heap(HeapOperations::Retrieve),
+                        IMP::Arithmetic(ArithmeticOperations::Subtract),
+                        IMP::Stack(StackOperations::Duplicate),
+                        IMP::FlowControl(FlowControlOperations::JumpIfZero(compare_same_label)),
+                        // Mismatch, the difference is the result
+                        IMP::Stack(StackOperations::Slide(2)),
+                        IMP::FlowControl(FlowControlOperations::Jump(compare_end_label)),
+                        IMP::FlowControl(FlowControlOperations::SetLabel(compare_same_label)),
+                        IMP::Stack(StackOperations::Discard),
+                        // Both strings ended
+                        IMP::Stack(StackOperations::CopyNth(1)),
+                        IMP::Heap(HeapOperations::Retrieve),
+                        IMP::FlowControl(FlowControlOperations::JumpIfZero(compare_equal_label)),
+                        // Advance both pointers
+                        IMP::Stack(StackOperations::PushNumber(HeapVariableSize::Char.size())),
+                        IMP::Arithmetic(ArithmeticOperations::Add),
+                        IMP::Stack(StackOperations::Swap),
+                        IMP::Stack(StackOperations::PushNumber(HeapVariableSize::Char.size())),
+                        IMP::Arithmetic(ArithmeticOperations::Add),
+                        IMP::Stack(StackOperations::Swap),
+                        IMP::FlowControl(FlowControlOperations::Jump(compare_start_label)),
+                        IMP::FlowControl(FlowControlOperations::SetLabel(compare_equal_label)),
+                        IMP::Stack(StackOperations::Discard),
+                        IMP::Stack(StackOperations::Discard),
+                        IMP::Stack(StackOperations::PushNumber(0)),
+                        IMP::FlowControl(FlowControlOperations::SetLabel(compare_end_label)),
+                    ],
+                );
+            }
+            (left_type, right_type) => {
+                return Err(Diagnostic::new(
+                    format!(
+                        "Operator {} cannot compare {} with {}",
+                        operator, left_type, right_type
+                    ),
+                    right.span,
+                ));
+            }
+        }
+
+        Ok(EvaluationResult {
+            code,
+            debug_code,
+            type_: VariableType::Int,
+        })
+    }
+
+    /// Consumes the result of [`CodeEmitter::evaluate_comparison`] and jumps to `label`
+    /// when the comparison holds.
+    fn comparison_jump(operator: &Operation, label: i32, state: &mut state::State) -> Vec<IMP> {
+        match operator {
+            Operation::CompareEquals => {
+                vec![IMP::FlowControl(FlowControlOperations::JumpIfZero(label))]
+            }
+            Operation::CompareNotEquals => {
+                let skip_label = state.get_label();
+                vec![
+                    IMP::FlowControl(FlowControlOperations::JumpIfZero(skip_label)),
+                    IMP::FlowControl(FlowControlOperations::Jump(label)),
+                    IMP::FlowControl(FlowControlOperations::SetLabel(skip_label)),
+                ]
+            }
+            Operation::CompareLessThan => {
+                vec![IMP::FlowControl(FlowControlOperations::JumpIfNegative(
+                    label,
+                ))]
+            }
+            // a <= b is a - b - 1 < 0
+            Operation::CompareLessOrEqual => vec![
+                IMP::Stack(StackOperations::PushNumber(1)),
+                IMP::Arithmetic(ArithmeticOperations::Subtract),
+                IMP::FlowControl(FlowControlOperations::JumpIfNegative(label)),
+            ],
+            // a > b is b - a < 0
+            Operation::CompareGreaterThan => vec![
+                IMP::Stack(StackOperations::PushNumber(-1)),
+                IMP::Arithmetic(ArithmeticOperations::Multiply),
+                IMP::FlowControl(FlowControlOperations::JumpIfNegative(label)),
+            ],
+            // a >= b is b - a - 1 < 0
+            _ => vec![
+                IMP::Stack(StackOperations::PushNumber(-1)),
+                IMP::Arithmetic(ArithmeticOperations::Multiply),
+                IMP::Stack(StackOperations::PushNumber(1)),
+                IMP::Arithmetic(ArithmeticOperations::Subtract),
+                IMP::FlowControl(FlowControlOperations::JumpIfNegative(label)),
+            ],
+        }
+    }
+
+    /// Evaluates a comparison used as a value, pushes 1 when it holds and 0 otherwise.
+    fn evaluate_comparison_value(
+        &mut self,
+        operator: &Operation,
+        left: &Expression,
+        right: &Expression,
+        state: &mut state::State,
+        level: i8,
+    ) -> Result<EvaluationResult, Diagnostic> {
+        let EvaluationResult {
+            mut code,
+            mut debug_code,
+            ..
+        } = self.evaluate_comparison(operator, left, right, state, level)?;
+        let true_label = state.get_label();
+        let end_label = state.get_label();
+        let mut instructions = Self::comparison_jump(operator, true_label, state);
+        instructions.extend([
+            IMP::Stack(StackOperations::PushNumber(0)),
+            IMP::FlowControl(FlowControlOperations::Jump(end_label)),
+            IMP::FlowControl(FlowControlOperations::SetLabel(true_label)),
+            IMP::Stack(StackOperations::PushNumber(1)),
+            IMP::FlowControl(FlowControlOperations::SetLabel(end_label)),
+        ]);
+        Self::emit_into(&mut code, &mut debug_code, instructions);
+        Ok(EvaluationResult {
+            code,
+            debug_code,
+            type_: VariableType::Int,
+        })
+    }
+
+    /// Emits code that jumps to `label` when the condition holds and falls through otherwise.
+    /// Comparisons jump on their result directly, any other integer expression holds when
+    /// it is not zero.
+    pub fn evaluate_condition(
+        &mut self,
+        condition: &Expression,
+        state: &mut state::State,
+        label: i32,
+    ) -> Result<CodeOutput, Diagnostic> {
+        let (code, debug_code) = self.evaluate_condition_block(condition, state, label, 0)?;
+        Ok(CodeOutput {
+            code,
+            debug_code: debug_code.render(),
+        })
+    }
+
+    /// Evaluates a condition that jumps to the label when it holds and falls through otherwise
+    fn evaluate_condition_block(
+        &mut self,
+        condition: &Expression,
+        state: &mut state::State,
+        label: i32,
+        level: i8,
+    ) -> Result<(String, DebugCodeBlock), Diagnostic> {
+        let (mut result, instructions) = match &condition.kind {
+            ExpressionKind::BinaryOp {
+                operator,
+                left,
+                right,
+            } if operator.is_comparison() => {
+                let result = self.evaluate_comparison(operator, left, right, state, level)?;
+                (result, Self::comparison_jump(operator, label, state))
+            }
+            _ => {
+                let result = self.evaluate_expression(condition, state, level)?;
+                if !result.type_.is_numeric() {
+                    return Err(Diagnostic::new(
+                        format!("Condition must be i32, found {}", result.type_),
+                        condition.span,
+                    ));
+                }
+                (
+                    result,
+                    Self::comparison_jump(&Operation::CompareNotEquals, label, state),
+                )
+            }
+        };
+        Self::emit_into(&mut result.code, &mut result.debug_code, instructions);
+        Ok((result.code, result.debug_code))
     }
 }
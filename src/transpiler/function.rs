@@ -0,0 +1,279 @@
+//! Functions written in the language, both by the program and in the prelude. Calls are
+//! expanded in place: the body is transpiled again at every call site, in a scope where the
+//! parameters are bound to the arguments. Functions that are never called produce no code,
+//! and a function cannot call itself, directly or through other functions.
+
+use crate::{
+    ast::{self, Expression, ExpressionKind, Function, ParameterType, StatementKind},
+    diagnostic::Diagnostic,
+    lexer::{Lexer, Span},
+    source::PRELUDE_FILE,
+    specs::whitespace::{FlowControlOperations, HeapOperations, StackOperations, IMP},
+};
+
+use super::{
+    built_in::{bi_str, check_arity, Parameter},
+    emitter::{CodeEmitter, EvaluationResult},
+    state::{Call, HeapVariable, State},
+    transpile_statements, CodeOutput, DebugCodeBlock, VariableType,
+};
+
+/// Library functions written in the language, see the Prelude section of the README. The
+/// language has no comments, so they are documented there. A function is only expanded where
+/// it is called, so the ones that are not used cost nothing.
+const PRELUDE: &str = include_str!("prelude.wsc");
+
+/// Parses the functions of the prelude
+pub fn prelude() -> Vec<Function> {
+    let tokens = Lexer::for_file(PRELUDE, PRELUDE_FILE).tokenize();
+    ast::parse(&tokens)
+        .expect("the prelude is valid")
+        .into_iter()
+        .map(|statement| match statement.kind {
+            StatementKind::Function(function) => function,
+            _ => unreachable!("the prelude only defines functions"),
+        })
+        .collect()
+}
+
+/// Expands a call of a function as a statement, a returned value is dropped
+pub fn call_statement(
+    name: &str,
+    args: &[Expression],
+    span: Span,
+    state: &mut State,
+    res: &mut CodeOutput,
+) -> Result<(), Diagnostic> {
+    let function = state.function(name).expect("function is defined");
+    call(&function, args, span, state, res)?;
+    if function.returns_value {
+        let mut emitter = CodeEmitter {};
+        res.append(emitter.emit(vec![IMP::Stack(StackOperations::Discard)]));
+    }
+    Ok(())
+}
+
+/// Expands a call of a function that returns a value inside of an expression
+pub fn evaluate(
+    name: &str,
+    args: &[Expression],
+    span: Span,
+    state: &mut State,
+    level: i8,
+) -> Result<EvaluationResult, Diagnostic> {
+    let function = state.function(name).expect("function is defined");
+    if !function.returns_value {
+        return Err(Diagnostic::new(
+            format!("{} does not return a value", name),
+            span,
+        ));
+    }
+    let mut res = CodeOutput::new();
+    call(&function, args, span, state, &mut res)?;
+    let mut debug_code = DebugCodeBlock::new(Some(format!("call {}", name)), level);
+    for line in res.debug_code.lines() {
+        debug_code.push(line.to_string());
+    }
+    Ok(EvaluationResult {
+        code: res.code,
+        debug_code,
+        type_: VariableType::Int,
+    })
+}
+
+/// Expands the body of the function, leaving the result on the stack when it returns a value
+fn call(
+    function: &Function,
+    args: &[Expression],
+    span: Span,
+    state: &mut State,
+    res: &mut CodeOutput,
+) -> Result<(), Diagnostic> {
+    check_arity(&function.name, function.parameters.len(), args, span)?;
+    if state.calls.iter().any(|call| call.name == function.name) {
+        return Err(Diagnostic::new(
+            format!("Recursive call of {} is not supported", function.name),
+            span,
+        ));
+    }
+    let mut emitter = CodeEmitter {};
+    res.add(String::new(), format!("# call {}", function.name));
+
+    // The arguments are evaluated in the scope of the caller. String variables are passed by
+    // reference, other values are pushed and stored into the parameters afterwards.
+    let mut references = vec![];
+    for ((_, type_), arg) in function.parameters.iter().zip(args) {
+        if let (ParameterType::String, ExpressionKind::Variable(name)) = (type_, &arg.kind) {
+            let variable = state.heap_allocation_map.lookup(name, arg.span)?;
+            if variable.type_().is_string() {
+                references.push(Some(variable.clone()));
+                continue;
+            }
+        }
+        let parameter = match type_ {
+            ParameterType::Int => Parameter::Int,
+            ParameterType::String => Parameter::String,
+            ParameterType::Pointer => Parameter::Pointer,
+        };
+        let result = emitter.evaluate_expression(arg, state, 0)?;
+        if !parameter.accepts(result.type_) {
+            return Err(Diagnostic::new(
+                format!(
+                    "{} expects {} as argument, found {}",
+                    function.name,
+                    parameter,
+                    state.type_name(result.type_)
+                ),
+                arg.span,
+            ));
+        }
+        res.add(result.code, result.debug_code.render());
+        references.push(None);
+    }
+
+    state.heap_allocation_map.enter_function();
+    let result = bind_parameters(function, references, span, state, res)
+        .and_then(|()| expand_body(function, state, res));
+    state.heap_allocation_map.exit_function();
+    result
+}
+
+/// Binds the parameters to the arguments, the values of the arguments that are not passed by
+/// reference are on the stack with the last one on top
+fn bind_parameters(
+    function: &Function,
+    references: Vec<Option<HeapVariable>>,
+    span: Span,
+    state: &mut State,
+    res: &mut CodeOutput,
+) -> Result<(), Diagnostic> {
+    let mut emitter = CodeEmitter {};
+    for ((name, type_), reference) in function.parameters.iter().zip(references).rev() {
+        if let Some(variable) = reference {
+            state.heap_allocation_map.bind(name, variable);
+            continue;
+        }
+        let variable_type = match type_ {
+            ParameterType::Int => VariableType::Int,
+            // Strings that are not variables are copied into a growable string, whose blocks
+            // are never returned to the allocator
+            ParameterType::String => VariableType::Str,
+            ParameterType::Pointer => VariableType::Pointer,
+        };
+        let addr = state
+            .heap_allocation_map
+            .declare(name, variable_type, span)?;
+        if variable_type == VariableType::Str {
+            res.append(emitter.emit(bi_str::declare(state, addr)));
+            res.append(emitter.emit(vec![
+                IMP::Stack(StackOperations::PushNumber(addr)),
+                IMP::Stack(StackOperations::Swap),
+            ]));
+            res.append(emitter.emit(bi_str::assign(state)));
+        } else {
+            res.append(emitter.emit(vec![
+                IMP::Stack(StackOperations::PushNumber(addr)),
+                IMP::Stack(StackOperations::Swap),
+                IMP::Heap(HeapOperations::Store),
+            ]));
+        }
+    }
+    Ok(())
+}
+
+fn expand_body(
+    function: &Function,
+    state: &mut State,
+    res: &mut CodeOutput,
+) -> Result<(), Diagnostic> {
+    let mut emitter = CodeEmitter {};
+    let end_label = state.get_label();
+    let result = function.returns_value.then(|| {
+        let addr = state.heap_allocation_map.reserve(VariableType::Int);
+        // A function that ends without return returns 0
+        res.append(emitter.emit(vec![
+            IMP::Stack(StackOperations::PushNumber(addr)),
+            IMP::Stack(StackOperations::PushNumber(0)),
+            IMP::Heap(HeapOperations::Store),
+        ]));
+        addr
+    });
+    state.enter_labels(&function.body)?;
+    state.calls.push(Call {
+        name: function.name.clone(),
+        end_label,
+        result,
+    });
+    let body = transpile_statements(&function.body, state, res);
+    state.exit_labels();
+    state.calls.pop();
+    body?;
+    res.append(
+        emitter.emit(vec![IMP::FlowControl(FlowControlOperations::SetLabel(
+            end_label,
+        ))]),
+    );
+    if let Some(addr) = result {
+        res.append(emitter.emit(vec![
+            IMP::Stack(StackOperations::PushNumber(addr)),
+            IMP::Heap(HeapOperations::Retrieve),
+        ]));
+    }
+    Ok(())
+}
+
+/// `return` stores the value of the innermost call and jumps past the end of its body
+pub fn return_statement(
+    value: Option<&Expression>,
+    span: Span,
+    state: &mut State,
+    res: &mut CodeOutput,
+) -> Result<(), Diagnostic> {
+    let Some(Call {
+        name,
+        end_label,
+        result,
+    }) = state.calls.last()
+    else {
+        return Err(Diagnostic::new("return outside of a function", span));
+    };
+    let (name, end_label, result) = (name.clone(), *end_label, *result);
+    let mut emitter = CodeEmitter {};
+    match (value, result) {
+        (Some(value), Some(addr)) => {
+            let evaluated = emitter.evaluate_expression(value, state, 0)?;
+            if !VariableType::Int.stores(evaluated.type_) {
+                return Err(Diagnostic::new(
+                    format!(
+                        "{} returns i32, found {}",
+                        name,
+                        state.type_name(evaluated.type_)
+                    ),
+                    value.span,
+                ));
+            }
+            res.append(emitter.emit(vec![IMP::Stack(StackOperations::PushNumber(addr))]));
+            res.add(evaluated.code, evaluated.debug_code.render());
+            res.append(emitter.emit(vec![IMP::Heap(HeapOperations::Store)]));
+        }
+        (None, None) => {}
+        (Some(value), None) => {
+            return Err(Diagnostic::new(
+                format!("{} does not return a value", name),
+                value.span,
+            ));
+        }
+        (None, Some(_)) => {
+            return Err(Diagnostic::new(
+                format!("{} must return a value", name),
+                span,
+            ));
+        }
+    }
+    res.append(
+        emitter.emit(vec![IMP::FlowControl(FlowControlOperations::Jump(
+            end_label,
+        ))]),
+    );
+    Ok(())
+}
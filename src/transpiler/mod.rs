@@ -1,11 +1,18 @@
+mod asm;
 mod built_in;
 mod emitter;
+mod function;
 mod state;
+#[cfg(test)]
 mod tests;
 
 use crate::ast::Expression;
+use crate::ast::ExpressionKind;
+use crate::ast::MapKey;
 use crate::ast::Operation;
 use crate::ast::Statement;
+use crate::ast::StatementKind;
+use crate::diagnostic::Diagnostic;
 
 use crate::specs::whitespace::ArithmeticOperations;
 use crate::specs::whitespace::FlowControlOperations;
@@ -13,6 +20,8 @@ use crate::specs::whitespace::HeapOperations;
 use crate::specs::whitespace::StackOperations;
 use crate::specs::whitespace::IMP;
 
+pub use state::{Options, State};
+
 pub struct CodeOutput {
     pub code: String,
     pub debug_code: String,
@@ -35,14 +44,16 @@ impl CodeOutput {
         self.code.push_str(&code.code);
         self.debug_code.push_str(&code.debug_code);
     }
+
+    pub fn append_imp(&mut self, imp: IMP) {
+        let CodeOutput { code, debug_code } = imp.gen();
+        self.add(code, debug_code);
+    }
 }
 
-#[derive()]
 enum HeapVariableSize {
     Int,
-    Float,
     Char,
-    Bool,
     String(usize),
 }
 
@@ -50,31 +61,68 @@ impl HeapVariableSize {
     fn size(&self) -> i32 {
         match self {
             HeapVariableSize::Int => 4,
-            HeapVariableSize::Float => 4,
             HeapVariableSize::Char => 1,
-            HeapVariableSize::Bool => 1,
             HeapVariableSize::String(size) => (*size) as i32 * Self::Char.size(),
         }
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq)]
 pub enum VariableType {
     Int,
-    String(usize),
-    Float,
     Char,
-    Bool,
+    String(usize),
+    /// Index of the enum definition in the state
+    Enum(usize),
+    /// Address of a block from `alloc`, indexing it reads and writes single heap cells
+    Pointer,
+    /// Hash table on the heap, the variable holds the address of the table. Maps can only be
+    /// used through the map built-ins.
+    Map(MapKey),
+    /// Growable string. The variable holds the address of a heap block with the length,
+    /// the capacity and the characters, which is replaced by a larger one when it fills up.
+    Str,
+}
+
+impl VariableType {
+    /// Chars are stored as their code points, enums as the values of their variants and
+    /// pointers as addresses. All of them can be used wherever integers are expected.
+    pub fn is_numeric(&self) -> bool {
+        matches!(
+            self,
+            VariableType::Int | VariableType::Char | VariableType::Enum(_) | VariableType::Pointer
+        )
+    }
+
+    /// Strings of both kinds are passed around by the address of their first character
+    pub fn is_string(&self) -> bool {
+        matches!(self, VariableType::String(_) | VariableType::Str)
+    }
+
+    /// Whether a value can be assigned to a numeric variable of this type. Enum variables
+    /// take plain integers too, but not the values of other enums. Pointers only come from
+    /// `alloc` and other pointers.
+    pub fn stores(&self, type_: VariableType) -> bool {
+        match self {
+            VariableType::Int => type_.is_numeric(),
+            VariableType::Enum(_) => type_ == VariableType::Int || type_ == *self,
+            VariableType::Pointer => type_ == VariableType::Pointer,
+            _ => false,
+        }
+    }
 }
 
 impl std::fmt::Display for VariableType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             VariableType::Int => write!(f, "i32"),
-            VariableType::String(length) => write!(f, "String[{}]", length),
-            VariableType::Float => write!(f, "f32"),
             VariableType::Char => write!(f, "char"),
-            VariableType::Bool => write!(f, "bool"),
+            VariableType::String(length) => write!(f, "String[{}]", length),
+            VariableType::Enum(_) => write!(f, "enum"),
+            VariableType::Pointer => write!(f, "ptr"),
+            VariableType::Str => write!(f, "str"),
+            VariableType::Map(MapKey::Int) => write!(f, "map<i32, i32>"),
+            VariableType::Map(MapKey::String) => write!(f, "map<String, i32>"),
         }
     }
 }
@@ -132,7 +180,7 @@ impl DebugCodeBlock {
                 }
             }
         }
-        if let Some(_) = &self.name {
+        if self.name.is_some() {
             result.push_str(&format!(
                 "{:indent$}}}\n",
                 "",
@@ -143,211 +191,545 @@ impl DebugCodeBlock {
     }
 }
 
-pub fn transpile(ast: Vec<Statement>, state: Option<state::State>) -> CodeOutput {
+/// Compiles the program, also returning the warnings about code that compiles but may not
+/// behave as intended
+pub fn transpile_with_warnings(
+    ast: Vec<Statement>,
+    state: Option<state::State>,
+) -> Result<(CodeOutput, Vec<Diagnostic>), Diagnostic> {
     let mut res: CodeOutput = CodeOutput::new();
-    let mut state: state::State = state.unwrap_or(state::State::new());
-    let mut emitter = emitter::CodeEmitter {};
+    let mut state: state::State = state.unwrap_or_else(state::State::new);
+    let ast = crate::macros::expand(ast)?;
+
+    // Functions can be called before their definition
+    let mut program = vec![];
+    for statement in ast {
+        match statement.kind {
+            StatementKind::Function(function) => state.define_function(function, statement.span)?,
+            kind => program.push(Statement::new(kind, statement.span)),
+        }
+    }
+    state.enter_labels(&program)?;
+    transpile_statements(&program, &mut state, &mut res)?;
+    state.exit_labels();
+
+    if let Some(prologue) = state.take_prologue() {
+        let mut code = CodeOutput::new();
+        code.add(String::new(), "# prologue".to_string());
+        code.append(prologue);
+        code.append(res);
+        res = code;
+    }
+
+    if let Some(subroutines) = state.take_subroutines() {
+        // Keep the main program from running into the subroutines
+        res.add(String::new(), "# subroutines".to_string());
+        res.append_imp(IMP::FlowControl(FlowControlOperations::Exit));
+        res.append(subroutines);
+    }
+
+    Ok((res, state.warnings))
+}
 
+fn transpile_statements(
+    ast: &[Statement],
+    state: &mut state::State,
+    res: &mut CodeOutput,
+) -> Result<(), Diagnostic> {
     for node in ast {
-        match node {
-            Statement::IntDeclaration(name) => {
-                state.heap_allocation_map.allocate(name, VariableType::Int);
+        transpile_statement(node, state, res)?;
+    }
+    Ok(())
+}
+
+/// Sets a declared number to 0 or a declared string to the empty string, both are a single 0
+/// at the address of the variable
+fn zero_initialize(addr: i32, res: &mut CodeOutput) {
+    let mut emitter = emitter::CodeEmitter {};
+    res.append(emitter.emit(vec![
+        IMP::Stack(StackOperations::PushNumber(addr)),
+        IMP::Stack(StackOperations::PushNumber(0)),
+        IMP::Heap(HeapOperations::Store),
+    ]));
+}
+
+fn transpile_statement(
+    node: &Statement,
+    state: &mut state::State,
+    res: &mut CodeOutput,
+) -> Result<(), Diagnostic> {
+    transpile_node(node, state, res, state.options.zero_initialize)
+}
+
+/// Transpiles a statement, `zero` tells whether a declaration it makes is zeroed
+fn transpile_node(
+    node: &Statement,
+    state: &mut state::State,
+    res: &mut CodeOutput,
+    zero: bool,
+) -> Result<(), Diagnostic> {
+    let mut emitter = emitter::CodeEmitter {};
+
+    if !matches!(
+        node.kind,
+        StatementKind::Block(_) | StatementKind::Uninitialized(_)
+    ) {
+        // Every instruction up to the next marker belongs to the statement at this location
+        res.add(String::new(), format!("# {}", node.span));
+    }
+
+    match &node.kind {
+        StatementKind::IntDeclaration(name) => {
+            let addr = state
+                .heap_allocation_map
+                .declare(name, VariableType::Int, node.span)?;
+            if zero {
+                zero_initialize(addr, res);
+            }
+        }
+        StatementKind::StringDeclaration(name, length) => {
+            let addr = state.heap_allocation_map.declare(
+                name,
+                VariableType::String(*length),
+                node.span,
+            )?;
+            if zero {
+                zero_initialize(addr, res);
             }
-            Statement::StringDeclaration(name, length) => {
+        }
+        StatementKind::StrDeclaration(name) => {
+            let addr = state
+                .heap_allocation_map
+                .declare(name, VariableType::Str, node.span)?;
+            res.append(emitter.emit(built_in::bi_str::declare(state, addr)));
+        }
+        StatementKind::MapDeclaration(name, key) => {
+            let addr =
                 state
                     .heap_allocation_map
-                    .allocate(name, VariableType::String(length));
+                    .declare(name, VariableType::Map(*key), node.span)?;
+            res.append(emitter.emit(built_in::bi_map::declare(state, addr)));
+        }
+        StatementKind::PointerDeclaration(name) => {
+            let addr = state
+                .heap_allocation_map
+                .declare(name, VariableType::Pointer, node.span)?;
+            if zero {
+                zero_initialize(addr, res);
             }
-            Statement::Assignment(name, value) => match value {
-                Expression::Integer(value) => {
-                    let CodeOutput { code, debug_code } =
-                        IMP::Stack(StackOperations::PushNumber(value)).gen();
-                    res.add(code, debug_code);
-
-                    let addr = state.heap_allocation_map.get(&name).unwrap().offset();
-                    let CodeOutput { code, debug_code } =
-                        IMP::Stack(StackOperations::PushNumber(addr)).gen();
-                    res.add(code, debug_code);
-                    let CodeOutput { code, debug_code } = IMP::Stack(StackOperations::Swap).gen();
-                    res.add(code, debug_code);
-                    let CodeOutput { code, debug_code } = IMP::Heap(HeapOperations::Store).gen();
-                    res.add(code, debug_code);
+        }
+        StatementKind::EnumDefinition(name, variants) => {
+            state.declare_enum(name, variants, node.span)?;
+        }
+        StatementKind::EnumDeclaration(type_name, name) => {
+            let type_ = state.lookup_enum(type_name, node.span)?;
+            let addr = state.heap_allocation_map.declare(name, type_, node.span)?;
+            if zero {
+                zero_initialize(addr, res);
+            }
+        }
+        StatementKind::Assignment(name, value) => {
+            let variable = state.heap_allocation_map.lookup(name, node.span)?;
+            let addr = variable.offset();
+            let target_type = variable.type_();
+            match (target_type, &value.kind) {
+                (VariableType::Str, _) => {
+                    let emitter::EvaluationResult {
+                        code,
+                        debug_code,
+                        type_,
+                    } = emitter.evaluate_expression(value, state, 0)?;
+                    if !type_.is_string() {
+                        return Err(Diagnostic::new(
+                            format!(
+                                "Cannot assign {} to {} of type {}",
+                                state.type_name(type_),
+                                name,
+                                target_type
+                            ),
+                            value.span,
+                        ));
+                    }
+                    res.append(emitter.emit(vec![IMP::Stack(StackOperations::PushNumber(addr))]));
+                    res.add(code, debug_code.render());
+                    res.append(emitter.emit(built_in::bi_str::assign(state)));
                 }
-                Expression::Literal(value) => {
+                (VariableType::String(length), ExpressionKind::Literal(value)) => {
+                    // Literals are written as they are, they must leave room for the terminator
+                    if value.chars().count() >= length {
+                        let literal_type = VariableType::String(value.chars().count() + 1);
+                        return Err(Diagnostic::new(
+                            format!(
+                                "Cannot assign {} to {} of type {}",
+                                literal_type, name, target_type
+                            ),
+                            node.span,
+                        ));
+                    }
                     res.add("".to_string(), "# write string literal".to_string());
-                    let mut addr = state.heap_allocation_map.get(&name).unwrap().offset();
+                    let mut addr = addr;
                     for ch in value.chars() {
-                        let CodeOutput { code, debug_code } =
-                            IMP::Stack(StackOperations::PushNumber(addr)).gen();
-                        res.add(code, debug_code);
-                        let CodeOutput { code, debug_code } =
-                            IMP::Stack(StackOperations::PushNumber(ch as i32)).gen();
-                        res.add(code, debug_code);
-                        let CodeOutput { code, debug_code } =
-                            IMP::Heap(HeapOperations::Store).gen();
-                        res.add(code, debug_code);
+                        res.append(emitter.emit(vec![
+                            IMP::Stack(StackOperations::PushNumber(addr)),
+                            IMP::Stack(StackOperations::PushNumber(ch as i32)),
+                            IMP::Heap(HeapOperations::Store),
+                        ]));
                         addr += HeapVariableSize::Char.size();
                     }
-                    let CodeOutput { code, debug_code } =
-                        IMP::Stack(StackOperations::PushNumber(addr)).gen();
-                    res.add(code, debug_code);
-                    let CodeOutput { code, debug_code } =
-                        IMP::Stack(StackOperations::PushNumber(0)).gen();
-                    res.add(code, debug_code);
-                    let CodeOutput { code, debug_code } = IMP::Heap(HeapOperations::Store).gen();
-                    res.add(code, debug_code);
+                    res.append(emitter.emit(vec![
+                        IMP::Stack(StackOperations::PushNumber(addr)),
+                        IMP::Stack(StackOperations::PushNumber(0)),
+                        IMP::Heap(HeapOperations::Store),
+                    ]));
                     res.add("".to_string(), "".to_string());
                 }
-                Expression::BinaryOp {
-                    operator,
-                    left,
-                    right,
-                } => {
-                    let (code, debug_code, ..) = emitter.evaluate_expression(
-                        &Expression::BinaryOp {
-                            operator,
-                            left,
-                            right,
-                        },
-                        &state.heap_allocation_map,
-                        0,
-                    );
-                    res.add(code, debug_code.render());
-
-                    let addr = state.heap_allocation_map.get(&name).unwrap().offset();
-                    let CodeOutput { code, debug_code } =
-                        IMP::Stack(StackOperations::PushNumber(addr)).gen();
-                    res.add(code, debug_code);
-                    let CodeOutput { code, debug_code } = IMP::Stack(StackOperations::Swap).gen();
-                    res.add(code, debug_code);
-                    let CodeOutput { code, debug_code } = IMP::Heap(HeapOperations::Store).gen();
-                    res.add(code, debug_code);
-                }
-                _ => {
-                    panic!("Unsupported expression");
+                (_, ExpressionKind::Literal(literal)) => {
+                    return Err(Diagnostic::new(
+                        format!(
+                            "Cannot assign {} to {} of type {}",
+                            VariableType::String(literal.chars().count() + 1),
+                            name,
+                            target_type
+                        ),
+                        value.span,
+                    ));
                 }
-            },
-            Statement::Call(name, args) => match name.as_str() {
-                "read" => built_in::bi_io::read(&mut state, &args, &mut res),
-                "concat" => built_in::bi_string::concat(&mut state, &args, &mut res),
-                "print" => built_in::bi_io::print(&mut state, &args, &mut res),
                 _ => {
-                    panic!("Unsupported function");
-                }
-            },
-            Statement::Exit => {
-                let CodeOutput { code, debug_code } =
-                    IMP::FlowControl(FlowControlOperations::Exit).gen();
-                res.add(code, debug_code);
-            }
-            Statement::WhileLoop { condition, body } => {
-                let loop_start_label = state.get_label();
-                let loop_body_start_label = state.get_label();
-                let loop_end_label = state.get_label();
-                let CodeOutput { code, debug_code } =
-                    IMP::FlowControl(FlowControlOperations::SetLabel(loop_start_label)).gen();
-                res.add(code, debug_code);
-                match *condition {
-                    Expression::BinaryOp {
-                        operator,
-                        left,
-                        right,
-                    } => match operator {
-                        Operation::CompareEquals => {
-                            let left_result =
-                                emitter.evaluate_expression(&left, &state.heap_allocation_map, 0);
-                            let right_result =
-                                emitter.evaluate_expression(&right, &state.heap_allocation_map, 0);
-                            let (left_code, left_debug_code, left_type, left_size) = left_result;
-                            let (right_code, right_debug_code, right_type, right_size) =
-                                right_result;
-                            res.add(left_code, left_debug_code.render());
-                            res.add(right_code, right_debug_code.render());
-                            match left_type {
-                                VariableType::Int => {
-                                    let CodeOutput {
-                                        code: c,
-                                        debug_code: dc,
-                                    } = IMP::Arithmetic(ArithmeticOperations::Subtract).gen();
-                                    res.add(c, dc);
-                                }
-                                _ => {
-                                    panic!("Only integer values are supported for now");
-                                }
-                            }
-                            let c2 = IMP::FlowControl(FlowControlOperations::JumpIfZero(
-                                loop_body_start_label,
-                            ))
-                            .gen();
-                            res.add(c2.code, c2.debug_code);
+                    let emitter::EvaluationResult {
+                        code,
+                        debug_code,
+                        type_,
+                    } = emitter.evaluate_expression(value, state, 0)?;
+                    match (target_type, type_) {
+                        (target_type, type_) if target_type.stores(type_) => {
+                            res.add(code, debug_code.render());
+                            res.append(emitter.emit(vec![
+                                IMP::Stack(StackOperations::PushNumber(addr)),
+                                IMP::Stack(StackOperations::Swap),
+                                IMP::Heap(HeapOperations::Store),
+                            ]));
                         }
-                        Operation::CompareLessThan => {
-                            let left_result =
-                                emitter.evaluate_expression(&left, &state.heap_allocation_map, 0);
-                            let right_result =
-                                emitter.evaluate_expression(&right, &state.heap_allocation_map, 0);
-                            let (left_code, left_debug_code, left_type, left_size) = left_result;
-                            let (right_code, right_debug_code, right_type, right_size) =
-                                right_result;
-                            res.add(left_code, left_debug_code.render());
-                            res.add(right_code, right_debug_code.render());
-                            match left_type {
-                                VariableType::Int => {
-                                    let CodeOutput {
-                                        code: c,
-                                        debug_code: dc,
-                                    } = IMP::Arithmetic(ArithmeticOperations::Subtract).gen();
-                                    res.add(c, dc);
-                                }
-                                _ => {
-                                    panic!("Only integer values are supported for now");
-                                }
-                            }
-                            let c2 = IMP::FlowControl(FlowControlOperations::JumpIfNegative(
-                                loop_body_start_label,
-                            ))
-                            .gen();
-                            res.add(c2.code, c2.debug_code);
+                        (VariableType::String(length), type_) if type_.is_string() => {
+                            res.add("".to_string(), "# copy string".to_string());
+                            res.append(
+                                emitter.emit(vec![IMP::Stack(StackOperations::PushNumber(addr))]),
+                            );
+                            res.add(code, debug_code.render());
+                            res.append(emitter.emit(built_in::bi_string::copy(state, length)));
                         }
                         _ => {
-                            panic!("Unsupported operator");
+                            return Err(Diagnostic::new(
+                                format!(
+                                    "Cannot assign {} to {} of type {}",
+                                    state.type_name(type_),
+                                    name,
+                                    state.type_name(target_type)
+                                ),
+                                value.span,
+                            ));
                         }
-                    },
-                    _ => {
-                        panic!("Unsupported condition");
                     }
                 }
-                let CodeOutput { code, debug_code } =
-                    IMP::FlowControl(FlowControlOperations::Jump(loop_end_label)).gen();
-                res.add(code, debug_code);
-                let CodeOutput { code, debug_code } =
-                    IMP::FlowControl(FlowControlOperations::SetLabel(loop_body_start_label)).gen();
-                res.add(code, debug_code);
-                match *body {
-                    Statement::Block(statements) => {
-                        let CodeOutput { code, debug_code } =
-                            transpile(statements, Some(state.clone()));
-                        res.add(code, debug_code);
-                    }
-                    _ => {
-                        panic!("Unsupported statement");
-                    }
+            }
+        }
+        StatementKind::CompoundAssignment(name, operator, value) => {
+            let variable = state.heap_allocation_map.lookup(name, node.span)?;
+            let addr = variable.offset();
+            let target_type = variable.type_();
+            let emitter::EvaluationResult {
+                code,
+                debug_code,
+                type_,
+            } = emitter.evaluate_expression(value, state, 0)?;
+            for (type_, span) in [(target_type, node.span), (type_, value.span)] {
+                if !type_.is_numeric() {
+                    return Err(Diagnostic::new(
+                        format!("Operator {}= is not supported for {}", operator, type_),
+                        span,
+                    ));
                 }
-                let CodeOutput { code, debug_code } =
-                    IMP::FlowControl(FlowControlOperations::Jump(loop_start_label)).gen();
-                res.add(code, debug_code);
-                let CodeOutput { code, debug_code } =
-                    IMP::FlowControl(FlowControlOperations::SetLabel(loop_end_label)).gen();
-                res.add(code, debug_code);
+            }
+            // The address stays on the stack for the store
+            res.append(emitter.emit(vec![
+                IMP::Stack(StackOperations::PushNumber(addr)),
+                IMP::Stack(StackOperations::Duplicate),
+                IMP::Heap(HeapOperations::Retrieve),
+            ]));
+            res.add(code, debug_code.render());
+            res.append(emitter.emit(vec![
+                IMP::Arithmetic(compound_operation(operator)),
+                IMP::Heap(HeapOperations::Store),
+            ]));
+        }
+        StatementKind::IndexAssignment {
+            name,
+            index,
+            operator,
+            value,
+        } => {
+            let (address_code, address_debug_code) =
+                built_in::bi_memory::element_address(name, index, node.span, state, 0)?;
+            let emitter::EvaluationResult {
+                code,
+                debug_code,
+                type_,
+            } = emitter.evaluate_expression(value, state, 0)?;
+            if !VariableType::Int.stores(type_) {
+                let message = match operator {
+                    Some(operator) => {
+                        format!("Operator {}= is not supported for {}", operator, type_)
+                    }
+                    None => format!(
+                        "Cannot assign {} to {}[{}] of type {}",
+                        state.type_name(type_),
+                        name,
+                        index,
+                        VariableType::Int
+                    ),
+                };
+                return Err(Diagnostic::new(message, value.span));
+            }
+            res.add(address_code, address_debug_code.render());
+            if operator.is_some() {
+                res.append(emitter.emit(vec![
+                    IMP::Stack(StackOperations::Duplicate),
+                    IMP::Heap(HeapOperations::Retrieve),
+                ]));
+            }
+            res.add(code, debug_code.render());
+            if let Some(operator) = operator {
+                res.append(emitter.emit(vec![IMP::Arithmetic(compound_operation(operator))]));
+            }
+            res.append(emitter.emit(vec![IMP::Heap(HeapOperations::Store)]));
+        }
+        StatementKind::Call(name, args) => match name.as_str() {
+            // Functions of the program take precedence over the built-ins
+            _ if state.function(name).is_some() => {
+                function::call_statement(name, args, node.span, state, res)?
+            }
+            "read" => built_in::bi_io::read(state, args, node.span, res)?,
+            "concat" => built_in::bi_string::concat(state, args, node.span, res)?,
+            "print" => built_in::bi_io::print(state, args, node.span, res)?,
+            "println" => built_in::bi_io::println(state, args, node.span, res)?,
+            "eprint" => built_in::bi_io::eprint(state, args, node.span, res, false)?,
+            "eprintln" => built_in::bi_io::eprint(state, args, node.span, res, true)?,
+            "substr" => built_in::bi_string::substr(state, args, node.span, res)?,
+            "to_upper" => built_in::bi_string::to_upper(state, args, node.span, res)?,
+            "to_lower" => built_in::bi_string::to_lower(state, args, node.span, res)?,
+            "reverse" => built_in::bi_string::reverse(state, args, node.span, res)?,
+            "trim" => built_in::bi_string::trim(state, args, node.span, res)?,
+            "to_string" => built_in::bi_convert::to_string(state, args, node.span, res)?,
+            "seed" => built_in::bi_random::seed(state, args, node.span, res)?,
+            "free" => built_in::bi_memory::free(state, args, node.span, res)?,
+            "put" => built_in::bi_map::put(state, args, node.span, res)?,
+            "remove" => built_in::bi_map::remove(state, args, node.span, res)?,
+            _ if state.prelude_function(name).is_some() => {
+                function::call_statement(name, args, node.span, state, res)?
             }
             _ => {
-                println!("{:?}", node);
-                panic!("Unsupported statement");
+                // Functions that return a value can be called for their side effects only
+                let emitter::EvaluationResult {
+                    code, debug_code, ..
+                } = built_in::evaluate_call(name, args, node.span, state, 0)?;
+                res.add(code, debug_code.render());
+                res.append(emitter.emit(vec![IMP::Stack(StackOperations::Discard)]));
+            }
+        },
+        StatementKind::Exit => {
+            res.append(emitter.emit(vec![IMP::FlowControl(FlowControlOperations::Exit)]));
+        }
+        StatementKind::Function(_) => {
+            return Err(Diagnostic::new(
+                "Functions can only be defined at the top level",
+                node.span,
+            ));
+        }
+        StatementKind::Return(value) => {
+            function::return_statement(value.as_ref(), node.span, state, res)?
+        }
+        StatementKind::Import(_) => {
+            // Imports at the top level of a file are resolved by the loader
+            return Err(Diagnostic::new(
+                "Imports are only allowed at the top level of a file",
+                node.span,
+            ));
+        }
+        StatementKind::WhileLoop { condition, body } => {
+            let loop_start_label = state.get_label();
+            let loop_body_start_label = state.get_label();
+            let loop_end_label = state.get_label();
+            res.append(
+                emitter.emit(vec![IMP::FlowControl(FlowControlOperations::SetLabel(
+                    loop_start_label,
+                ))]),
+            );
+            res.append(emitter.evaluate_condition(condition, state, loop_body_start_label)?);
+            res.append(emitter.emit(vec![
+                IMP::FlowControl(FlowControlOperations::Jump(loop_end_label)),
+                IMP::FlowControl(FlowControlOperations::SetLabel(loop_body_start_label)),
+            ]));
+            transpile_statement(body, state, res)?;
+            res.append(emitter.emit(vec![
+                IMP::FlowControl(FlowControlOperations::Jump(loop_start_label)),
+                IMP::FlowControl(FlowControlOperations::SetLabel(loop_end_label)),
+            ]));
+        }
+        StatementKind::Switch {
+            value,
+            cases,
+            default,
+        } => {
+            let emitter::EvaluationResult {
+                code,
+                debug_code,
+                type_,
+            } = emitter.evaluate_expression(value, state, 0)?;
+            if !type_.is_numeric() {
+                return Err(Diagnostic::new(
+                    format!("Switch value must be i32, found {}", type_),
+                    value.span,
+                ));
             }
+            res.add(code, debug_code.render());
+
+            let default_label = state.get_label();
+            let end_label = state.get_label();
+            let mut labels: Vec<(i32, i32)> = vec![];
+            for case in cases {
+                let value = case_value(&case.value, state)?;
+                if labels.iter().any(|(other, _)| *other == value) {
+                    return Err(Diagnostic::new(
+                        format!("Duplicate case value {}", value),
+                        case.value.span,
+                    ));
+                }
+                labels.push((value, state.get_label()));
+            }
+            let case_labels: Vec<i32> = labels.iter().map(|(_, label)| *label).collect();
+            labels.sort();
+            res.append(emitter.emit(switch_dispatch(&labels, default_label, state)));
+
+            // The value stays on the stack until a case is chosen
+            for (case, label) in cases.iter().zip(case_labels) {
+                res.append(emitter.emit(vec![
+                    IMP::FlowControl(FlowControlOperations::SetLabel(label)),
+                    IMP::Stack(StackOperations::Discard),
+                ]));
+                transpile_statement(&case.body, state, res)?;
+                res.append(
+                    emitter.emit(vec![IMP::FlowControl(FlowControlOperations::Jump(
+                        end_label,
+                    ))]),
+                );
+            }
+            res.append(emitter.emit(vec![
+                IMP::FlowControl(FlowControlOperations::SetLabel(default_label)),
+                IMP::Stack(StackOperations::Discard),
+            ]));
+            if let Some(default) = default {
+                transpile_statement(default, state, res)?;
+            }
+            res.append(
+                emitter.emit(vec![IMP::FlowControl(FlowControlOperations::SetLabel(
+                    end_label,
+                ))]),
+            );
+        }
+        StatementKind::Block(statements) => {
+            state.heap_allocation_map.enter_scope();
+            let result = transpile_statements(statements, state, res);
+            state.heap_allocation_map.exit_scope();
+            result?;
+        }
+        StatementKind::Expansion(name, statements) => {
+            // The body of a macro is a block of its own, errors also point at the call
+            state.heap_allocation_map.enter_scope();
+            let result = transpile_statements(statements, state, res);
+            state.heap_allocation_map.exit_scope();
+            result.map_err(|err| {
+                err.with_note(format!("in expansion of macro {}", name), node.span)
+            })?;
         }
+        StatementKind::Label(name) => {
+            let label = state.label(name, node.span)?;
+            res.append(
+                emitter.emit(vec![IMP::FlowControl(FlowControlOperations::SetLabel(
+                    label,
+                ))]),
+            );
+        }
+        StatementKind::Goto(name) => {
+            let label = state.label(name, node.span)?;
+            res.append(emitter.emit(vec![IMP::FlowControl(FlowControlOperations::Jump(label))]));
+        }
+        StatementKind::Uninitialized(declaration) => {
+            transpile_node(declaration, state, res, false)?
+        }
+        StatementKind::Asm(instructions) => asm::asm(instructions, state, res)?,
+        StatementKind::Macro(_) => unreachable!("macros are expanded before transpiling"),
     }
-    CodeOutput {
-        code: res.code,
-        debug_code: res.debug_code,
+    Ok(())
+}
+
+/// Arithmetic behind `a op= b`
+fn compound_operation(operator: &Operation) -> ArithmeticOperations {
+    match operator {
+        Operation::Add => ArithmeticOperations::Add,
+        Operation::Sub => ArithmeticOperations::Subtract,
+        Operation::Mul => ArithmeticOperations::Multiply,
+        Operation::Div => ArithmeticOperations::DivideInteger,
+        _ => ArithmeticOperations::Modulo,
+    }
+}
+
+/// Returns the value of a switch case, which has to be known at compile time
+fn case_value(value: &Expression, state: &state::State) -> Result<i32, Diagnostic> {
+    match &value.kind {
+        ExpressionKind::Integer(value) => Ok(*value),
+        ExpressionKind::Character(value) => Ok(*value as i32),
+        ExpressionKind::EnumVariant(name, variant) => state
+            .lookup_variant(name, variant, value.span)
+            .map(|(_, value)| value),
+        _ => Err(Diagnostic::new(
+            format!("Case value must be a constant, found {}", value),
+            value.span,
+        )),
+    }
+}
+
+/// Cases up to this count are tested one after another
+const SWITCH_CHAIN_LIMIT: usize = 4;
+
+/// Jumps to the label of the case that matches the value on top of the stack, or to the default
+/// label. The value is left on the stack. Whitespace has no indirect jumps, so instead of a jump
+/// table, larger switches split the sorted cases in halves until few enough are left to test
+/// them one by one.
+fn switch_dispatch(cases: &[(i32, i32)], default_label: i32, state: &mut state::State) -> Vec<IMP> {
+    let mut instructions = vec![];
+    if cases.len() <= SWITCH_CHAIN_LIMIT {
+        for (value, label) in cases {
+            instructions.extend([
+                IMP::Stack(StackOperations::Duplicate),
+                IMP::Stack(StackOperations::PushNumber(*value)),
+                IMP::Arithmetic(ArithmeticOperations::Subtract),
+                IMP::FlowControl(FlowControlOperations::JumpIfZero(*label)),
+            ]);
+        }
+        instructions.push(IMP::FlowControl(FlowControlOperations::Jump(default_label)));
+        return instructions;
     }
+    let (lower, upper) = cases.split_at(cases.len() / 2);
+    let lower_label = state.get_label();
+    instructions.extend([
+        IMP::Stack(StackOperations::Duplicate),
+        IMP::Stack(StackOperations::PushNumber(upper[0].0)),
+        IMP::Arithmetic(ArithmeticOperations::Subtract),
+        IMP::FlowControl(FlowControlOperations::JumpIfNegative(lower_label)),
+    ]);
+    instructions.extend(switch_dispatch(upper, default_label, state));
+    instructions.push(IMP::FlowControl(FlowControlOperations::SetLabel(
+        lower_label,
+    )));
+    instructions.extend(switch_dispatch(lower, default_label, state));
+    instructions
 }
@@ -1,6 +1,12 @@
 use std::collections::HashMap;
+use std::rc::Rc;
 
-use super::{HeapVariableSize, VariableType};
+use crate::ast::{Function, Operand, Statement, StatementKind};
+use crate::diagnostic::Diagnostic;
+use crate::lexer::Span;
+use crate::specs::whitespace::{FlowControlOperations, HeapOperations, StackOperations, IMP};
+
+use super::{CodeOutput, HeapVariableSize, VariableType};
 
 #[derive(Clone)]
 pub struct HeapVariable {
@@ -18,71 +24,339 @@ impl HeapVariable {
     }
 }
 
+/// Maps variable names to their heap cells. Every block opens a new scope, variables
+/// declared inside of it are not visible after the block ends. Heap cells are never reused.
+#[derive(Clone)]
 pub struct HeapAllocation {
-    map: HashMap<String, HeapVariable>,
+    scopes: Vec<HashMap<String, HeapVariable>>,
+    /// First scope of every function body that is being expanded
+    functions: Vec<usize>,
     offset: i32,
 }
 
-impl Clone for HeapAllocation {
-    fn clone(&self) -> Self {
-        let mut map: HashMap<String, HeapVariable> = HashMap::new();
-        for (key, value) in &self.map {
-            map.insert(key.clone(), value.clone());
-        }
-        HeapAllocation {
-            map,
-            offset: self.offset,
-        }
-    }
-}
-
 impl HeapAllocation {
     pub fn new() -> Self {
         HeapAllocation {
-            map: HashMap::new(),
+            scopes: vec![HashMap::new()],
+            functions: vec![],
             offset: 0,
         }
     }
 
     pub fn allocate(&mut self, name: String, type_: VariableType) -> i32 {
+        let offset = self.reserve(type_);
+        self.scopes
+            .last_mut()
+            .expect("global scope is never closed")
+            .insert(name, HeapVariable { offset, type_ });
+        offset
+    }
+
+    /// Reserves heap cells that are not bound to any variable, e.g. for temporary values.
+    pub fn reserve(&mut self, type_: VariableType) -> i32 {
         let offset = self.offset;
-        match type_ {
-            VariableType::Int => {
-                self.offset += HeapVariableSize::Int.size();
-            }
-            VariableType::String(length) => {
-                self.offset += HeapVariableSize::String(length).size();
-            }
-            _ => {
-                panic!("Only integer values are supported for now");
-            }
-        }
-        self.map.insert(
-            name,
-            HeapVariable {
-                offset: offset,
-                type_,
-            },
-        );
+        self.offset += match type_ {
+            VariableType::Int
+            | VariableType::Enum(_)
+            | VariableType::Pointer
+            | VariableType::Map(_)
+            | VariableType::Str => HeapVariableSize::Int.size(),
+            VariableType::Char => HeapVariableSize::Char.size(),
+            VariableType::String(length) => HeapVariableSize::String(length).size(),
+        };
         offset
     }
 
-    pub fn get(&self, name: &String) -> Option<&HeapVariable> {
-        self.map.get(name)
+    /// Number of heap cells taken by variables and reserved cells so far
+    pub fn size(&self) -> i32 {
+        self.offset
+    }
+
+    /// Same as [`HeapAllocation::allocate`], but reports redeclarations within the current scope.
+    pub fn declare(
+        &mut self,
+        name: &str,
+        type_: VariableType,
+        span: Span,
+    ) -> Result<i32, Diagnostic> {
+        if self
+            .scopes
+            .last()
+            .is_some_and(|scope| scope.contains_key(name))
+        {
+            return Err(Diagnostic::new(
+                format!("Variable {} is already declared", name),
+                span,
+            ));
+        }
+        Ok(self.allocate(name.to_string(), type_))
+    }
+
+    /// Makes an existing variable visible under another name in the current scope
+    pub fn bind(&mut self, name: &str, variable: HeapVariable) {
+        self.scopes
+            .last_mut()
+            .expect("global scope is never closed")
+            .insert(name.to_string(), variable);
+    }
+
+    /// A function body only sees its own variables and the global ones
+    pub fn get(&self, name: &str) -> Option<&HeapVariable> {
+        let start = self.functions.last().copied().unwrap_or(0);
+        self.scopes[start..]
+            .iter()
+            .rev()
+            .chain(self.scopes.first())
+            .find_map(|scope| scope.get(name))
+    }
+
+    /// Same as [`HeapAllocation::get`], but reports unknown variables.
+    pub fn lookup(&self, name: &str, span: Span) -> Result<&HeapVariable, Diagnostic> {
+        self.get(name)
+            .ok_or_else(|| Diagnostic::new(format!("Unknown variable {}", name), span))
+    }
+
+    pub fn enter_scope(&mut self) {
+        self.scopes.push(HashMap::new());
+    }
+
+    pub fn exit_scope(&mut self) {
+        if self.scopes.len() > 1 {
+            self.scopes.pop();
+        }
+    }
+
+    /// Opens the scope of a function body, see [`HeapAllocation::get`]
+    pub fn enter_function(&mut self) {
+        self.functions.push(self.scopes.len());
+        self.enter_scope();
+    }
+
+    pub fn exit_function(&mut self) {
+        let start = self.functions.pop().expect("function scope is open");
+        self.scopes.truncate(start);
     }
 }
 
-#[derive(Clone)]
+/// Enum type declared in the program, the variants are compile-time constants
+pub struct EnumDefinition {
+    name: String,
+    variants: Vec<(String, i32)>,
+}
+
 pub struct State {
     pub heap_allocation_map: HeapAllocation,
+    enums: Vec<EnumDefinition>,
     current_label_n: i32,
+    subroutines: HashMap<&'static str, i32>,
+    subroutine_code: CodeOutput,
+    globals: HashMap<&'static str, i32>,
+    prologue: CodeOutput,
+    heap_top: Option<i32>,
+    functions: HashMap<String, Rc<Function>>,
+    prelude_loaded: bool,
+    /// Functions whose calls are being expanded, innermost last
+    pub calls: Vec<Call>,
+    /// Labels of the program and of every call being expanded, innermost last
+    labels: Vec<HashMap<String, i32>>,
+    pub options: Options,
+    /// Problems that do not stop the compilation, see [`super::transpile_with_warnings`]
+    pub warnings: Vec<Diagnostic>,
+}
+
+/// Settings of a compilation
+#[derive(Clone, Copy)]
+pub struct Options {
+    /// Set declared variables to zero or the empty string, unless they are declared with
+    /// `uninit` or declared with a value
+    pub zero_initialize: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            zero_initialize: true,
+        }
+    }
+}
+
+/// Function call that is being expanded
+pub struct Call {
+    pub name: String,
+    /// Label after the body, where `return` jumps to
+    pub end_label: i32,
+    /// Cell of the result of a function that returns a value
+    pub result: Option<i32>,
 }
 
 impl State {
     pub fn new() -> Self {
+        Self::with_options(Options::default())
+    }
+
+    pub fn with_options(options: Options) -> Self {
         State {
             heap_allocation_map: HeapAllocation::new(),
+            enums: vec![],
             current_label_n: 0,
+            subroutines: HashMap::new(),
+            subroutine_code: CodeOutput::new(),
+            globals: HashMap::new(),
+            prologue: CodeOutput::new(),
+            heap_top: None,
+            functions: HashMap::new(),
+            prelude_loaded: false,
+            calls: vec![],
+            labels: vec![],
+            options,
+            warnings: vec![],
+        }
+    }
+
+    pub fn declare_enum(
+        &mut self,
+        name: &str,
+        variants: &[(String, i32)],
+        span: Span,
+    ) -> Result<(), Diagnostic> {
+        if self.enums.iter().any(|definition| definition.name == name) {
+            return Err(Diagnostic::new(
+                format!("Enum {} is already declared", name),
+                span,
+            ));
+        }
+        self.enums.push(EnumDefinition {
+            name: name.to_string(),
+            variants: variants.to_vec(),
+        });
+        Ok(())
+    }
+
+    /// Returns the type of the variables of an enum
+    pub fn lookup_enum(&self, name: &str, span: Span) -> Result<VariableType, Diagnostic> {
+        self.enums
+            .iter()
+            .position(|definition| definition.name == name)
+            .map(VariableType::Enum)
+            .ok_or_else(|| Diagnostic::new(format!("Unknown enum {}", name), span))
+    }
+
+    /// Returns the type and the value of `name::variant`
+    pub fn lookup_variant(
+        &self,
+        name: &str,
+        variant: &str,
+        span: Span,
+    ) -> Result<(VariableType, i32), Diagnostic> {
+        let type_ = self.lookup_enum(name, span)?;
+        let VariableType::Enum(id) = type_ else {
+            unreachable!("enums have enum types");
+        };
+        self.enums[id]
+            .variants
+            .iter()
+            .find(|(candidate, _)| candidate == variant)
+            .map(|(_, value)| (type_, *value))
+            .ok_or_else(|| {
+                Diagnostic::new(format!("Enum {} has no variant {}", name, variant), span)
+            })
+    }
+
+    pub fn define_function(&mut self, function: Function, span: Span) -> Result<(), Diagnostic> {
+        if self.functions.contains_key(&function.name) {
+            return Err(Diagnostic::new(
+                format!("Function {} is already defined", function.name),
+                span,
+            ));
+        }
+        self.functions
+            .insert(function.name.clone(), Rc::new(function));
+        Ok(())
+    }
+
+    /// Gives a fresh label to every `label` of a function body, or of the program, so that
+    /// `goto` can jump forward. Labels are not visible outside of the body.
+    pub fn enter_labels(&mut self, body: &[Statement]) -> Result<(), Diagnostic> {
+        let mut labels = HashMap::new();
+        let mut statements: Vec<&Statement> = body.iter().collect();
+        while let Some(statement) = statements.pop() {
+            match &statement.kind {
+                StatementKind::Label(name) => {
+                    if labels.contains_key(name) {
+                        return Err(Diagnostic::new(
+                            format!("Label {} is already defined", name),
+                            statement.span,
+                        ));
+                    }
+                    labels.insert(name.clone(), self.get_label());
+                }
+                StatementKind::Asm(instructions) => {
+                    for instruction in instructions {
+                        if let ("label", Some(Operand::Label(name))) =
+                            (instruction.mnemonic.as_str(), &instruction.operand)
+                        {
+                            if labels.contains_key(name) {
+                                return Err(Diagnostic::new(
+                                    format!("Label {} is already defined", name),
+                                    instruction.span,
+                                ));
+                            }
+                            labels.insert(name.clone(), self.get_label());
+                        }
+                    }
+                }
+                StatementKind::Block(body) | StatementKind::Expansion(_, body) => {
+                    statements.extend(body.iter().rev())
+                }
+                StatementKind::WhileLoop { body, .. } => statements.push(body),
+                StatementKind::Switch { cases, default, .. } => {
+                    statements.extend(default.iter().map(|default| default.as_ref()));
+                    statements.extend(cases.iter().rev().map(|case| &case.body));
+                }
+                _ => {}
+            }
+        }
+        self.labels.push(labels);
+        Ok(())
+    }
+
+    pub fn exit_labels(&mut self) {
+        self.labels.pop();
+    }
+
+    /// Returns the generated label of a `label` of the current function
+    pub fn label(&self, name: &str, span: Span) -> Result<i32, Diagnostic> {
+        self.labels
+            .last()
+            .and_then(|labels| labels.get(name))
+            .copied()
+            .ok_or_else(|| Diagnostic::new(format!("Unknown label {}", name), span))
+    }
+
+    /// Returns a function defined by the program, or one of the prelude once it is loaded
+    pub fn function(&self, name: &str) -> Option<Rc<Function>> {
+        self.functions.get(name).cloned()
+    }
+
+    /// Returns a function of the prelude, which is parsed on the first call only. Functions
+    /// of the program take precedence over the prelude ones with the same name.
+    pub fn prelude_function(&mut self, name: &str) -> Option<Rc<Function>> {
+        if !self.prelude_loaded {
+            self.prelude_loaded = true;
+            for function in super::function::prelude() {
+                self.functions
+                    .entry(function.name.clone())
+                    .or_insert_with(|| Rc::new(function));
+            }
+        }
+        self.function(name)
+    }
+
+    /// Name of a type for diagnostics, enums are shown by their names
+    pub fn type_name(&self, type_: VariableType) -> String {
+        match type_ {
+            VariableType::Enum(id) => self.enums[id].name.clone(),
+            type_ => type_.to_string(),
         }
     }
 
@@ -92,4 +366,88 @@ impl State {
         self.current_label_n += 1;
         label
     }
+
+    /// Returns the label of a shared subroutine, generating it on the first use only.
+    /// `body` produces the instructions of the subroutine, including the final return.
+    pub fn subroutine(&mut self, name: &'static str, body: fn(&mut State) -> Vec<IMP>) -> i32 {
+        if let Some(label) = self.subroutines.get(name) {
+            return *label;
+        }
+        let label = self.get_label();
+        self.subroutines.insert(name, label);
+
+        let mut code = CodeOutput::new();
+        code.add(String::new(), format!("# subroutine {}", name));
+        let instructions = body(self);
+        for imp in std::iter::once(IMP::FlowControl(FlowControlOperations::SetLabel(label)))
+            .chain(instructions)
+        {
+            code.append_imp(imp);
+        }
+        self.subroutine_code.append(code);
+        label
+    }
+
+    /// Returns the address of a hidden global shared by subroutines. The cell is reserved
+    /// on the first use and set to `initial` before the main program starts.
+    pub fn global(&mut self, name: &'static str, initial: i32) -> i32 {
+        if let Some(addr) = self.globals.get(name) {
+            return *addr;
+        }
+        let addr = self.heap_allocation_map.reserve(VariableType::Int);
+        self.globals.insert(name, addr);
+        self.initialize(addr, initial);
+        addr
+    }
+
+    /// Sets a heap cell to `value` before the main program starts
+    pub fn initialize(&mut self, addr: i32, value: i32) {
+        for imp in [
+            IMP::Stack(StackOperations::PushNumber(addr)),
+            IMP::Stack(StackOperations::PushNumber(value)),
+            IMP::Heap(HeapOperations::Store),
+        ] {
+            self.prologue.append_imp(imp);
+        }
+    }
+
+    /// Returns the address of the hidden global that holds the first heap cell that was never
+    /// handed out by the allocator. It starts right after the static data, which is only known
+    /// once the whole program is transpiled, so it is set up by [`State::take_prologue`].
+    pub fn heap_top(&mut self) -> i32 {
+        if let Some(addr) = self.heap_top {
+            return addr;
+        }
+        let addr = self.heap_allocation_map.reserve(VariableType::Int);
+        self.heap_top = Some(addr);
+        addr
+    }
+
+    pub fn take_prologue(&mut self) -> Option<CodeOutput> {
+        if let Some(addr) = self.heap_top.take() {
+            for imp in [
+                IMP::Stack(StackOperations::PushNumber(addr)),
+                IMP::Stack(StackOperations::PushNumber(self.heap_allocation_map.size())),
+                IMP::Heap(HeapOperations::Store),
+            ] {
+                self.prologue.append_imp(imp);
+            }
+        }
+        if self.prologue.code.is_empty() {
+            return None;
+        }
+        Some(std::mem::replace(&mut self.prologue, CodeOutput::new()))
+    }
+
+    /// Takes the code of all subroutines generated so far, they have to be placed where
+    /// the main program can't run into them.
+    pub fn take_subroutines(&mut self) -> Option<CodeOutput> {
+        if self.subroutine_code.code.is_empty() {
+            return None;
+        }
+        Some(std::mem::replace(
+            &mut self.subroutine_code,
+            CodeOutput::new(),
+        ))
+    }
 }
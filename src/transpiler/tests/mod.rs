@@ -1,71 +1,1053 @@
+use crate::ast::{Expression, ExpressionKind, Operation};
+use crate::lexer::Span;
 use crate::util;
 
 use super::*;
 
+mod vm;
+
+fn statement(kind: StatementKind) -> Statement {
+    Statement::new(kind, Span::default())
+}
+
+fn expression(kind: ExpressionKind) -> Expression {
+    Expression::new(kind, Span::default())
+}
+
+/// Compiles the program, leaving out the warnings
+fn transpile(ast: Vec<Statement>, state: Option<State>) -> Result<CodeOutput, Diagnostic> {
+    transpile_with_warnings(ast, state).map(|(res, _)| res)
+}
+
+fn compile(code: &str) -> Result<CodeOutput, Diagnostic> {
+    let tokens = crate::lexer::Lexer::new(code).tokenize();
+    transpile(crate::ast::parse(&tokens)?, None)
+}
+
+/// Compiles the program and runs it on the given input, returning the printed output.
+fn run(code: &str, input: &str) -> String {
+    let CodeOutput { code, .. } = compile(code).unwrap();
+    vm::run(&code, input).unwrap()
+}
+
 #[test]
 fn print() {
     let input = vec![
-        Statement::Call("print".to_string(), vec![Expression::Integer(99)]),
-        Statement::Exit,
+        statement(StatementKind::Call(
+            "print".to_string(),
+            vec![expression(ExpressionKind::Integer(99))],
+        )),
+        statement(StatementKind::Exit),
     ];
-    let CodeOutput { code, .. } = transpile(input, None);
+    let CodeOutput { code, .. } = transpile(input, None).unwrap();
     assert_eq!(util::unbleach(code), "sssttsssttntnstnnn");
 }
 #[test]
 fn heap_allocation() {
     let input = vec![
-        Statement::IntDeclaration("m".to_string()),
-        Statement::Assignment("m".to_string(), Expression::Integer(11)),
-        Statement::Exit,
+        statement(StatementKind::Uninitialized(Box::new(statement(
+            StatementKind::IntDeclaration("m".to_string()),
+        )))),
+        statement(StatementKind::Assignment(
+            "m".to_string(),
+            expression(ExpressionKind::Integer(11)),
+        )),
+        statement(StatementKind::Exit),
     ];
-    let CodeOutput { code, .. } = transpile(input, None);
+    let CodeOutput { code, .. } = transpile(input, None).unwrap();
     assert_eq!(util::unbleach(code), "ssststtnssssnsntttsnnn");
 }
 #[test]
 fn print_from_heap() {
     let input = vec![
-        Statement::IntDeclaration("m".to_string()),
-        Statement::Assignment("m".to_string(), Expression::Integer(11)),
-        Statement::Call(
+        statement(StatementKind::Uninitialized(Box::new(statement(
+            StatementKind::IntDeclaration("m".to_string()),
+        )))),
+        statement(StatementKind::Assignment(
+            "m".to_string(),
+            expression(ExpressionKind::Integer(11)),
+        )),
+        statement(StatementKind::Call(
             "print".to_string(),
-            vec![Expression::Variable("m".to_string())],
-        ),
-        Statement::Exit,
+            vec![expression(ExpressionKind::Variable("m".to_string()))],
+        )),
+        statement(StatementKind::Exit),
     ];
-    let CodeOutput { code, .. } = transpile(input, None);
+    let CodeOutput { code, .. } = transpile(input, None).unwrap();
     assert_eq!(util::unbleach(code), "ssststtnssssnsntttsssssnttttnstnnn");
 }
 #[test]
 fn while_less_than() {
     let input = vec![
-        Statement::IntDeclaration("m".to_string()),
-        Statement::Assignment("m".to_string(), Expression::Integer(8)),
-        Statement::WhileLoop {
-            condition: Box::new(Expression::BinaryOp {
+        statement(StatementKind::Uninitialized(Box::new(statement(
+            StatementKind::IntDeclaration("m".to_string()),
+        )))),
+        statement(StatementKind::Assignment(
+            "m".to_string(),
+            expression(ExpressionKind::Integer(8)),
+        )),
+        statement(StatementKind::WhileLoop {
+            condition: Box::new(expression(ExpressionKind::BinaryOp {
                 operator: Operation::CompareLessThan,
-                left: Box::new(Expression::Variable("m".to_string())),
-                right: Box::new(Expression::Integer(11)),
-            }),
-            body: Box::new(Statement::Block(vec![
-                Statement::Call(
+                left: Box::new(expression(ExpressionKind::Variable("m".to_string()))),
+                right: Box::new(expression(ExpressionKind::Integer(11))),
+            })),
+            body: Box::new(statement(StatementKind::Block(vec![
+                statement(StatementKind::Call(
                     "print".to_string(),
-                    vec![Expression::Variable("m".to_string())],
-                ),
-                Statement::Assignment(
+                    vec![expression(ExpressionKind::Variable("m".to_string()))],
+                )),
+                statement(StatementKind::Assignment(
                     "m".to_string(),
-                    Expression::BinaryOp {
+                    expression(ExpressionKind::BinaryOp {
                         operator: Operation::Add,
-                        left: Box::new(Expression::Variable("m".to_string())),
-                        right: Box::new(Expression::Integer(1)),
-                    },
-                ),
-            ])),
-        },
-        Statement::Exit,
+                        left: Box::new(expression(ExpressionKind::Variable("m".to_string()))),
+                        right: Box::new(expression(ExpressionKind::Integer(1))),
+                    }),
+                )),
+            ]))),
+        }),
+        statement(StatementKind::Exit),
     ];
-    let CodeOutput { code, .. } = transpile(input, None);
+    let CodeOutput { code, .. } = transpile(input, None).unwrap();
     assert_eq!(
         util::unbleach(code),
         "ssstsssnssssnsntttsnssnssssntttssststtntsstntttnnsntsnnsstnssssnttttnstssssntttssstntsssssssnsntttsnsnnnsstsnnnn"
     );
 }
+#[test]
+fn unknown_variable_points_at_source() {
+    let err = compile("int a;\nprint(b);\nexit;").err().unwrap();
+    assert_eq!(err.to_string(), "Unknown variable b at 2:7");
+}
+#[test]
+fn debug_output_is_traced_to_statements() {
+    let CodeOutput { debug_code, .. } = compile("int a = 1;\n  exit;").unwrap();
+    let markers: Vec<&str> = debug_code
+        .lines()
+        .filter(|line| line.starts_with('#'))
+        .collect();
+    assert_eq!(markers, vec!["# 1:1", "# 1:7", "# 2:3"]);
+}
+#[test]
+fn assign_variable_to_variable() {
+    let output = run("int a = 4; int b = a + 1; a = b; print(a); exit;", "");
+    assert_eq!(output, "5");
+}
+#[test]
+fn string_copy_respects_capacity() {
+    let output = run(
+        "string[16] long = \"overflowing\"; string[5] short = long; string[16] copy = short;
+        print(copy); exit;",
+        "",
+    );
+    assert_eq!(output, "over");
+}
+#[test]
+fn assign_string_to_int_is_type_error() {
+    let err = compile("string[4] s = \"abc\";\nint a = s; exit;")
+        .err()
+        .unwrap();
+    assert_eq!(
+        err.to_string(),
+        "Cannot assign String[4] to a of type i32 at 2:9"
+    );
+}
+#[test]
+fn integer_comparisons() {
+    let output = run(
+        "int a = 3;
+        print(a == 3); print(a != 3); print(a < 3); print(a <= 3); print(a > 2); print(a >= 4);
+        print(a + 1 == 4);
+        exit;",
+        "",
+    );
+    assert_eq!(output, "1001101");
+}
+#[test]
+fn string_comparisons() {
+    let output = run(
+        "string[8] a = \"abc\"; string[8] b = \"abd\";
+        print(a < b); print(a == \"abc\"); print(a != \"abc\"); print(\"ab\" < a);
+        print(b >= a); print(\"b\" > a); print(a > \"abc\"); print(a <= \"abc\");
+        exit;",
+        "",
+    );
+    assert_eq!(output, "11011101");
+}
+#[test]
+fn while_string_not_equals() {
+    let output = run(
+        "string[10] cmd; read(cmd);
+        while (cmd != \"quit\") { print(cmd); read(cmd); }
+        exit;",
+        "one\ntwo\nquit\n",
+    );
+    assert_eq!(output, "onetwo");
+}
+#[test]
+fn compare_string_with_int_is_type_error() {
+    let err = compile("string[8] a = \"abc\";\nwhile (a == 1) { exit; }")
+        .err()
+        .unwrap();
+    assert_eq!(
+        err.to_string(),
+        "Operator == cannot compare String[8] with i32 at 2:13"
+    );
+}
+#[test]
+fn string_queries() {
+    let output = run(
+        "string[16] s = \"hello world\";
+        print(len(s)); print(\" \"); print(len(\"\")); print(\" \");
+        print(char_at(s, 4)); print(char_at(s, 11) == 0); print(char_at(s, -1) == 0); print(\" \");
+        print(index_of(s, \"o\")); print(index_of(s, \"world\")); print(index_of(s, \"x\")); print(\" \");
+        print(starts_with(s, \"hell\")); print(starts_with(s, \"world\")); print(starts_with(s, \"\"));
+        exit;",
+        "",
+    );
+    assert_eq!(output, "11 0 o11 46-1 101");
+}
+#[test]
+fn substr_clamps_range() {
+    let output = run(
+        "string[16] s = \"hello world\"; string[4] t;
+        substr(t, s, 6, 2); print(t); print(\"|\");
+        substr(t, s, 6, 10); print(t); print(\"|\");
+        substr(t, s, 20, 3); print(t); print(\"|\");
+        substr(t, s, -5, 1); print(t);
+        exit;",
+        "",
+    );
+    assert_eq!(output, "wo|wor||h");
+}
+#[test]
+fn string_transformations() {
+    let output = run(
+        "string[17] s = \"  Hello, World! \";
+        trim(s); print(s); print(\"|\");
+        to_upper(s); print(s); print(\"|\");
+        to_lower(s); print(s); print(\"|\");
+        reverse(s); print(s); print(\"|\");
+        s = \" \t \"; trim(s); print(s); print(len(s));
+        exit;",
+        "",
+    );
+    assert_eq!(
+        output,
+        "Hello, World!|HELLO, WORLD!|hello, world!|!dlrow ,olleh|0"
+    );
+}
+#[test]
+fn string_built_in_argument_errors() {
+    let err = compile("int a;\nprint(len(a));").err().unwrap();
+    assert_eq!(
+        err.to_string(),
+        "len expects String as argument, found i32 at 2:11"
+    );
+    let err = compile("string[4] s;\nreverse(\"abc\");").err().unwrap();
+    assert_eq!(err.to_string(), "reverse expects a string variable at 2:9");
+    let err = compile("string[4] s;\nprint(char_at(s));").err().unwrap();
+    assert_eq!(
+        err.to_string(),
+        "char_at expects 2 arguments, found 1 at 2:7"
+    );
+}
+#[test]
+fn int_to_string() {
+    let output = run(
+        "string[12] s; string[4] short; int n = 0 - 2048;
+        to_string(s, n); print(s); print(\" \"); print(len(s)); print(\" \");
+        to_string(s, 0); print(s); print(\" \");
+        to_string(s, n * n); print(s); print(\" \");
+        to_string(short, 12345); print(short);
+        exit;",
+        "",
+    );
+    assert_eq!(output, "-2048 5 0 4194304 123");
+}
+#[test]
+fn string_to_int() {
+    let output = run(
+        "string[16] s;
+        read(s); print(parse_int(s) + 1); print(\" \");
+        read(s); print(parse_int(s)); print(\" \");
+        read(s); print(parse_int(s)); print(\" \");
+        print(parse_int(\"abc\")); print(\" \"); print(parse_int(\"-\"));
+        exit;",
+        "41\n  -17 apples\n+8x\n",
+    );
+    assert_eq!(output, "42 -17 8 0 0");
+}
+#[test]
+fn string_interpolation() {
+    let output = run(
+        "int count = 0 - 3; string[8] name = \"Ann\"; string[12] short;
+        print(\"You have {count} items, {name}! {{ok}}\");
+        short = \"{name}:{count}:{name}\"; print(short); print(len(short));
+        print(\"{name}\" == name);
+        exit;",
+        "",
+    );
+    assert_eq!(output, "You have -3 items, Ann! {ok}Ann:-3:Ann101");
+}
+#[test]
+fn interpolation_errors() {
+    let err = compile("int a;\nprint(\"a = {b}\");").err().unwrap();
+    assert_eq!(err.to_string(), "Unknown variable b at 2:13");
+}
+#[test]
+fn literal_braces() {
+    // Only braces around a variable name are placeholders
+    let output = run(
+        "int a = 1;
+        print(\"a}\", \"|{1}\", \"|{a + 1}\", \"|a {b\", \"|{}\", \"|}{a}{\");
+        exit;",
+        "",
+    );
+    assert_eq!(output, "a}|{1}|{a + 1}|a {b|{}|}1{");
+}
+#[test]
+fn variadic_print() {
+    let output = run(
+        "int n = 7; string[8] s = \"abc\";
+        print(\"n=\", n, \" s=\", s, \" c=\", char_at(s, 1), \" \", n * 2);
+        println();
+        println(s, \"!\");
+        eprintln(\"error \", n);
+        print n + 1;
+        exit;",
+        "",
+    );
+    assert_eq!(output, "n=7 s=abc c=b 14\nabc!\nerror 7\n8");
+}
+#[test]
+fn print_parenthesized_expression() {
+    let output = run(
+        "int a = 1; int b = 2;
+        print (a + b) * 2;
+        print (a), (b);
+        print((a + b));
+        exit;",
+        "",
+    );
+    assert_eq!(output, "6123");
+}
+#[test]
+fn arguments_need_commas() {
+    assert_eq!(run("int a = 5; print(a - 1); exit;", ""), "4");
+    // `-1` is a negative literal, so this is two arguments without a comma in between
+    let err = compile("int a = 5; print(a-1);").err().unwrap();
+    assert_eq!(err.to_string(), "Expected , or ), found -1 at 1:19");
+    let err = compile("print(1 2);").err().unwrap();
+    assert_eq!(err.to_string(), "Expected , or ), found 2 at 1:9");
+    let err = compile("println(1, 2").err().unwrap();
+    assert_eq!(err.to_string(), "Expected , or ), found EOF at 1:13");
+}
+#[test]
+fn read_int_discards_rest_of_line() {
+    let output = run(
+        "int a; int b; read(a); read(b); print(a, \" \", b); exit;",
+        "12 34\n56\n",
+    );
+    assert_eq!(output, "12 56");
+}
+#[test]
+fn eprint_is_marked_in_debug_output() {
+    let CodeOutput { debug_code, .. } = compile("eprint(\"x\");").unwrap();
+    assert_eq!(
+        debug_code,
+        "# 1:1\n# stderr\npush 120\nprint_stack_top_as_char\n# end of stderr\n"
+    );
+}
+#[test]
+fn read_line_reports_truncation() {
+    let output = run(
+        "string[4] s; int status;
+        status = read_line(s); print(s, \" \", status, \"|\");
+        status = read_line(s); print(s, \" \", status, \"|\");
+        status = read_line(s); print(s, \" \", status, \"|\");
+        read(s); print(s, \"|\");
+        int n; read(n); print(n + 1);
+        exit;",
+        "abc\nabcdef\n\nxyzzy\n 41\n",
+    );
+    assert_eq!(output, "abc 0|abc 1| 0|xyz|42");
+}
+#[test]
+fn input_end_is_detected_across_interpreters() {
+    let code = "string[8] line;
+        while (eof() == 0) { read_line(line); println(\"[{line}]\"); }
+        print(read_line(line), \" \", read_char(), \" \", eof());
+        exit;";
+    let CodeOutput { code, .. } = compile(code).unwrap();
+    for eof in [vm::Eof::Unchanged, vm::Eof::Store(-1), vm::Eof::Store(0)] {
+        let output = vm::run_with_eof(&code, "one\ntwo", eof).unwrap();
+        assert_eq!(output, "[one]\n[two]\n-1 -1 1");
+    }
+}
+#[test]
+fn read_char_uses_lookahead() {
+    let output = run(
+        "int c = read_char(); print(c, \" \", eof(), \" \");
+        c = read_char(); print(c, \" \"); c = read_char(); print(c);
+        exit;",
+        "ab\n",
+    );
+    assert_eq!(output, "97 0 98 10");
+}
+#[test]
+fn math_built_ins() {
+    let output = run(
+        "int n = 0 - 7;
+        println(abs(n), \" \", abs(3), \" \", sign(n), sign(0), sign(9));
+        println(min(n, 2), \" \", max(n, 2), \" \", min(4, 4));
+        println(pow(3, 4), \" \", pow(n, 3), \" \", pow(5, 0), \" \", pow(2, 0 - 1));
+        println(isqrt(0), \" \", isqrt(15), \" \", isqrt(16), \" \", isqrt(1000000), \" \", isqrt(n));
+        println(gcd(12, 18), \" \", gcd(n, 21), \" \", gcd(0, 0), \" \", lcm(4, 6), \" \", lcm(n, 3), \" \", lcm(0, 5));
+        exit;",
+        "",
+    );
+    assert_eq!(
+        output,
+        "7 3 -101\n-7 2 4\n81 -343 1 0\n0 3 4 1000 -1\n6 7 0 12 21 0\n"
+    );
+}
+#[test]
+fn math_subroutines_are_shared() {
+    let CodeOutput { debug_code, .. } =
+        compile("print(gcd(4, 6) + gcd(6, 9) + lcm(2, 3)); exit;").unwrap();
+    assert_eq!(debug_code.matches("# subroutine gcd").count(), 1);
+    assert_eq!(debug_code.matches("# subroutine abs").count(), 1);
+}
+#[test]
+fn random_numbers_are_reproducible() {
+    let output = run(
+        "int i = 0;
+        while (i < 5) { print(rand(100), \" \"); i = i + 1; }
+        print(rand(2147483647), \" \", rand(0), \"|\");
+        seed(42); print(rand(6), rand(6), rand(6), \"|\");
+        seed(0 - 42); print(rand(6), rand(6), rand(6));
+        exit;",
+        "",
+    );
+    assert_eq!(output, "71 94 86 37 41 407355683 0|135|135");
+}
+#[test]
+fn bitwise_operators() {
+    let output = run(
+        "print(12 & 10, \" \", 12 | 10, \" \", 12 ^ 10, \" \", ~5, \" \", 1 << 4, \" \", 100 >> 3, \"|\");
+        int min = 0 - 2147483647 - 1;
+        print((0 - 12) & 10, \" \", (0 - 1) ^ 5, \" \", (0 - 7) >> 1, \" \", (0 - 12) | (0 - 3), \" \", min | 1, \"|\");
+        print(255 & ~15, \" \", 1 + 2 << 3, \" \", 6 & 3 == 2, \" \", 5 >> (0 - 1));
+        exit;",
+        "",
+    );
+    assert_eq!(output, "8 14 6 -6 16 12|0 -6 -4 -3 -2147483647|240 24 1 5");
+}
+#[test]
+fn compound_assignment() {
+    let output = run(
+        "int m = 8;
+        while (m < 11) { print(m, \" \"); m++; }
+        m += 10; m -= 1; m *= 3; m /= 4; m %= 6; m--;
+        print(m);
+        exit;",
+        "",
+    );
+    assert_eq!(output, "8 9 10 2");
+}
+#[test]
+fn compound_assignment_errors() {
+    let error = compile("string[4] s; s += 1;").err().unwrap();
+    assert_eq!(error.message, "Operator += is not supported for String[4]");
+    let error = compile("int m; m *= \"a\";").err().unwrap();
+    assert_eq!(error.message, "Operator *= is not supported for String[2]");
+}
+#[test]
+fn conditional_expression() {
+    let output = run(
+        "int m = 0;
+        while (m < 4) {
+            print(m == 0 ? \"zero\" : m % 2 == 1 ? \"odd\" : \"even\", \" \");
+            m++;
+        }
+        int n = m > 3 ? m * 10 : 0 - 1;
+        string[8] s = n != 40 ? \"no\" : \"yes\";
+        print(n, \" \", s, \" \", n ? 1 : 2, \" \", min(n, 50) < 45 ? 3 : 4);
+        exit;",
+        "",
+    );
+    assert_eq!(output, "zero odd even odd 40 yes 1 3");
+}
+#[test]
+fn conditional_evaluates_one_side() {
+    let output = run(
+        "int m = 0; int n = 1 < 2 ? 5 : read_char(); print(n); exit;",
+        "",
+    );
+    assert_eq!(output, "5");
+    let error = compile("int m = 1 ? 2 : \"a\";").err().unwrap();
+    assert_eq!(
+        error.message,
+        "Branches of ?: have different types: i32 and String[2]"
+    );
+}
+#[test]
+fn switch_statement() {
+    let output = run(
+        "int c = read_char();
+        while (c != '.') {
+            switch (c) {
+                case 'a': print(\"add \");
+                case 'q': print(\"quit \");
+                default: print(\"? \");
+            }
+            c = read_char();
+        }
+        int i = 0 - 1;
+        while (i < 12) {
+            switch (i * 3) {
+                case 0: print('z');
+                case 3: print('a');
+                case 6: print('b');
+                case 9: print('c');
+                case 12: print('d');
+                case 15: print('e');
+                case 18: print('f');
+                case 21: print('g');
+                case 24: print('h');
+                case 27: print('i');
+                case 30: print('j');
+                case -3: print('m');
+            }
+            i++;
+        }
+        exit;",
+        "aqx.",
+    );
+    assert_eq!(output, "add quit ? mzabcdefghij");
+}
+#[test]
+fn switch_errors() {
+    let error = compile("switch (1) { case 'a': case 97: }").err().unwrap();
+    assert_eq!(error.message, "Duplicate case value 97");
+    let error = compile("switch (1) { default: default: }").err().unwrap();
+    assert_eq!(error.message, "Duplicate default case");
+    let error = compile("switch (\"a\") { }").err().unwrap();
+    assert_eq!(error.message, "Switch value must be i32, found String[2]");
+}
+#[test]
+fn enums() {
+    let output = run(
+        "enum Color { Red, Green = 5, Blue }
+        enum Mode { Idle, Busy, }
+        Color c = Color::Blue;
+        int n = Color::Green;
+        print(Color::Red, \" \", n, \" \", c, \" \", c == Color::Blue, \" \");
+        c = 0;
+        Mode m;
+        m = Mode::Busy;
+        switch (c) {
+            case Color::Red: print(\"red \");
+            case Color::Blue: print(\"blue \");
+        }
+        print(m == Mode::Busy ? \"busy\" : \"idle\");
+        exit;",
+        "",
+    );
+    assert_eq!(output, "0 5 6 1 red busy");
+}
+#[test]
+fn enum_errors() {
+    let error = compile("enum A { X } enum B { Y } A a = B::Y;")
+        .err()
+        .unwrap();
+    assert_eq!(error.message, "Cannot assign B to a of type A");
+    let error = compile("enum A { X } int n = A::Z;").err().unwrap();
+    assert_eq!(error.message, "Enum A has no variant Z");
+    let error = compile("enum A { X, X }").err().unwrap();
+    assert_eq!(error.message, "Duplicate variant X");
+    let error = compile("enum A { X } enum A { Y }").err().unwrap();
+    assert_eq!(error.message, "Enum A is already declared");
+    let error = compile("B b;").err().unwrap();
+    assert_eq!(error.message, "Unknown enum B");
+    let error = compile("enum A { X = 97 } switch (1) { case 'a': case A::X: }")
+        .err()
+        .unwrap();
+    assert_eq!(error.message, "Duplicate case value 97");
+    let error = compile("enum A { X = 2147483647, Y }").err().unwrap();
+    assert_eq!(
+        error.to_string(),
+        "Value of variant Y is out of range at 1:26"
+    );
+    assert!(compile("enum A { X = 2147483646, Y }").is_ok());
+}
+#[test]
+fn alloc_and_free() {
+    let output = run(
+        "ptr a = alloc(4);
+        ptr b = alloc(2);
+        a[0] = 7;
+        a[3] = a[0] * 2;
+        a[3] += 1;
+        a[0]++;
+        b[1] = a[3] - a[0];
+        int i = 1;
+        print(a[0], \" \", a[i + 2], \" \", b[i], \" \", b - a, \" \");
+        free(a);
+        ptr c = alloc(2);
+        ptr d = alloc(1);
+        ptr e = alloc(3);
+        print(c == a, \" \", d - a, \" \", e - b);
+        exit;",
+        "",
+    );
+    assert_eq!(output, "8 15 7 5 1 3 3");
+}
+#[test]
+fn alloc_errors() {
+    let error = compile("int n; n[0] = 1;").err().unwrap();
+    assert_eq!(error.message, "Only pointers can be indexed, found i32");
+    let error = compile("free(5);").err().unwrap();
+    assert_eq!(error.message, "free expects ptr as argument, found i32");
+    let error = compile("ptr p = 5;").err().unwrap();
+    assert_eq!(error.message, "Cannot assign i32 to p of type ptr");
+    let error = compile("ptr p = alloc(1); p[\"a\"] = 1;").err().unwrap();
+    assert_eq!(error.message, "Index must be i32, found String[2]");
+    let error = compile("ptr p = alloc(1); p[0] = \"a\";").err().unwrap();
+    assert_eq!(error.message, "Cannot assign String[2] to p[0] of type i32");
+}
+#[test]
+fn growable_strings() {
+    let output = run(
+        "str s;
+        print(len(s), \"|\");
+        s = \"Hello\";
+        str t = s;
+        concat(s, \", world! This is longer than the first block\");
+        print(s, \"|\", len(s), \"|\", t, \"|\");
+        concat(t, t);
+        concat(t, t);
+        concat(t, t);
+        print(len(t), \"|\", char_at(t, 39), \"|\");
+        int n = len(s);
+        s = \"{t} and {n}!\";
+        print(len(s), \"|\", index_of(s, \"and 49\"), \"|\", s == t, \"|\");
+        string[4] short = s;
+        print(short, \"|\");
+        read(s);
+        print(s, \"|\", len(s), \"|\", read_line(t), \"|\", t, \"|\", len(t));
+        exit;",
+        "a line that does not fit into fifteen characters\nend\n",
+    );
+    assert_eq!(
+        output,
+        "0|Hello, world! This is longer than the first block|49|Hello|\
+         40|o|48|41|0|Hel|\
+         a line that does not fit into fifteen characters|48|0|end|3"
+    );
+}
+#[test]
+fn growable_string_errors() {
+    let error = compile("str s = 5;").err().unwrap();
+    assert_eq!(error.message, "Cannot assign i32 to s of type str");
+    let error = compile("str s; to_upper(s);").err().unwrap();
+    assert_eq!(error.message, "to_upper does not support str variables");
+    let error = compile("str s; string[4] t; print(1 == 1 ? s : t);")
+        .err()
+        .unwrap();
+    assert_eq!(
+        error.message,
+        "Branches of ?: have different types: str and String[4]"
+    );
+}
+#[test]
+fn maps() {
+    let output = run(
+        "map<int, int> squares;
+        int i = 0 - 10;
+        while (i < 20) {
+            put(squares, i, i * i);
+            i++;
+        }
+        print(get(squares, 0 - 7), \"|\", get(squares, 19), \"|\", get(squares, 25), \"|\");
+        i = 0;
+        while (i < 20) {
+            remove(squares, i);
+            i += 2;
+        }
+        remove(squares, 100);
+        put(squares, 3, 0 - 1);
+        print(has(squares, 4), has(squares, 5), has(squares, 0 - 4), \"|\", get(squares, 3), \"|\");
+        map<string, int> counts;
+        str word;
+        read(word);
+        while (word != \"end\") {
+            put(counts, word, get(counts, word) + 1);
+            read(word);
+        }
+        string[8] key = \"apple\";
+        print(get(counts, key), get(counts, \"pear\"), get(counts, \"plum\"), \"|\");
+        remove(counts, \"apple\");
+        key = \"pear\";
+        put(counts, \"apple\", 7);
+        print(has(counts, \"apple\"), get(counts, \"apple\"), get(counts, key));
+        exit;",
+        "apple\npear\napple\nfig\napple\npear\nend\n",
+    );
+    assert_eq!(output, "49|361|0|011|-1|320|172");
+}
+#[test]
+fn map_errors() {
+    let error = compile("map<int, int> m; put(m, \"a\", 1);").err().unwrap();
+    assert_eq!(error.message, "put expects i32 as key, found String[2]");
+    let error = compile("map<string, int> m; print(get(m, 1));")
+        .err()
+        .unwrap();
+    assert_eq!(error.message, "get expects String as key, found i32");
+    let error = compile("int m; print(has(m, 1));").err().unwrap();
+    assert_eq!(error.message, "has expects a map variable");
+    let error = compile("map<int, int> m; print(m);").err().unwrap();
+    assert_eq!(
+        error.message,
+        "Map m can only be used with put, get, has and remove"
+    );
+    let error = compile("map<int, string> m;").err().unwrap();
+    assert_eq!(error.message, "Expected int, found string");
+}
+#[test]
+fn imports() {
+    let files: std::collections::HashMap<String, String> = [
+        (
+            "lib/strings.wsc",
+            "import \"../common.wsc\";\nstring[16] greeting = \"Hello\";",
+        ),
+        ("common.wsc", "int answer = 42;"),
+        ("lib/broken.wsc", "int x = 1;\nprint(x + y);"),
+        ("lib/placeholder.wsc", "int x = 1;\nprint(\"{y}\");"),
+        ("lib/loop.wsc", "import \"../main.wsc\";"),
+    ]
+    .into_iter()
+    .map(|(path, code)| (path.to_string(), code.to_string()))
+    .collect();
+    let load = |code: &str| crate::source::load("main.wsc", code, &files);
+    let (program, _) = load(
+        "import \"lib/strings.wsc\";
+        import \"./common.wsc\";
+        print(greeting, \" \", answer);
+        exit;",
+    )
+    .unwrap();
+    let CodeOutput { code, .. } = transpile(program, None).unwrap();
+    assert_eq!(vm::run(&code, "").unwrap(), "Hello 42");
+    let (program, sources) = load("import \"lib/broken.wsc\";").unwrap();
+    let error = sources.locate(transpile(program, None).err().unwrap());
+    assert_eq!(
+        error.to_string(),
+        "Unknown variable y at lib/broken.wsc:2:11"
+    );
+    let (program, sources) = load("import \"lib/placeholder.wsc\";").unwrap();
+    let error = sources.locate(transpile(program, None).err().unwrap());
+    assert_eq!(
+        error.to_string(),
+        "Unknown variable y at lib/placeholder.wsc:2:9"
+    );
+    let error = load("import \"lib/loop.wsc\";").err().unwrap();
+    assert_eq!(
+        error.to_string(),
+        "Import cycle: main.wsc -> lib/loop.wsc -> main.wsc at lib/loop.wsc:1:1"
+    );
+    let error = load("int a;\nimport \"missing.wsc\";").err().unwrap();
+    assert_eq!(error.to_string(), "Cannot find file missing.wsc at 2:1");
+    let error = load("import \"lib/strings.wsc\" exit;").err().unwrap();
+    assert_eq!(error.to_string(), "Expected ;, found exit at 1:26");
+    let error = compile("while (1) { import \"common.wsc\"; }")
+        .err()
+        .unwrap();
+    assert_eq!(
+        error.message,
+        "Imports are only allowed at the top level of a file"
+    );
+}
+#[test]
+fn functions() {
+    let output = run(
+        "int total = 0;
+        print(square(3), \"|\");
+        func int square(int x) {
+            return x * x;
+        }
+        func add_twice(string s, int n) {
+            total += n;
+            n = 0;
+            s = \"<{s}>\";
+        }
+        func int sign_of(int x) {
+            while (x < 0) {
+                return 0 - 1;
+            }
+            return x > 0;
+        }
+        string[16] word = \"ab\";
+        int n = 4;
+        add_twice(word, n);
+        add_twice(word, square(n));
+        print(word, \"|\", total, \"|\", n, \"|\");
+        print(sign_of(0 - 5), sign_of(0), sign_of(square(2) - 1), \"|\");
+        square(2);
+        exit;",
+        "",
+    );
+    assert_eq!(output, "9|<<ab>>|20|4|-101|");
+}
+#[test]
+fn prelude() {
+    let output = run(
+        "string[16] s = \"42\";
+        pad_left(s, 6, \"0\");
+        str t = \"ab\";
+        pad_right(t, 5, \".\");
+        print(s, \"|\", t, \"|\");
+        repeat(s, \"xy\", 3);
+        print(s, \"|\");
+        format_thousands(s, 0 - 1234567);
+        print(s, \"|\");
+        format_thousands(t, 999);
+        print(t, \"|\", is_digit('7'), is_digit('a'), is_space(' '), is_space('x'));
+        exit;",
+        "",
+    );
+    assert_eq!(output, "000042|ab...|xyxyxy|-1,234,567|999|1010");
+    // Only the functions that are called are expanded
+    let CodeOutput { debug_code, .. } = compile("print(is_digit('1')); exit;").unwrap();
+    assert!(debug_code.contains("# call is_digit"));
+    assert!(!debug_code.contains("# call is_space"));
+}
+#[test]
+fn function_errors() {
+    let error = compile("func f() { g(); } func g() { f(); } f();")
+        .err()
+        .unwrap();
+    assert_eq!(error.message, "Recursive call of f is not supported");
+    let error = compile("return 1;").err().unwrap();
+    assert_eq!(error.message, "return outside of a function");
+    let error = compile("func f() { return 1; } f();").err().unwrap();
+    assert_eq!(error.message, "f does not return a value");
+    let error = compile("func int f() { return; } f();").err().unwrap();
+    assert_eq!(error.message, "f must return a value");
+    let error = compile("func f() {} print(f());").err().unwrap();
+    assert_eq!(error.message, "f does not return a value");
+    let error = compile("pad_left(1, 2, \"0\");").err().unwrap();
+    assert_eq!(
+        error.message,
+        "pad_left expects String as argument, found i32"
+    );
+    let error = compile("func f(int a) {} f();").err().unwrap();
+    assert_eq!(error.message, "f expects 1 arguments, found 0");
+    let error = compile("func f() {} func f() {}").err().unwrap();
+    assert_eq!(error.message, "Function f is already defined");
+    let error = compile("while (1) { func f() {} }").err().unwrap();
+    assert_eq!(
+        error.message,
+        "Functions can only be defined at the top level"
+    );
+    let error = compile("func f() { print(a); } { int a; f(); }")
+        .err()
+        .unwrap();
+    assert_eq!(error.message, "Unknown variable a");
+}
+#[test]
+fn macros() {
+    let code = "macro swap(a, b) { int t = a; a = b; b = t; }
+macro sort2(a, b) { swap(a, b); }
+int t = 1; int u = 2;
+swap(t, u);
+println(\"{t} {u}\");
+int i = 0;
+while (i < 3) { sort2(t, u); i += 1; }
+print(\"{t} {u}\"); exit;";
+    assert_eq!(run(code, ""), "2 1\n1 2");
+    // Arguments are evaluated at every use
+    let code = "macro add(x, n) { x += n; }
+macro twice(x, n) { add(x, n); add(x, n); }
+int a = 1;
+twice(a, a * 2);
+print(a); exit;";
+    assert_eq!(run(code, ""), "9");
+}
+#[test]
+fn macro_errors() {
+    let error = compile("macro m() { m(); } m();").err().unwrap();
+    assert_eq!(error.message, "Recursive expansion of macro m");
+    let error = compile("macro m() {} macro m() {}").err().unwrap();
+    assert_eq!(error.message, "Macro m is already defined");
+    let error = compile("while (1) { macro m() {} }").err().unwrap();
+    assert_eq!(error.message, "Macros can only be defined at the top level");
+    let error = compile("macro m(a) {} m();").err().unwrap();
+    assert_eq!(error.message, "m expects 1 arguments, found 0");
+    let error = compile("macro m() {} print(m());").err().unwrap();
+    assert_eq!(error.message, "Macro m can only be used as a statement");
+    let error = compile("macro inc(a) { a += 1; }\ninc(2);").err().unwrap();
+    assert_eq!(
+        error.to_string(),
+        "Macro argument a must be a variable, found 2 at 1:16\n  argument at 2:5\n  in expansion of macro inc at 2:1"
+    );
+    let error = compile("macro show(a) {\n  print(x);\n}\nshow(1);")
+        .err()
+        .unwrap();
+    assert_eq!(
+        error.to_string(),
+        "Unknown variable x at 2:9\n  in expansion of macro show at 4:1"
+    );
+}
+#[test]
+fn goto() {
+    let code = "int i = 0;
+label again:
+i += 1;
+switch (i % 3) {
+    case 0: { goto done; }
+    default: { print(i); goto again; }
+}
+label done:
+println();
+func count(int n) {
+    label top:
+    print(n);
+    n -= 1;
+    while (n > 0) { goto top; }
+}
+count(2);
+count(1);
+macro skip(x) { goto over; x += 100; label over: }
+skip(i);
+skip(i);
+print(\" \", i);
+exit;";
+    assert_eq!(run(code, ""), "12\n211 3");
+}
+#[test]
+fn goto_errors() {
+    let error = compile("goto end;").err().unwrap();
+    assert_eq!(error.message, "Unknown label end");
+    let error = compile("label a: { label a: }").err().unwrap();
+    assert_eq!(error.message, "Label a is already defined");
+    let error = compile("label a: func f() { goto a; } f();").err().unwrap();
+    assert_eq!(error.message, "Unknown label a");
+}
+#[test]
+fn asm() {
+    let code = "asm { push 5; dup; print_num; print_num; }
+int x = 3;
+asm {
+    label loop:
+    push &x; retrieve; jump_if_zero done;
+    push &x; retrieve; print_stack_top_as_number;
+    push &x; push &x; retrieve; push 1; subtract; store;
+    jump loop;
+}
+label done:
+asm { push '!'; print_stack_top_as_char; push 7; push 8; copy_nth(1); print_num; slide 1; print_num; }
+macro show(v) { asm { push &v; retrieve; print_num; } }
+show(x);
+exit;";
+    assert_eq!(run(code, ""), "55321!780");
+    let CodeOutput { debug_code, .. } = compile("asm { push 1; dup; slide(1); discard; }").unwrap();
+    assert!(debug_code.contains("push 1\nduplicate\nslide 1\ndiscard\n"));
+}
+#[test]
+fn asm_errors() {
+    let error = compile("asm { nop; }").err().unwrap();
+    assert_eq!(error.message, "Unknown instruction nop");
+    let error = compile("asm { push; }").err().unwrap();
+    assert_eq!(error.message, "push expects a number");
+    let error = compile("asm { jump 1; }").err().unwrap();
+    assert_eq!(error.message, "jump expects a label");
+    let error = compile("asm { add 1; }").err().unwrap();
+    assert_eq!(error.message, "add takes no operand");
+    let error = compile("asm { push &x; }").err().unwrap();
+    assert_eq!(error.message, "Unknown variable x");
+    let error = compile("asm { jump x; }").err().unwrap();
+    assert_eq!(error.message, "Unknown label x");
+    let error = compile("label a: asm { label a: }").err().unwrap();
+    assert_eq!(error.message, "Label a is already defined");
+}
+#[test]
+fn zero_initialization() {
+    let code = "int a; string[8] s; string[8] t = \"ab\"; concat(s, t);
+int i = 0;
+while (i < 2) { int x; x += 1; print(x); i += 1; }
+print(a, \"[\", s, \"]\"); exit;";
+    assert_eq!(run(code, ""), "110[ab]");
+    // Declarations with an initializer are not zeroed first
+    let CodeOutput { debug_code, .. } = compile("int a = 5; exit;").unwrap();
+    assert_eq!(debug_code.matches("store").count(), 1);
+    // A separate assignment may read the variable, so the declaration is zeroed
+    assert_eq!(run("int x; x = x + 1; print(x); exit;", ""), "1");
+    assert_eq!(run("string[8] s; s = \"{s}a\"; print(s); exit;", ""), "a");
+    assert_eq!(run("uninit int x = 2; print(x); exit;", ""), "2");
+    let CodeOutput { code, .. } = compile("uninit int a; print(a); exit;").unwrap();
+    assert!(vm::run(&code, "").is_err());
+    let code = "int a; string[4] s; a = 1; s = \"x\"; print(a, s); exit;";
+    let options = Options {
+        zero_initialize: false,
+    };
+    let small = transpile(
+        crate::ast::parse(&crate::lexer::Lexer::new(code).tokenize()).unwrap(),
+        Some(State::with_options(options)),
+    )
+    .unwrap();
+    assert!(small.code.len() < compile(code).unwrap().code.len());
+    assert_eq!(vm::run(&small.code, "").unwrap(), "1x");
+    let error = compile("uninit str s;").err().unwrap();
+    assert_eq!(
+        error.message,
+        "uninit only applies to int, string, ptr and enum declarations"
+    );
+}
+#[test]
+fn string_capacity_checks() {
+    assert_eq!(run("string[4] s = \"abc\"; print(s); exit;", ""), "abc");
+    let err = compile("string[3] s = \"hello\";").err().unwrap();
+    assert_eq!(
+        err.to_string(),
+        "Cannot assign String[6] to s of type String[3] at 1:13"
+    );
+    let err = compile("string[4] s;\ns = \"abcd\";").err().unwrap();
+    assert_eq!(
+        err.to_string(),
+        "Cannot assign String[5] to s of type String[4] at 2:1"
+    );
+    let warnings = |code: &str| {
+        let tokens = crate::lexer::Lexer::new(code).tokenize();
+        let (_, warnings) =
+            transpile_with_warnings(crate::ast::parse(&tokens).unwrap(), None).unwrap();
+        warnings
+            .iter()
+            .map(|warning| warning.to_string())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        warnings("string[4] a; string[8] b;\nconcat(a, b);"),
+        vec!["a of type String[4] may not have room for b of type String[8] at 2:1"]
+    );
+    assert_eq!(
+        warnings("string[8] a; string[8] b; concat(a, b);"),
+        vec!["a of type String[8] may not have room for b of type String[8] at 1:27"]
+    );
+    // The target has room for the source and some content of its own, a str has no capacity
+    // to compare
+    assert!(warnings("string[16] a; string[8] b; str c; concat(a, b); concat(a, c);").is_empty());
+    assert_eq!(
+        warnings("string[4] a; concat(a, \"abc\"); concat(a, \"ab\");"),
+        vec!["a of type String[4] may not have room for \"abc\" of type String[4] at 1:14"]
+    );
+    assert_eq!(
+        run(
+            "string[8] s = \"ab\"; concat(s, \"cd\"); concat(s, \"\"); print(s); exit;",
+            ""
+        ),
+        "abcd"
+    );
+}
@@ -0,0 +1,323 @@
+//! Minimal Whitespace interpreter used to check the behaviour of the generated code.
+//! Division and modulo round towards negative infinity like the reference implementation,
+//! reading a heap cell that was never written and exiting with a non-empty stack are errors.
+//! What reading a character past the end of input does is configurable, see [`Eof`].
+
+use std::collections::HashMap;
+
+enum Instruction {
+    Push(i64),
+    Duplicate,
+    Copy(i64),
+    Swap,
+    Discard,
+    Slide(i64),
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+    Store,
+    Retrieve,
+    Label(String),
+    Call(String),
+    Jump(String),
+    JumpIfZero(String),
+    JumpIfNegative(String),
+    Return,
+    Exit,
+    PrintChar,
+    PrintNumber,
+    ReadChar,
+    ReadNumber,
+}
+
+struct Parser {
+    code: Vec<char>,
+    position: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<char> {
+        let ch = self.code.get(self.position).copied();
+        self.position += 1;
+        ch
+    }
+
+    fn expect(&mut self) -> Result<char, String> {
+        self.next()
+            .ok_or_else(|| "unexpected end of program".to_string())
+    }
+
+    fn number(&mut self) -> Result<i64, String> {
+        let sign = match self.expect()? {
+            ' ' => 1,
+            '\t' => -1,
+            _ => return Err("number without a sign".to_string()),
+        };
+        let mut value: i64 = 0;
+        loop {
+            match self.expect()? {
+                ' ' => value *= 2,
+                '\t' => value = value * 2 + 1,
+                _ => break,
+            }
+        }
+        Ok(sign * value)
+    }
+
+    fn label(&mut self) -> Result<String, String> {
+        let mut label = String::new();
+        loop {
+            match self.expect()? {
+                '\n' => break,
+                ch => label.push(ch),
+            }
+        }
+        Ok(label)
+    }
+
+    fn instruction(&mut self, first: char) -> Result<Instruction, String> {
+        let second = self.expect()?;
+        Ok(match (first, second) {
+            (' ', ' ') => Instruction::Push(self.number()?),
+            (' ', '\n') => match self.expect()? {
+                ' ' => Instruction::Duplicate,
+                '\t' => Instruction::Swap,
+                _ => Instruction::Discard,
+            },
+            (' ', '\t') => match self.expect()? {
+                ' ' => Instruction::Copy(self.number()?),
+                '\n' => Instruction::Slide(self.number()?),
+                _ => return Err("unknown stack instruction".to_string()),
+            },
+            ('\t', ' ') => match (self.expect()?, self.expect()?) {
+                (' ', ' ') => Instruction::Add,
+                (' ', '\t') => Instruction::Subtract,
+                (' ', '\n') => Instruction::Multiply,
+                ('\t', ' ') => Instruction::Divide,
+                ('\t', '\t') => Instruction::Modulo,
+                _ => return Err("unknown arithmetic instruction".to_string()),
+            },
+            ('\t', '\t') => match self.expect()? {
+                ' ' => Instruction::Store,
+                '\t' => Instruction::Retrieve,
+                _ => return Err("unknown heap instruction".to_string()),
+            },
+            ('\t', '\n') => match (self.expect()?, self.expect()?) {
+                (' ', ' ') => Instruction::PrintChar,
+                (' ', '\t') => Instruction::PrintNumber,
+                ('\t', ' ') => Instruction::ReadChar,
+                ('\t', '\t') => Instruction::ReadNumber,
+                _ => return Err("unknown IO instruction".to_string()),
+            },
+            ('\n', ' ') => match self.expect()? {
+                ' ' => Instruction::Label(self.label()?),
+                '\t' => Instruction::Call(self.label()?),
+                _ => Instruction::Jump(self.label()?),
+            },
+            ('\n', '\t') => match self.expect()? {
+                ' ' => Instruction::JumpIfZero(self.label()?),
+                '\t' => Instruction::JumpIfNegative(self.label()?),
+                _ => Instruction::Return,
+            },
+            ('\n', '\n') => match self.expect()? {
+                '\n' => Instruction::Exit,
+                _ => return Err("unknown flow control instruction".to_string()),
+            },
+            _ => unreachable!(),
+        })
+    }
+}
+
+fn parse(code: &str) -> Result<Vec<Instruction>, String> {
+    let mut parser = Parser {
+        code: code
+            .chars()
+            .filter(|ch| matches!(ch, ' ' | '\t' | '\n'))
+            .collect(),
+        position: 0,
+    };
+    let mut program = vec![];
+    while let Some(first) = parser.next() {
+        program.push(parser.instruction(first)?);
+    }
+    Ok(program)
+}
+
+fn floor_div(a: i64, b: i64) -> i64 {
+    let quotient = a / b;
+    if a % b != 0 && (a < 0) != (b < 0) {
+        quotient - 1
+    } else {
+        quotient
+    }
+}
+
+/// Behaviour of reading a character past the end of input, interpreters disagree on it
+#[derive(Clone, Copy)]
+pub enum Eof {
+    /// Stop with an error, like the reference implementation
+    Abort,
+    /// Leave the heap cell untouched
+    Unchanged,
+    /// Store the given value
+    Store(i64),
+}
+
+/// Runs the program with the given input and returns everything it printed.
+pub fn run(code: &str, input: &str) -> Result<String, String> {
+    run_with_eof(code, input, Eof::Abort)
+}
+
+pub fn run_with_eof(code: &str, input: &str, eof: Eof) -> Result<String, String> {
+    let program = parse(code)?;
+    let mut labels = HashMap::new();
+    for (i, instruction) in program.iter().enumerate() {
+        if let Instruction::Label(label) = instruction {
+            if labels.insert(label.clone(), i).is_some() {
+                return Err(format!("duplicate label {:?}", label));
+            }
+        }
+    }
+    let jump = |label: &String| -> Result<usize, String> {
+        labels
+            .get(label)
+            .copied()
+            .ok_or_else(|| format!("undefined label {:?}", label))
+    };
+
+    let mut input = input.chars();
+    let mut output = String::new();
+    let mut stack: Vec<i64> = vec![];
+    let mut calls: Vec<usize> = vec![];
+    let mut heap: HashMap<i64, i64> = HashMap::new();
+    let mut pc = 0;
+    let mut steps = 0;
+
+    macro_rules! pop {
+        () => {
+            stack
+                .pop()
+                .ok_or_else(|| format!("stack underflow at instruction {}", pc))?
+        };
+    }
+
+    loop {
+        steps += 1;
+        if steps > 10_000_000 {
+            return Err("step limit exceeded".to_string());
+        }
+        let instruction = program
+            .get(pc)
+            .ok_or_else(|| "program ended without exit".to_string())?;
+        pc += 1;
+        match instruction {
+            Instruction::Push(n) => stack.push(*n),
+            Instruction::Duplicate => {
+                let a = pop!();
+                stack.extend([a, a]);
+            }
+            Instruction::Copy(n) => {
+                let index = stack.len() as i64 - 1 - n;
+                if *n < 0 || index < 0 {
+                    return Err(format!("copy out of range at instruction {}", pc - 1));
+                }
+                stack.push(stack[index as usize]);
+            }
+            Instruction::Swap => {
+                let a = pop!();
+                let b = pop!();
+                stack.extend([a, b]);
+            }
+            Instruction::Discard => {
+                pop!();
+            }
+            Instruction::Slide(n) => {
+                let a = pop!();
+                for _ in 0..*n {
+                    pop!();
+                }
+                stack.push(a);
+            }
+            Instruction::Add
+            | Instruction::Subtract
+            | Instruction::Multiply
+            | Instruction::Divide
+            | Instruction::Modulo => {
+                let b = pop!();
+                let a = pop!();
+                stack.push(match instruction {
+                    Instruction::Add => a + b,
+                    Instruction::Subtract => a - b,
+                    Instruction::Multiply => a * b,
+                    _ if b == 0 => return Err("division by zero".to_string()),
+                    Instruction::Divide => floor_div(a, b),
+                    _ => a - b * floor_div(a, b),
+                });
+            }
+            Instruction::Store => {
+                let value = pop!();
+                let address = pop!();
+                heap.insert(address, value);
+            }
+            Instruction::Retrieve => {
+                let address = pop!();
+                let value = heap
+                    .get(&address)
+                    .ok_or_else(|| format!("read of uninitialised heap cell {}", address))?;
+                stack.push(*value);
+            }
+            Instruction::Label(_) => {}
+            Instruction::Call(label) => {
+                calls.push(pc);
+                pc = jump(label)?;
+            }
+            Instruction::Jump(label) => pc = jump(label)?,
+            Instruction::JumpIfZero(label) => {
+                if pop!() == 0 {
+                    pc = jump(label)?;
+                }
+            }
+            Instruction::JumpIfNegative(label) => {
+                if pop!() < 0 {
+                    pc = jump(label)?;
+                }
+            }
+            Instruction::Return => {
+                pc = calls
+                    .pop()
+                    .ok_or_else(|| "return outside of a subroutine".to_string())?;
+            }
+            Instruction::Exit if stack.is_empty() => return Ok(output),
+            Instruction::Exit => return Err(format!("stack is not empty on exit: {:?}", stack)),
+            Instruction::PrintChar => {
+                let value = pop!();
+                output.push(char::from_u32(value as u32).ok_or("invalid character")?);
+            }
+            Instruction::PrintNumber => output.push_str(&pop!().to_string()),
+            Instruction::ReadChar => {
+                let address = pop!();
+                match (input.next(), eof) {
+                    (Some(ch), _) => {
+                        heap.insert(address, ch as i64);
+                    }
+                    (None, Eof::Abort) => return Err("end of input".to_string()),
+                    (None, Eof::Unchanged) => {}
+                    (None, Eof::Store(value)) => {
+                        heap.insert(address, value);
+                    }
+                }
+            }
+            Instruction::ReadNumber => {
+                let address = pop!();
+                let line: String = input.by_ref().take_while(|ch| *ch != '\n').collect();
+                let value = line
+                    .trim()
+                    .parse()
+                    .map_err(|_| format!("invalid number {:?}", line))?;
+                heap.insert(address, value);
+            }
+        }
+    }
+}